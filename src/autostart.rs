@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+/// Start of the hex ZRQINIT header `sz` sends when a ZModem upload starts
+const ZRQINIT: &[u8] = b"**\x18B00";
+
+/// A YModem receiver polls with 'C' until the sender starts
+const POLL: u8 = b'C';
+
+/// Polls in a row taken as a receiver waiting, rather than text
+const POLLS: usize = 3;
+
+/// Receivers poll about once a second, text doesn't come at that pace
+const POLL_GAP: (Duration, Duration) = (Duration::from_millis(500), Duration::from_secs(5));
+
+/// Eight CANs to stop the device's transfer, then eight backspaces that
+/// erase them again once it is back at a command line
+pub const DECLINE: &[u8] = b"\x18\x18\x18\x18\x18\x18\x18\x18\x08\x08\x08\x08\x08\x08\x08\x08";
+
+/// A transfer the device started from its end, named by what the
+/// terminal does to complete it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoStart {
+    /// The device sent ZRQINIT, it is sending files with ZModem
+    ZModemReceive,
+    /// The device polls with 'C', it is waiting for files over YModem
+    YModemSend,
+}
+
+impl AutoStart {
+    pub fn title(&self) -> &'static str {
+        match self {
+            AutoStart::ZModemReceive => "zModem Receive",
+            AutoStart::YModemSend => "yModem Send",
+        }
+    }
+
+    /// The bytes of the handshake, to take back out of the console
+    pub fn pattern(&self) -> &'static [u8] {
+        match self {
+            AutoStart::ZModemReceive => ZRQINIT,
+            AutoStart::YModemSend => b"CCC",
+        }
+    }
+}
+
+/// Watches the bytes shown in the terminal for a device starting a transfer
+#[derive(Default)]
+pub struct Detector {
+    /// Last bytes seen, as long as the longest pattern
+    tail: Vec<u8>,
+    /// Polls received in a row, each on its own and a poll interval apart
+    polls: usize,
+    /// When the last poll arrived
+    last_poll: Option<Instant>,
+}
+
+impl Detector {
+    /// Scans received bytes, returns the transfer once its handshake is seen
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<AutoStart> {
+        self.feed_at(bytes, Instant::now())
+    }
+
+    fn feed_at(&mut self, bytes: &[u8], now: Instant) -> Option<AutoStart> {
+        for &byte in bytes {
+            self.tail.push(byte);
+            if self.tail.len() > ZRQINIT.len() {
+                self.tail.remove(0);
+            }
+            if self.tail == ZRQINIT {
+                self.reset();
+                return Some(AutoStart::ZModemReceive);
+            }
+        }
+        // A poll comes alone, or at the end of a line announcing it
+        let poll = match bytes {
+            [.., before, POLL] => *before == b'\n' || *before == b'\r',
+            [POLL] => true,
+            _ => false,
+        };
+        if !poll {
+            self.polls = 0;
+            self.last_poll = None;
+            return None;
+        }
+        let paced = self
+            .last_poll
+            .map(|last| now.saturating_duration_since(last));
+        self.polls = match paced {
+            Some(gap) if gap >= POLL_GAP.0 && gap <= POLL_GAP.1 && bytes.len() == 1 => {
+                self.polls + 1
+            }
+            _ => 1,
+        };
+        self.last_poll = Some(now);
+        if self.polls == POLLS {
+            self.reset();
+            return Some(AutoStart::YModemSend);
+        }
+        None
+    }
+
+    pub fn reset(&mut self) {
+        self.tail.clear();
+        self.polls = 0;
+        self.last_poll = None;
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_detect_handshakes() {
+    let mut detector = Detector::default();
+    assert_eq!(detector.feed(b"$ sz firmware.bin\r\nrz\r*"), None);
+    // The header can be split over reads
+    assert_eq!(
+        detector.feed(b"*\x18B00000000000000\r\x8a\x11"),
+        Some(AutoStart::ZModemReceive)
+    );
+    let start = Instant::now();
+    let second = |n| start + Duration::from_secs(n);
+    assert_eq!(detector.feed_at(b"Ready to receive\r\nC", second(0)), None);
+    assert_eq!(detector.feed_at(b"C", second(1)), None);
+    assert_eq!(
+        detector.feed_at(b"C", second(2)),
+        Some(AutoStart::YModemSend)
+    );
+    // Text with C's in it, however it arrives, isn't a receiver
+    assert_eq!(detector.feed_at(b"Cat and Cow C", second(3)), None);
+    assert_eq!(detector.feed_at(b"dump: 0CCC", second(4)), None);
+    assert_eq!(detector.feed_at(b"C", second(4)), None);
+    assert_eq!(detector.feed_at(b"C", second(4)), None);
+    assert_eq!(detector.feed_at(b"C", second(4)), None);
+    assert_eq!(detector.feed_at(b"C", second(5)), None);
+    assert_eq!(detector.feed_at(b"x", second(6)), None);
+    assert_eq!(detector.feed_at(b"C", second(7)), None);
+}
@@ -0,0 +1,260 @@
+use crate::vt100::{Screen, SCROLLBACK};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// How received bytes are shown on the console
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8, bytes that aren't show as U+FFFD
+    Utf8,
+    /// Printable ASCII, other bytes as \xNN escapes
+    Ascii,
+    /// ISO 8859-1, every byte is the code point of the same value
+    Latin1,
+    /// The IBM PC character set, box drawing and all
+    Cp437,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 4] = [
+        Encoding::Utf8,
+        Encoding::Ascii,
+        Encoding::Latin1,
+        Encoding::Cp437,
+    ];
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "UTF-8"),
+            Encoding::Ascii => write!(f, "ASCII"),
+            Encoding::Latin1 => write!(f, "Latin-1"),
+            Encoding::Cp437 => write!(f, "CP437"),
+        }
+    }
+}
+
+/// Characters of CP437 bytes 0x80 to 0xFF, the lower half is ASCII
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}",
+);
+
+/// Decodes bytes as they arrive. A UTF-8 character split across two reads
+/// is held back until the rest of it arrives.
+pub struct Decoder {
+    encoding: Encoding,
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            pending: vec![],
+        }
+    }
+
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        match self.encoding {
+            Encoding::Utf8 => self.decode_utf8(bytes),
+            Encoding::Ascii => bytes
+                .iter()
+                .map(|&byte| match byte {
+                    b'\t' | b'\n' | b'\r' | 0x20..=0x7E => (byte as char).to_string(),
+                    _ => format!("\\x{byte:02X}"),
+                })
+                .collect(),
+            Encoding::Latin1 => bytes.iter().map(|&byte| byte as char).collect(),
+            Encoding::Cp437 => bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x00..=0x7F => byte as char,
+                    _ => CP437_HIGH
+                        .chars()
+                        .nth(byte as usize - 0x80)
+                        .unwrap_or('\u{FFFD}'),
+                })
+                .collect(),
+        }
+    }
+
+    fn decode_utf8(&mut self, bytes: &[u8]) -> String {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = &pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, after) = rest.split_at(err.valid_up_to());
+                    text.push_str(&String::from_utf8_lossy(valid));
+                    match err.error_len() {
+                        Some(len) => {
+                            text.push('\u{FFFD}');
+                            rest = &after[len..];
+                        }
+                        // The start of a character, the rest is still to come
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        text
+    }
+}
+
+/// Most bytes kept of lines that never end
+const MAX_RAW: usize = 1 << 20;
+
+/// What was received on the console, kept as bytes so it can be shown
+/// again in another encoding, and the terminal screen it makes up. Bytes
+/// are kept for as many lines as the scrollback and screen hold.
+pub struct Console {
+    raw: VecDeque<u8>,
+    /// Line feeds in `raw`
+    lines: usize,
+    screen: Screen,
+    decoder: Decoder,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self {
+            raw: VecDeque::new(),
+            lines: 0,
+            screen: Screen::new(24, 80),
+            decoder: Decoder::new(Encoding::Utf8),
+        }
+    }
+}
+
+impl Console {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.raw.extend(bytes);
+        self.lines += bytes.iter().filter(|&&byte| byte == b'\n').count();
+        self.trim();
+        let text = self.decoder.decode(bytes);
+        self.screen.feed(&text);
+    }
+
+    /// Drops the oldest lines the screen no longer has room for
+    fn trim(&mut self) {
+        let kept = SCROLLBACK + self.screen.size().0;
+        let mut drop = 0;
+        if self.lines > kept {
+            let mut extra = self.lines - kept;
+            for (index, &byte) in self.raw.iter().enumerate() {
+                if byte == b'\n' {
+                    extra -= 1;
+                    if extra == 0 {
+                        drop = index + 1;
+                        break;
+                    }
+                }
+            }
+            self.lines = kept;
+        }
+        if self.raw.len() - drop > MAX_RAW {
+            drop = self.raw.len() - MAX_RAW;
+            self.lines = self
+                .raw
+                .range(drop..)
+                .filter(|&&byte| byte == b'\n')
+                .count();
+        }
+        self.raw.drain(..drop);
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.decoder.encoding
+    }
+
+    /// Shows everything received so far in the encoding
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        let (rows, cols) = self.screen.size();
+        self.decoder = Decoder::new(encoding);
+        self.screen = Screen::new(rows, cols);
+        let text = self.decoder.decode(self.raw.make_contiguous());
+        self.screen.feed(&text);
+        // What the device asked for was answered the first time
+        self.screen.take_replies();
+    }
+
+    /// Drops the last occurrence of the pattern and everything after it
+    pub fn truncate_from_last(&mut self, pattern: &[u8]) {
+        if pattern.is_empty() || pattern.len() > self.raw.len() {
+            return;
+        }
+        if let Some(start) = self
+            .raw
+            .make_contiguous()
+            .windows(pattern.len())
+            .rposition(|bytes| bytes == pattern)
+        {
+            self.raw.truncate(start);
+            self.lines = self.raw.iter().filter(|&&byte| byte == b'\n').count();
+            self.set_encoding(self.encoding());
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_decoders() {
+    let mut utf8 = Decoder::new(Encoding::Utf8);
+    // A three byte character split across reads, then a stray byte
+    assert_eq!(utf8.decode(b"caf\xE2\x82"), "caf");
+    assert_eq!(utf8.decode(b"\xAC ok \xFF!"), "\u{20AC} ok \u{FFFD}!");
+    let mut ascii = Decoder::new(Encoding::Ascii);
+    assert_eq!(ascii.decode(b"A\x1B[0m\r\n\xC3"), "A\\x1B[0m\r\n\\xC3");
+    let mut latin1 = Decoder::new(Encoding::Latin1);
+    assert_eq!(latin1.decode(b"\xE9\xFF"), "éÿ");
+    let mut cp437 = Decoder::new(Encoding::Cp437);
+    assert_eq!(cp437.decode(b"\xC9\xCD\xBB a \x80\xFF"), "╔═╗ a Ç\u{A0}");
+}
+
+#[cfg(test)]
+#[test]
+fn test_console_switches_encoding() {
+    let mut console = Console::default();
+    console.push(b"\xB0\xB1 boot");
+    assert_eq!(console.screen().text(), "\u{FFFD}\u{FFFD} boot");
+    console.set_encoding(Encoding::Cp437);
+    assert_eq!(console.screen().text(), "░▒ boot");
+    console.push(b"\r\n**\x18B00");
+    console.truncate_from_last(b"**\x18B");
+    assert_eq!(console.screen().text(), "░▒ boot");
+    assert_eq!(console.screen().cursor(), (1, 0));
+    // Only the lines the scrollback and screen hold are kept
+    for line in 0..2000 {
+        console.push(format!("line {line}\r\n").as_bytes());
+    }
+    assert_eq!(console.lines, SCROLLBACK + 24);
+    let oldest: Vec<u8> = console.raw.iter().take(10).copied().collect();
+    assert_eq!(oldest, b"line 976\r\n");
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use std::io;
+
+/// Why a file transfer failed
+#[derive(Debug)]
+pub enum TransferError {
+    /// The peer never answered the start of the transfer
+    SyncTimeout,
+    /// No data arrived from the peer in time
+    Timeout,
+    /// A packet or frame was retried more than the allowed number of times
+    TooManyRetries,
+    /// The peer cancelled the transfer
+    Cancelled,
+    /// The transfer was cancelled on this end
+    Aborted,
+    /// The peer ended the transfer with an error message
+    Remote(String),
+    /// Reading from or writing to the transport failed
+    Io(io::Error),
+    /// A packet arrived out of sequence
+    Sequence { expected: u8, received: u8 },
+    /// A packet or frame broke the protocol, such as a bad CRC
+    Protocol(&'static str),
+    /// Reading the data to send failed
+    StreamRead(io::Error),
+    /// Writing the received data failed
+    StreamWrite(io::Error),
+}
+
+impl TransferError {
+    /// True for errors a protocol recovers from by retrying, the ones
+    /// caused by a noisy or slow line rather than by either end giving up.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            TransferError::Timeout | TransferError::Protocol(_) | TransferError::Sequence { .. }
+        )
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::SyncTimeout => write!(f, "Synchronization failed, no answer from peer"),
+            TransferError::Timeout => write!(f, "Timeout waiting for data"),
+            TransferError::TooManyRetries => write!(f, "Reached max number of retries"),
+            TransferError::Cancelled => write!(f, "Transfer cancelled by peer"),
+            TransferError::Aborted => write!(f, "Transfer cancelled"),
+            TransferError::Remote(message) => write!(f, "Peer error: {message}"),
+            TransferError::Io(err) => write!(f, "I/O error: {err}"),
+            TransferError::Sequence { expected, received } => write!(
+                f,
+                "Packet sequence error, expected {expected} received {received}"
+            ),
+            TransferError::Protocol(message) => write!(f, "Protocol error: {message}"),
+            TransferError::StreamRead(err) => write!(f, "Failed to read from stream: {err}"),
+            TransferError::StreamWrite(err) => write!(f, "Failed to write to stream: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransferError::Io(err)
+            | TransferError::StreamRead(err)
+            | TransferError::StreamWrite(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(err: io::Error) -> Self {
+        TransferError::Io(err)
+    }
+}
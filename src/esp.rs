@@ -0,0 +1,585 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use md5::{Digest as _, Md5};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+// Serial protocol of the ESP8266 and ESP32 ROM bootloaders, as spoken by
+// esptool. Packets travel in SLIP frames.
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+const FLASH_BEGIN: u8 = 0x02;
+const FLASH_DATA: u8 = 0x03;
+const FLASH_END: u8 = 0x04;
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0A;
+const SPI_SET_PARAMS: u8 = 0x0B;
+const SPI_ATTACH: u8 = 0x0D;
+const CHANGE_BAUDRATE: u8 = 0x0F;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// Seed of the checksum of flash data
+const CHECKSUM_SEED: u8 = 0xEF;
+
+/// Register holding a value that tells the chips apart
+const CHIP_DETECT_MAGIC: u32 = 0x4000_1000;
+const ESP8266_MAGIC: u32 = 0xFFF0_C101;
+
+/// Flash data block size of the ROM loader
+const BLOCK: usize = 0x400;
+const SECTOR: usize = 0x1000;
+
+/// Flash size told to the ESP32 ROM, large enough for any offset we write
+const FLASH_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Which chip the ROM runs on, they differ in the status they send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip {
+    Esp8266,
+    Esp32,
+}
+
+impl Chip {
+    /// Where an application image goes, the ESP32 keeps its second stage
+    /// bootloader and partition table below it
+    pub fn app_offset(self) -> u32 {
+        match self {
+            Chip::Esp8266 => 0,
+            Chip::Esp32 => 0x1_0000,
+        }
+    }
+}
+
+/// Talks to the ESP ROM bootloader
+pub struct Esp {
+    timeout: Duration,
+    chip: Chip,
+    reporter: Reporter,
+}
+
+impl Esp {
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(3),
+            chip: Chip::Esp32,
+            reporter: Reporter::default(),
+        }
+    }
+
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Resets into the bootloader with the DTR and RTS lines, wired as on
+    /// the usual dev boards: DTR pulls IO0 low and RTS pulls EN low.
+    pub fn reset_to_bootloader(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.set_control_lines(false, true)?;
+        std::thread::sleep(Duration::from_millis(100));
+        device.set_control_lines(true, false)?;
+        std::thread::sleep(Duration::from_millis(50));
+        device.set_control_lines(false, false)?;
+        Ok(())
+    }
+
+    /// Pulses EN to run the flashed application
+    pub fn hard_reset(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.set_control_lines(false, true)?;
+        std::thread::sleep(Duration::from_millis(100));
+        device.set_control_lines(false, false)?;
+        Ok(())
+    }
+
+    /// Sends SYNC until the ROM answers, then drops the extra answers it sends
+    pub fn sync(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let mut data = vec![0x07, 0x07, 0x12, 0x20];
+        data.extend_from_slice(&[0x55; 32]);
+        device.clear_input()?;
+        for attempt in 1..=7 {
+            self.check_cancel()?;
+            self.send_command(device, SYNC, &data, 0)?;
+            match self.read_response(device, SYNC, Duration::from_millis(100)) {
+                Ok(_) => {
+                    std::thread::sleep(Duration::from_millis(50));
+                    device.clear_input()?;
+                    println!("Synced with the ROM after {attempt} attempts");
+                    return Ok(());
+                }
+                Err(TransferError::Timeout) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(TransferError::SyncTimeout)
+    }
+
+    pub fn read_reg(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+    ) -> Result<u32, TransferError> {
+        let (value, _) = self.command(device, READ_REG, &address.to_le_bytes(), 0, self.timeout)?;
+        Ok(value)
+    }
+
+    /// Finds out the chip from its magic register
+    pub fn detect_chip(&mut self, device: &mut dyn Transport) -> Result<Chip, TransferError> {
+        let magic = self.read_reg(device, CHIP_DETECT_MAGIC)?;
+        self.chip = if magic == ESP8266_MAGIC {
+            Chip::Esp8266
+        } else {
+            Chip::Esp32
+        };
+        println!("Chip magic {magic:#010x}, {:?}", self.chip);
+        Ok(self.chip)
+    }
+
+    /// Switches the ROM and the port to another baud rate
+    pub fn change_baudrate(
+        &mut self,
+        device: &mut dyn Transport,
+        baud: u32,
+    ) -> Result<(), TransferError> {
+        let mut data = baud.to_le_bytes().to_vec();
+        // The ROM doesn't need the old rate, only the stub does
+        data.extend_from_slice(&0u32.to_le_bytes());
+        self.command(device, CHANGE_BAUDRATE, &data, 0, self.timeout)?;
+        device.set_baud_rate(baud)?;
+        std::thread::sleep(Duration::from_millis(50));
+        device.clear_input()?;
+        println!("Changed baud rate to {baud}");
+        Ok(())
+    }
+
+    /// Erases the flash the image goes to and prepares to write it
+    pub fn flash_begin(
+        &mut self,
+        device: &mut dyn Transport,
+        offset: u32,
+        size: usize,
+    ) -> Result<(), TransferError> {
+        let blocks = size / BLOCK + (size & (BLOCK - 1) != 0) as usize;
+        let erase_size = match self.chip {
+            Chip::Esp8266 => esp8266_erase_size(offset as usize, size),
+            Chip::Esp32 => size,
+        };
+        let mut data = vec![];
+        for word in [erase_size as u32, blocks as u32, BLOCK as u32, offset] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        // Erasing takes about 30 seconds per megabyte
+        let timeout = self
+            .timeout
+            .max(Duration::from_secs(30 * erase_size as u64 / 0x10_0000 + 1));
+        self.command(device, FLASH_BEGIN, &data, 0, timeout)?;
+        Ok(())
+    }
+
+    /// Writes a block, padded with 0xFF to the block size
+    pub fn flash_data(
+        &mut self,
+        device: &mut dyn Transport,
+        sequence: u32,
+        block: &[u8],
+    ) -> Result<(), TransferError> {
+        let mut payload = block.to_vec();
+        payload.resize(BLOCK, 0xFF);
+        let mut data = vec![];
+        for word in [payload.len() as u32, sequence, 0, 0] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(&payload);
+        let checksum = payload.iter().fold(CHECKSUM_SEED, |sum, byte| sum ^ byte);
+        self.command(device, FLASH_DATA, &data, checksum as u32, self.timeout)?;
+        Ok(())
+    }
+
+    /// Ends the flash write, the ROM runs the application unless told to stay
+    pub fn flash_end(
+        &mut self,
+        device: &mut dyn Transport,
+        run: bool,
+    ) -> Result<(), TransferError> {
+        let stay = if run { 0u32 } else { 1u32 };
+        self.command(device, FLASH_END, &stay.to_le_bytes(), 0, self.timeout)?;
+        Ok(())
+    }
+
+    /// MD5 of a region of flash, as lower case hex
+    pub fn flash_md5(
+        &mut self,
+        device: &mut dyn Transport,
+        offset: u32,
+        size: usize,
+    ) -> Result<String, TransferError> {
+        let mut data = vec![];
+        for word in [offset, size as u32, 0, 0] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        // Hashing takes about 8 seconds per megabyte
+        let timeout = self
+            .timeout
+            .max(Duration::from_secs(8 * size as u64 / 0x10_0000 + 1));
+        let (_, reply) = self.command(device, SPI_FLASH_MD5, &data, 0, timeout)?;
+        match reply.len() {
+            // The ROM answers in hex, the flasher stub in binary
+            32 => Ok(String::from_utf8_lossy(&reply).to_ascii_lowercase()),
+            16 => Ok(reply.iter().map(|byte| format!("{byte:02x}")).collect()),
+            _ => Err(TransferError::Protocol("MD5 reply of unexpected length")),
+        }
+    }
+
+    /// Resets into the bootloader, writes the image at the offset, or the
+    /// chip's application offset if none is given, and checks its MD5. The
+    /// baud rate is raised for the write if one is given. Returns the offset.
+    pub fn flash(
+        &mut self,
+        device: &mut dyn Transport,
+        offset: Option<u32>,
+        image: &[u8],
+        baud: Option<u32>,
+        run: bool,
+    ) -> Result<u32, TransferError> {
+        self.reporter.set_total(Some(image.len() as u64));
+        self.reporter.start();
+        let result = self.flash_image(device, offset, image, baud, run);
+        self.reporter.finish(result)
+    }
+
+    fn flash_image(
+        &mut self,
+        device: &mut dyn Transport,
+        offset: Option<u32>,
+        image: &[u8],
+        baud: Option<u32>,
+        run: bool,
+    ) -> Result<u32, TransferError> {
+        // Without control lines the board has to be put in the bootloader by hand
+        match self.reset_to_bootloader(device) {
+            Err(TransferError::Io(err)) if err.kind() == ErrorKind::Unsupported => {
+                println!("No DTR/RTS, expecting the board in the bootloader already")
+            }
+            result => result?,
+        }
+        self.sync(device)?;
+        if self.detect_chip(device)? == Chip::Esp32 {
+            self.command(device, SPI_ATTACH, &[0; 8], 0, self.timeout)?;
+            let mut data = vec![];
+            for word in [0, FLASH_SIZE, 0x1_0000, SECTOR as u32, 0x100, 0xFFFF] {
+                data.extend_from_slice(&u32::to_le_bytes(word));
+            }
+            self.command(device, SPI_SET_PARAMS, &data, 0, self.timeout)?;
+        }
+        // Only the ESP32 ROM and the flasher stub can change the baud rate
+        match baud {
+            Some(_) if self.chip == Chip::Esp8266 => {
+                println!("The ESP8266 ROM can't change the baud rate, keeping the port's")
+            }
+            Some(baud) => self.change_baudrate(device, baud)?,
+            None => (),
+        }
+
+        let offset = offset.unwrap_or_else(|| self.chip.app_offset());
+        println!("Erasing {} bytes at {offset:#x}", image.len());
+        self.flash_begin(device, offset, image.len())?;
+        self.reporter.set_state(TransferState::Transferring);
+        for (sequence, block) in image.chunks(BLOCK).enumerate() {
+            self.check_cancel()?;
+            self.flash_data(device, sequence as u32, block)?;
+            self.reporter.count_packet(block.len());
+        }
+
+        self.reporter.set_state(TransferState::Finishing);
+        // The ESP8266 ROM has no MD5 command either, esptool skips the check too
+        if self.chip == Chip::Esp8266 {
+            println!("The ESP8266 ROM can't hash the flash, the write isn't verified");
+        } else {
+            let expected: String = Md5::digest(image)
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            let md5 = self.flash_md5(device, offset, image.len())?;
+            if md5 != expected {
+                return Err(TransferError::Remote(format!(
+                    "MD5 mismatch, flash {md5}, image {expected}"
+                )));
+            }
+            println!("MD5 verified {md5}");
+        }
+        self.flash_end(device, run)?;
+        if run {
+            let _ = self.hard_reset(device);
+        }
+        Ok(offset)
+    }
+
+    /// The ROM has no abort, an unfinished write leaves the old application broken
+    fn check_cancel(&self) -> Result<(), TransferError> {
+        if self.reporter.is_cancelled() {
+            return Err(TransferError::Aborted);
+        }
+        Ok(())
+    }
+
+    /// Sends a command and waits for its response, returns the value and data
+    fn command(
+        &mut self,
+        device: &mut dyn Transport,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> Result<(u32, Vec<u8>), TransferError> {
+        self.send_command(device, command, data, checksum)?;
+        self.read_response(device, command, timeout)
+    }
+
+    fn send_command(
+        &mut self,
+        device: &mut dyn Transport,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+    ) -> Result<(), TransferError> {
+        let mut packet = vec![0x00, command];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(data);
+        device.write_all(&slip_encode(&packet))?;
+        device.flush()?;
+        Ok(())
+    }
+
+    /// Reads frames until the response to the command, checks its status
+    fn read_response(
+        &mut self,
+        device: &mut dyn Transport,
+        command: u8,
+        timeout: Duration,
+    ) -> Result<(u32, Vec<u8>), TransferError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let frame = self.read_frame(device, deadline)?;
+            if frame.len() < 8 || frame[0] != 0x01 || frame[1] != command {
+                // Leftover answers to an earlier SYNC, or noise
+                continue;
+            }
+            let value = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+            let data = &frame[8..];
+            let status_length = match self.chip {
+                Chip::Esp8266 => 2,
+                Chip::Esp32 => 4,
+            };
+            // The stub and the ESP8266 send 2 status bytes, pick by what arrived
+            let status_length = if data.len() == 2 { 2 } else { status_length };
+            if data.len() < status_length {
+                return Err(TransferError::Protocol("Response too short for its status"));
+            }
+            let (data, status) = data.split_at(data.len() - status_length);
+            if status[0] != 0 {
+                return Err(TransferError::Remote(format!(
+                    "Command {command:#04x} failed with error {:#04x}",
+                    status[1]
+                )));
+            }
+            return Ok((value, data.to_vec()));
+        }
+    }
+
+    /// Reads one SLIP frame, skipping anything before its start
+    fn read_frame(
+        &mut self,
+        device: &mut dyn Transport,
+        deadline: Instant,
+    ) -> Result<Vec<u8>, TransferError> {
+        let mut frame = vec![];
+        let mut started = false;
+        let mut escaped = false;
+        let mut byte = [0; 1];
+        loop {
+            self.check_cancel()?;
+            match device.read(&mut byte) {
+                Ok(0) => return Err(TransferError::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(_) => match (started, escaped, byte[0]) {
+                    (false, _, END) => started = true,
+                    (false, _, _) => (),
+                    (true, false, END) if frame.is_empty() => (),
+                    (true, false, END) => return Ok(frame),
+                    (true, false, ESC) => escaped = true,
+                    (true, false, byte) => frame.push(byte),
+                    (true, true, ESC_END) => {
+                        frame.push(END);
+                        escaped = false;
+                    }
+                    (true, true, ESC_ESC) => {
+                        frame.push(ESC);
+                        escaped = false;
+                    }
+                    (true, true, _) => return Err(TransferError::Protocol("Invalid SLIP escape")),
+                },
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+    }
+}
+
+/// Wraps a packet in a SLIP frame
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = vec![END];
+    for &byte in packet {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            byte => frame.push(byte),
+        }
+    }
+    frame.push(END);
+    frame
+}
+
+/// The ESP8266 ROM erases more than asked for, esptool works out the size
+/// to ask for so that it erases the right amount.
+fn esp8266_erase_size(offset: usize, size: usize) -> usize {
+    let sectors_per_block = 16;
+    let sectors = size / SECTOR + (size & (SECTOR - 1) != 0) as usize;
+    let start_sector = offset / SECTOR;
+    let head_sectors = (sectors_per_block - start_sector % sectors_per_block).min(sectors);
+    if sectors < 2 * head_sectors {
+        (sectors / 2 + (sectors & 1)) * SECTOR
+    } else {
+        (sectors - head_sectors) * SECTOR
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_slip_and_erase_size() {
+    assert_eq!(
+        slip_encode(&[0x01, END, 0x02, ESC]),
+        [END, 0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC, END]
+    );
+    assert_eq!(esp8266_erase_size(0, 0x1000), 0x1000);
+    assert_eq!(esp8266_erase_size(0, 0x10_0000), 0xF_0000);
+}
+
+/// An ESP ROM simulated well enough to flash an image. The ESP8266 one
+/// sends 2 status bytes and fails the commands only the ESP32 ROM has.
+#[cfg(test)]
+fn simulate_rom(device: &mut crate::transport::Loopback, flash: &mut [u8], chip: Chip) {
+    use std::io::{Read, Write};
+    let mut esp = Esp::new();
+    let status_length = match chip {
+        Chip::Esp8266 => 2,
+        Chip::Esp32 => 4,
+    };
+    let respond = |device: &mut crate::transport::Loopback,
+                   command: u8,
+                   value: u32,
+                   data: &[u8],
+                   error: u8| {
+        let mut packet = vec![0x01, command];
+        packet.extend_from_slice(&((data.len() + status_length) as u16).to_le_bytes());
+        packet.extend_from_slice(&value.to_le_bytes());
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&[(error != 0) as u8, error, 0, 0][..status_length]);
+        device.write_all(&slip_encode(&packet)).unwrap();
+    };
+    let reply = |device: &mut crate::transport::Loopback, command: u8, value: u32, data: &[u8]| {
+        respond(device, command, value, data, 0)
+    };
+    let word = |data: &[u8], index: usize| {
+        u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap()) as usize
+    };
+    let mut offset = 0;
+    // Noise before the first frame, as after a reset
+    let mut noise = [0; 1];
+    let _ = device.read(&mut noise);
+    loop {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let packet = match esp.read_frame(device, deadline) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let (command, data) = (packet[1], &packet[8..]);
+        match command {
+            SYNC => {
+                for _ in 0..4 {
+                    reply(device, SYNC, 0, &[]);
+                }
+            }
+            READ_REG if chip == Chip::Esp8266 => reply(device, READ_REG, ESP8266_MAGIC, &[]),
+            READ_REG => reply(device, READ_REG, 0x00F0_1D83, &[]),
+            // Invalid command, as the ESP8266 ROM answers what it lacks
+            CHANGE_BAUDRATE | SPI_FLASH_MD5 if chip == Chip::Esp8266 => {
+                respond(device, command, 0, &[], 0x05)
+            }
+            SPI_ATTACH | SPI_SET_PARAMS | CHANGE_BAUDRATE => reply(device, command, 0, &[]),
+            FLASH_BEGIN => {
+                offset = word(data, 3);
+                flash[offset..offset + word(data, 0)].fill(0xFF);
+                reply(device, command, 0, &[]);
+            }
+            FLASH_DATA => {
+                let (len, sequence) = (word(data, 0), word(data, 1));
+                let checksum = data[16..]
+                    .iter()
+                    .fold(CHECKSUM_SEED, |sum, byte| sum ^ byte);
+                assert_eq!(
+                    checksum as u32,
+                    u32::from_le_bytes(packet[4..8].try_into().unwrap())
+                );
+                let start = offset + sequence * BLOCK;
+                flash[start..start + len].copy_from_slice(&data[16..16 + len]);
+                reply(device, command, 0, &[]);
+            }
+            SPI_FLASH_MD5 => {
+                let (start, size) = (word(data, 0), word(data, 1));
+                let md5: String = Md5::digest(&flash[start..start + size])
+                    .iter()
+                    .map(|byte| format!("{byte:02X}"))
+                    .collect();
+                reply(device, command, 0, md5.as_bytes());
+            }
+            FLASH_END => {
+                reply(device, command, 0, &[]);
+                return;
+            }
+            _ => panic!("unexpected command {command:#04x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_flash_simulated_rom() {
+    use crate::transport::loopback_pair;
+    // Holds an END so the escaping is exercised
+    let image: Vec<u8> = (0..3000).map(|i| (i * 13 + i / 256) as u8).collect();
+    for chip in [Chip::Esp32, Chip::Esp8266] {
+        let (mut device, mut peer) = loopback_pair();
+        let handle = std::thread::spawn(move || {
+            let mut flash = vec![0; 0x2_0000];
+            simulate_rom(&mut peer, &mut flash, chip);
+            flash
+        });
+        let mut esp = Esp::new();
+        let offset = esp
+            .flash(&mut device, None, &image, Some(921600), true)
+            .unwrap();
+        let flash = handle.join().unwrap();
+        assert_eq!(esp.chip, chip);
+        assert_eq!(offset, chip.app_offset());
+        let offset = offset as usize;
+        assert_eq!(flash[offset..offset + image.len()], image[..]);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::transport::Transport;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// A fault injected into one write through a `Faulty` transport
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Flips bits of the byte at an offset
+    Corrupt { offset: usize, mask: u8 },
+    /// Removes the byte at an offset
+    Drop { offset: usize },
+    /// Sends the write twice
+    Duplicate,
+    /// Holds the write back before sending it
+    Delay(Duration),
+    /// Sends only the first bytes of the write
+    Truncate { len: usize },
+    /// Loses the whole write
+    Lose,
+}
+
+/// Decides which writes get a fault
+pub enum Policy {
+    /// Each write gets a random fault with a probability, from a seeded
+    /// generator so a failing run can be repeated.
+    Random { seed: u64, rate: f64 },
+    /// Faults for chosen writes, by the index of the write
+    Script(Vec<(usize, Fault)>),
+}
+
+/// Wraps a transport and injects faults into the bytes written through it,
+/// reads pass through untouched. Wrap both ends to fault both directions.
+pub struct Faulty<T: Transport> {
+    inner: T,
+    policy: Policy,
+    rng: XorShift,
+    script: VecDeque<(usize, Fault)>,
+    /// Index of the next write
+    writes: usize,
+    /// Faults injected so far, with the index of the write
+    pub injected: Vec<(usize, Fault)>,
+}
+
+impl<T: Transport> Faulty<T> {
+    pub fn new(inner: T, policy: Policy) -> Self {
+        let (seed, script) = match &policy {
+            Policy::Random { seed, .. } => (*seed, VecDeque::new()),
+            Policy::Script(faults) => {
+                let mut faults = faults.clone();
+                faults.sort_by_key(|(index, _)| *index);
+                (1, faults.into())
+            }
+        };
+        Self {
+            inner,
+            policy,
+            rng: XorShift::new(seed),
+            script,
+            writes: 0,
+            injected: vec![],
+        }
+    }
+
+    /// The fault for the next write, if it gets one
+    fn next_fault(&mut self, len: usize) -> Option<Fault> {
+        let index = self.writes;
+        self.writes += 1;
+        match self.policy {
+            Policy::Script(_) => {
+                if self.script.front()?.0 != index {
+                    return None;
+                }
+                self.script.pop_front().map(|(_, fault)| fault)
+            }
+            Policy::Random { rate, .. } => {
+                if self.rng.next_f64() >= rate {
+                    return None;
+                }
+                let offset = self.rng.next_below(len);
+                let fault = match self.rng.next_below(6) {
+                    0 => Fault::Corrupt {
+                        offset,
+                        mask: 1 << self.rng.next_below(8),
+                    },
+                    1 => Fault::Drop { offset },
+                    2 => Fault::Duplicate,
+                    3 => Fault::Delay(Duration::from_millis(self.rng.next_below(50) as u64)),
+                    4 => Fault::Truncate { len: offset },
+                    _ => Fault::Lose,
+                };
+                Some(fault)
+            }
+        }
+    }
+}
+
+impl<T: Transport> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Transport> Write for Faulty<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let fault = match self.next_fault(buf.len()) {
+            Some(fault) => fault,
+            None => return self.inner.write_all(buf).map(|_| buf.len()),
+        };
+        self.injected.push((self.writes - 1, fault));
+        let mut bytes = buf.to_vec();
+        match fault {
+            Fault::Corrupt { offset, mask } => bytes[offset % buf.len()] ^= mask,
+            Fault::Drop { offset } => {
+                bytes.remove(offset % buf.len());
+            }
+            Fault::Duplicate => bytes.extend_from_slice(buf),
+            Fault::Delay(delay) => std::thread::sleep(delay),
+            Fault::Truncate { len } => bytes.truncate(len),
+            Fault::Lose => bytes.clear(),
+        }
+        self.inner.write_all(&bytes)?;
+        // The caller wrote everything as far as it can tell
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Faulty<T> {
+    fn bytes_to_read(&self) -> io::Result<usize> {
+        self.inner.bytes_to_read()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.inner.clear_input()
+    }
+}
+
+/// Xorshift64 generator, enough randomness for picking faults
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn next_below(&mut self, bound: usize) -> usize {
+        (self.next() % bound.max(1) as u64) as usize
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_scripted_faults() {
+    use crate::transport::loopback_pair;
+    let (a, mut b) = loopback_pair();
+    let script = vec![
+        (
+            0,
+            Fault::Corrupt {
+                offset: 1,
+                mask: 0xff,
+            },
+        ),
+        (1, Fault::Drop { offset: 0 }),
+        (2, Fault::Duplicate),
+        (3, Fault::Truncate { len: 2 }),
+        (4, Fault::Lose),
+    ];
+    let mut faulty = Faulty::new(a, Policy::Script(script));
+    for _ in 0..6 {
+        faulty.write_all(b"abc").unwrap();
+    }
+    let mut received = vec![0; 64];
+    let len = b.read(&mut received).unwrap();
+    assert_eq!(&received[..len], b"a\x9dcbcabcabcababc");
+    assert_eq!(faulty.injected.len(), 5);
+}
+
+#[cfg(test)]
+fn faulty_round_trip(
+    data: &[u8],
+    to_receiver: Policy,
+    to_sender: Policy,
+) -> (Vec<u8>, Vec<(usize, Fault)>) {
+    use crate::transport::{loopback_pair, Shared};
+    use crate::xmodem::XModem;
+    let (a, b) = loopback_pair();
+    let mut sender = Faulty::new(a, to_receiver);
+    let mut receiver = Faulty::new(b, to_sender);
+    let source = data.to_vec();
+    let handle = std::thread::spawn(move || {
+        let result = XModem::builder()
+            .packet_timeout(Duration::from_millis(200))
+            .retries(32)
+            .build()
+            .send(&mut sender, Box::new(io::Cursor::new(source)));
+        (result, sender.injected)
+    });
+    let output = Shared::default();
+    XModem::builder()
+        .crc_mode(true)
+        .packet_timeout(Duration::from_millis(200))
+        .retries(32)
+        .build()
+        .receive(&mut receiver, Box::new(output.clone()))
+        .unwrap();
+    let (result, injected) = handle.join().unwrap();
+    result.unwrap();
+    (output.bytes(), injected)
+}
+
+#[cfg(test)]
+#[test]
+fn test_xmodem_recovers_from_scripted_faults() {
+    let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    // Writes from the sender are one per packet, write 0 is packet 1
+    let to_receiver = Policy::Script(vec![
+        (
+            0,
+            Fault::Corrupt {
+                offset: 40,
+                mask: 0x10,
+            },
+        ),
+        (2, Fault::Lose),
+        (4, Fault::Truncate { len: 60 }),
+        (6, Fault::Drop { offset: 3 }),
+        (8, Fault::Delay(Duration::from_millis(100))),
+        (
+            9,
+            Fault::Corrupt {
+                offset: 1,
+                mask: 0x01,
+            },
+        ),
+    ]);
+    // The receiver's poll is write 0, write 3 is an ACK
+    let to_sender = Policy::Script(vec![
+        (3, Fault::Lose),
+        (
+            6,
+            Fault::Corrupt {
+                offset: 0,
+                mask: 0x40,
+            },
+        ),
+    ]);
+    let (received, injected) = faulty_round_trip(&data, to_receiver, to_sender);
+    assert_eq!(received, data);
+    assert_eq!(injected.len(), 6);
+}
+
+#[cfg(test)]
+#[test]
+fn test_xmodem_recovers_from_random_faults() {
+    let data: Vec<u8> = (0..3000).map(|i| (i * 13 + i / 256) as u8).collect();
+    for seed in 1..=10 {
+        let to_receiver = Policy::Random { seed, rate: 0.2 };
+        let to_sender = Policy::Script(vec![]);
+        let (received, injected) = faulty_round_trip(&data, to_receiver, to_sender);
+        assert_eq!(received, data, "seed {seed}, faults {injected:?}");
+    }
+}
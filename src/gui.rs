@@ -1,8 +1,19 @@
-use eframe::{
-    egui::{self, epaint::vec2, Event, Key, Response, Ui},
-    emath::Align,
-};
+use crate::autostart::{AutoStart, Detector};
+use crate::encoding::{Console, Encoding};
+use crate::image::{Image, ImageError};
+use crate::keyboard::{key_sequence, Newline, FUNCTION_KEYS};
+use crate::smp::CHUNK;
+use crate::stk500::Version;
+use crate::stm32::FLASH_BASE;
+use crate::transfer::TransferJob;
+use crate::upgrade::StepState;
+use crate::verify::{Readback, Verdict};
+use crate::vt100::{Cell, Color, Style};
+use crate::xmodem::{BlockLength, XModem, XModemBuilder};
+use eframe::egui::{self, epaint::vec2, Color32, Event, Key, Sense, Ui};
 use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub struct SerialPortSettings {
     /// The baud rate in symbols-per-second
@@ -19,19 +30,261 @@ pub struct SerialPortSettings {
     pub timeout: u64,
 }
 
-pub fn read_byte(port: &mut Box<dyn SerialPort>) -> String {
-    let mut string: Vec<u8> = vec![];
-    let mut read_buffer: Vec<u8> = vec![0; 1];
-    loop {
-        match port.read(&mut read_buffer[..]) {
-            Err(_) => break,
-            Ok(_) => {
-                let byte = read_buffer[0];
-                string.push(byte);
-            }
+/// XModem settings from the transfer options dialog
+pub struct XModemOptions {
+    /// Maximum retries for each packet
+    pub retries: i32,
+    /// Byte the last block is padded with
+    pub padbyte: u8,
+    /// Send 1024 byte blocks
+    pub one_k: bool,
+    /// Receive with CRC-16 rather than the 8bit checksum
+    pub crc_mode: bool,
+    /// Remove the padding from the last block received
+    pub trim_padding: bool,
+    /// Seconds to wait for the peer to start the transfer
+    pub sync_timeout: u64,
+    /// Milliseconds to wait for each packet once the transfer started
+    pub packet_timeout: u64,
+}
+
+impl Default for XModemOptions {
+    fn default() -> Self {
+        Self {
+            retries: 16,
+            padbyte: 0x1A,
+            one_k: false,
+            crc_mode: false,
+            trim_padding: true,
+            sync_timeout: 10,
+            packet_timeout: 3000,
+        }
+    }
+}
+
+impl XModemOptions {
+    pub fn builder(&self) -> XModemBuilder {
+        let block_length = if self.one_k {
+            BlockLength::OneK
+        } else {
+            BlockLength::Standard
+        };
+        XModem::builder()
+            .retries(self.retries)
+            .padbyte(self.padbyte)
+            .block_length(block_length)
+            .crc_mode(self.crc_mode)
+            .trim_padding(self.trim_padding)
+            .sync_timeout(Duration::from_secs(self.sync_timeout))
+            .packet_timeout(Duration::from_millis(self.packet_timeout))
+    }
+}
+
+/// ZModem settings from the transfer options dialog
+#[derive(Default)]
+pub struct ZModemOptions {
+    /// Ask the receiver to continue files it holds part of
+    pub resume: bool,
+}
+
+/// Device readback run after a send, from the transfer options dialog
+pub struct ReadbackOptions {
+    /// Run the readback after each successful send
+    pub enabled: bool,
+    /// Command asking the device for a checksum, `{size}` is the image size
+    pub command: String,
+    /// Milliseconds to wait for the device's reply
+    pub timeout: u64,
+}
+
+impl Default for ReadbackOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            command: "crc32 {size}".to_owned(),
+            timeout: 5000,
+        }
+    }
+}
+
+impl ReadbackOptions {
+    pub fn readback(&self) -> Option<Readback> {
+        if !self.enabled || self.command.trim().is_empty() {
+            return None;
+        }
+        Some(Readback {
+            command: self.command.clone(),
+            timeout: Duration::from_millis(self.timeout),
+        })
+    }
+}
+
+/// Settings of the file transfers from the Transfer menu
+#[derive(Default)]
+pub struct TransferOptions {
+    pub xmodem: XModemOptions,
+    pub zmodem: ZModemOptions,
+    pub readback: ReadbackOptions,
+}
+
+/// Settings of the bootloaders flashed from the Transfer menu
+#[derive(Default)]
+pub struct BootloaderOptions {
+    pub stm32: Stm32Options,
+    pub esp: EspOptions,
+    pub smp: SmpOptions,
+    pub avr: AvrOptions,
+}
+
+/// STM32 bootloader settings from the transfer options dialog
+pub struct Stm32Options {
+    /// Hex address plain binaries are flashed to
+    pub address: String,
+    /// Erase the whole flash rather than the pages the image covers
+    pub mass_erase: bool,
+    /// Start the code once it is flashed
+    pub start: bool,
+}
+
+impl Default for Stm32Options {
+    fn default() -> Self {
+        Self {
+            address: format!("{FLASH_BASE:08X}"),
+            mass_erase: false,
+            start: true,
+        }
+    }
+}
+
+impl Stm32Options {
+    pub fn address(&self) -> Option<u32> {
+        parse_hex(&self.address)
+    }
+}
+
+/// ESP ROM bootloader settings from the transfer options dialog
+pub struct EspOptions {
+    /// Hex flash offset plain binaries are written to, empty picks the
+    /// chip's application offset
+    pub offset: String,
+    /// Baud rate to flash at, 0 keeps the port's own
+    pub baud: u32,
+    /// Reset into the flashed application afterwards
+    pub run: bool,
+}
+
+impl Default for EspOptions {
+    fn default() -> Self {
+        Self {
+            offset: String::new(),
+            baud: 460800,
+            run: true,
+        }
+    }
+}
+
+impl EspOptions {
+    /// None if the offset isn't hex, Some(None) if it is left to the chip
+    pub fn offset(&self) -> Option<Option<u32>> {
+        if self.offset.trim().is_empty() {
+            return Some(None);
         }
+        parse_hex(&self.offset).map(Some)
     }
-    std::str::from_utf8(&string).unwrap().to_string()
+
+    pub fn baud(&self) -> Option<u32> {
+        Some(self.baud).filter(|&baud| baud != 0)
+    }
+}
+
+/// Arduino bootloader settings from the transfer options dialog
+pub struct AvrOptions {
+    pub version: Version,
+    /// Baud rate of the bootloader, 0 keeps the port's
+    pub baud: u32,
+    /// Pulse DTR to reset the board into the bootloader
+    pub reset: bool,
+}
+
+impl Default for AvrOptions {
+    fn default() -> Self {
+        Self {
+            version: Version::V1,
+            baud: 115200,
+            reset: true,
+        }
+    }
+}
+
+impl AvrOptions {
+    pub fn baud(&self) -> Option<u32> {
+        Some(self.baud).filter(|&baud| baud != 0)
+    }
+}
+
+/// MCUmgr settings from the transfer options dialog
+pub struct SmpOptions {
+    /// Image bytes per upload packet
+    pub chunk: usize,
+    /// Mark the uploaded image for a test boot
+    pub test: bool,
+    /// Reset the device once the image is uploaded
+    pub reset: bool,
+}
+
+impl Default for SmpOptions {
+    fn default() -> Self {
+        Self {
+            chunk: CHUNK,
+            test: false,
+            reset: true,
+        }
+    }
+}
+
+/// Parses a hex number with or without its 0x prefix
+fn parse_hex(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// A firmware image picked for an XModem send, waiting for the user to
+/// choose how it is sent
+pub struct ImageOffer {
+    pub path: PathBuf,
+    pub image: Result<Image, ImageError>,
+    pub options: XModemBuilder,
+    /// Byte the gaps between regions are filled with when flattened
+    pub fill: u8,
+}
+
+impl ImageOffer {
+    pub fn new(path: PathBuf, options: XModemBuilder) -> Self {
+        let image = Image::load(&path);
+        if let Err(err) = &image {
+            println!("Can't load {}: {err}", path.display());
+        }
+        Self {
+            path,
+            image,
+            options,
+            fill: 0xFF,
+        }
+    }
+}
+
+/// How to send a firmware image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageChoice {
+    /// Write the file to the console as it is
+    Text,
+    /// Flatten to a binary and send it over XModem
+    Flattened,
+    Cancel,
 }
 
 impl Default for SerialPortSettings {
@@ -47,14 +300,6 @@ impl Default for SerialPortSettings {
     }
 }
 
-pub fn selectable_text(ui: &mut egui::Ui, mut text: &str) -> Response {
-    ui.add(
-        egui::TextEdit::multiline(&mut text)
-            .desired_width(1000.0)
-            .desired_rows(25),
-    )
-}
-
 pub fn comport_setting_combo_box(
     ui: &mut Ui,
     selected_comport: &mut String,
@@ -112,6 +357,38 @@ pub fn flowcontrol_setting_combo_box(ui: &mut Ui, flow_control: &mut FlowControl
     });
 }
 
+/// Picks how the console shows received bytes, everything received is
+/// shown again in the new encoding
+pub fn encoding_combo_box(ui: &mut Ui, console: &mut Console) {
+    let mut encoding = console.encoding();
+    ui.horizontal(|ui| {
+        ui.label("Encoding:");
+        egui::ComboBox::from_id_source("Encoding")
+            .selected_text(encoding.to_string())
+            .show_ui(ui, |ui| {
+                for choice in Encoding::ALL {
+                    ui.selectable_value(&mut encoding, choice, choice.to_string());
+                }
+            })
+    });
+    if encoding != console.encoding() {
+        console.set_encoding(encoding);
+    }
+}
+
+pub fn newline_combo_box(ui: &mut Ui, newline: &mut Newline) {
+    ui.horizontal(|ui| {
+        ui.label("Enter sends:");
+        egui::ComboBox::from_id_source("Newline")
+            .selected_text(newline.to_string())
+            .show_ui(ui, |ui| {
+                for choice in Newline::ALL {
+                    ui.selectable_value(newline, choice, choice.to_string());
+                }
+            })
+    });
+}
+
 pub fn parity_setting_combo_box(ui: &mut Ui, parity: &mut Parity) {
     ui.horizontal(|ui| {
         ui.label("Parity:");
@@ -172,34 +449,502 @@ pub fn serial_settings_window(
         });
 }
 
-pub fn terminal(ui: &mut Ui, console_text: &mut String, serial_port: &mut Box<dyn SerialPort>) {
-    egui::ScrollArea::vertical().show(ui, |ui| {
-        if selectable_text(ui, console_text).has_focus() {
-            let events = ui.input().events.clone(); // avoid dead-lock by cloning. TODO: optimize
-            for event in &events {
-                match event {
-                    Event::Text(text) => {
-                        // Newlines are handled by `Key::Enter`.
-                        if !text.is_empty() && text != "\n" && text != "\r" {
-                            serial_port.write(text.as_bytes()).unwrap();
+pub fn transfer_options_window(
+    ctx: &egui::Context,
+    transfers: &mut TransferOptions,
+    bootloaders: &mut BootloaderOptions,
+    open: &mut bool,
+) {
+    let TransferOptions {
+        xmodem: options,
+        zmodem,
+        readback,
+    } = transfers;
+    let BootloaderOptions {
+        stm32,
+        esp,
+        smp,
+        avr,
+    } = bootloaders;
+    egui::Window::new("Transfer Options")
+        .open(open)
+        .collapsible(true)
+        .show(ctx, |ui| {
+            ui.group(|ui| {
+                ui.label("xModem Parameters");
+                ui.horizontal(|ui| {
+                    ui.label("Retries:");
+                    ui.add(egui::DragValue::new(&mut options.retries).clamp_range(0..=255));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Pad Byte:");
+                    ui.add(egui::DragValue::new(&mut options.padbyte));
+                });
+                ui.checkbox(&mut options.one_k, "Send 1K blocks");
+                ui.checkbox(&mut options.crc_mode, "Receive with CRC-16");
+                ui.checkbox(
+                    &mut options.trim_padding,
+                    "Trim padding from received files",
+                );
+                ui.horizontal(|ui| {
+                    ui.label("Sync Timeout (s):");
+                    ui.add(egui::DragValue::new(&mut options.sync_timeout).clamp_range(1..=600));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Packet Timeout (ms):");
+                    ui.add(
+                        egui::DragValue::new(&mut options.packet_timeout).clamp_range(10..=60000),
+                    );
+                });
+            });
+            ui.group(|ui| {
+                ui.label("zModem Parameters");
+                ui.checkbox(&mut zmodem.resume, "Resume partial files on the receiver");
+            });
+            ui.group(|ui| {
+                ui.label("Verification");
+                ui.checkbox(&mut readback.enabled, "Read back checksum after send");
+                ui.horizontal(|ui| {
+                    ui.label("Command:");
+                    ui.text_edit_singleline(&mut readback.command);
+                });
+                ui.label("{size} is replaced by the image size in bytes");
+                ui.horizontal(|ui| {
+                    ui.label("Reply Timeout (ms):");
+                    ui.add(egui::DragValue::new(&mut readback.timeout).clamp_range(100..=60000));
+                });
+            });
+            ui.group(|ui| {
+                ui.label("STM32 Bootloader");
+                ui.horizontal(|ui| {
+                    ui.label("Binary Address (hex):");
+                    ui.text_edit_singleline(&mut stm32.address);
+                });
+                if stm32.address().is_none() {
+                    ui.colored_label(egui::Color32::RED, "Not a hex address");
+                }
+                ui.checkbox(&mut stm32.mass_erase, "Mass erase the whole flash");
+                ui.checkbox(&mut stm32.start, "Start the code after flashing");
+            });
+            ui.group(|ui| {
+                ui.label("ESP Bootloader");
+                ui.horizontal(|ui| {
+                    ui.label("Binary Offset (hex):");
+                    ui.text_edit_singleline(&mut esp.offset);
+                });
+                ui.label("Empty writes at 0x10000 on an ESP32, 0x0 on an ESP8266");
+                if esp.offset().is_none() {
+                    ui.colored_label(egui::Color32::RED, "Not a hex offset");
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Flash Baud Rate (0 keeps the port's):");
+                    ui.add(egui::DragValue::new(&mut esp.baud).clamp_range(0..=2000000));
+                });
+                ui.checkbox(&mut esp.run, "Run the application after flashing");
+            });
+            ui.group(|ui| {
+                ui.label("MCUboot (SMP)");
+                ui.horizontal(|ui| {
+                    ui.label("Upload Chunk (bytes):");
+                    ui.add(egui::DragValue::new(&mut smp.chunk).clamp_range(32..=2048));
+                });
+                ui.checkbox(&mut smp.test, "Mark the upload for a test boot");
+                ui.checkbox(&mut smp.reset, "Reset after upload");
+            });
+            ui.group(|ui| {
+                ui.label("Arduino Bootloader (STK500)");
+                ui.horizontal(|ui| {
+                    ui.label("Protocol:");
+                    ui.radio_value(&mut avr.version, Version::V1, "v1 (Optiboot)");
+                    ui.radio_value(&mut avr.version, Version::V2, "v2 (ATmega2560)");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Bootloader Baud Rate (0 keeps the port's):");
+                    ui.add(egui::DragValue::new(&mut avr.baud).clamp_range(0..=2000000));
+                });
+                ui.checkbox(&mut avr.reset, "Reset the board with DTR");
+            });
+        });
+}
+
+/// Shows the regions of a firmware image and asks how to send it
+pub fn image_window(ctx: &egui::Context, offer: &mut ImageOffer) -> Option<ImageChoice> {
+    let mut choice = None;
+    egui::Window::new("Firmware Image")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, vec2(0.0, 0.0))
+        .show(ctx, |ui| {
+            ui.label(offer.path.display().to_string());
+            ui.separator();
+            match &offer.image {
+                Ok(image) => {
+                    for (start, end) in image.ranges() {
+                        ui.monospace(format!(
+                            "{start:#010x} - {:#010x}  {} bytes",
+                            end - 1,
+                            end - start as u64
+                        ));
+                    }
+                    ui.label(format!("Data: {} bytes", image.data_size()));
+                    if let Some(entry) = image.entry {
+                        ui.monospace(format!("Entry: {entry:#010x}"));
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Fill Byte:");
+                        ui.add(egui::DragValue::new(&mut offer.fill));
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Send as Text").clicked() {
+                            choice = Some(ImageChoice::Text);
+                        }
+                        if ui.button("Send Flattened (xModem)").clicked() {
+                            choice = Some(ImageChoice::Flattened);
                         }
+                        if ui.button("Cancel").clicked() {
+                            choice = Some(ImageChoice::Cancel);
+                        }
+                    });
+                }
+                Err(err) => {
+                    ui.colored_label(egui::Color32::RED, format!("Invalid image: {err}"));
+                    if ui.button("Close").clicked() {
+                        choice = Some(ImageChoice::Cancel);
                     }
-                    Event::Key {
-                        key: Key::Enter,
-                        pressed: true,
-                        ..
-                    } => {
-                        serial_port.write("\r\n".as_bytes()).unwrap();
+                }
+            }
+        });
+    choice
+}
+
+/// Shows the progress of a running transfer with a Cancel button,
+/// returns true once the finished transfer is dismissed.
+pub fn transfer_window(ctx: &egui::Context, job: &TransferJob) -> bool {
+    let mut close = false;
+    let progress = job.progress();
+    egui::Window::new(job.title())
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, vec2(0.0, 0.0))
+        .show(ctx, |ui| {
+            if let Some(steps) = job.steps() {
+                for (index, (step, state)) in steps.iter().enumerate() {
+                    let text = format!("{}. {step}", index + 1);
+                    match state {
+                        StepState::Pending => ui.weak(text),
+                        StepState::Running => ui.strong(text),
+                        StepState::Done(detail) => {
+                            ui.colored_label(egui::Color32::GREEN, format!("{text}: {detail}"))
+                        }
+                        StepState::Failed(message) => {
+                            ui.colored_label(egui::Color32::RED, format!("{text}: {message}"))
+                        }
+                    };
+                }
+                ui.separator();
+            }
+            match progress.fraction() {
+                Some(fraction) => ui.add(egui::ProgressBar::new(fraction).show_percentage()),
+                None => ui.add(egui::ProgressBar::new(0.0).animate(job.outcome().is_none())),
+            };
+            match progress.total {
+                Some(total) => ui.label(format!("Bytes: {} / {}", progress.bytes, total)),
+                None => ui.label(format!("Bytes: {}", progress.bytes)),
+            };
+            ui.label(format!("Packets: {}", progress.packets));
+            ui.label(format!("Errors: {}", progress.retries));
+            ui.label(format!(
+                "Throughput: {:.0} bytes/s, Elapsed: {:.1} s",
+                job.throughput(),
+                job.elapsed().as_secs_f32()
+            ));
+            ui.separator();
+            match job.outcome() {
+                None => {
+                    ui.label(format!("State: {:?}", progress.state));
+                    if job.is_cancelled() {
+                        ui.label("Cancelling...");
+                    } else if ui.button("Cancel").clicked() {
+                        job.cancel();
                     }
-                    _ => (),
-                };
-                ui.scroll_to_cursor(Some(Align::BOTTOM));
+                }
+                Some(outcome) => {
+                    match outcome {
+                        Ok(message) => ui.label(message),
+                        Err(err) => ui.colored_label(egui::Color32::RED, format!("Error: {err}")),
+                    };
+                    if let Some(report) = job.report() {
+                        for (path, digest) in &report.files {
+                            ui.label(path.display().to_string());
+                            ui.monospace(format!("CRC32   {}", digest.crc32_hex()));
+                            ui.monospace(format!("SHA-256 {}", digest.sha256_hex()));
+                        }
+                        match &report.verdict {
+                            Verdict::NotChecked => ui.label(report.verdict.to_string()),
+                            verdict if verdict.is_pass() => {
+                                ui.colored_label(egui::Color32::GREEN, verdict.to_string())
+                            }
+                            verdict => ui.colored_label(egui::Color32::RED, verdict.to_string()),
+                        };
+                    }
+                    if ui.button("Close").clicked() {
+                        close = true;
+                    }
+                }
+            }
+        });
+    close
+}
+
+/// Shows the console screen, sends what is typed to the port and runs what
+/// the reader received through the terminal. Returns a transfer the device
+/// started, if any.
+pub fn terminal(
+    ui: &mut Ui,
+    console: &mut Console,
+    serial_port: &mut Box<dyn SerialPort>,
+    received: &[u8],
+    detector: &mut Detector,
+    newline: Newline,
+) -> Option<AutoStart> {
+    let mut detected = None;
+    if !received.is_empty() {
+        console.push(received);
+        detected = detector.feed(received);
+        if let Some(transfer) = detected {
+            // The handshake and what follows it is protocol, not text
+            console.truncate_from_last(transfer.pattern());
+        }
+    }
+    // Cursor position and device attribute reports
+    let replies = console.screen_mut().take_replies();
+    if !replies.is_empty() {
+        let _ = serial_port.write_all(&replies);
+    }
+
+    // The screen fills the panel, less the scroll bar
+    let font = egui::TextStyle::Monospace.resolve(ui.style());
+    let cell = {
+        let fonts = ui.fonts();
+        vec2(fonts.glyph_width(&font, 'M'), fonts.row_height(&font))
+    };
+    let available = ui.available_size() - vec2(ui.spacing().scroll_bar_width + 4.0, 0.0);
+    let cols = (available.x / cell.x) as usize;
+    let rows = (available.y / cell.y) as usize;
+    console.screen_mut().resize(rows, cols);
+
+    let screen = console.screen();
+    let (rows, cols) = screen.size();
+    let lines = screen.scrollback_len() + rows;
+    let mut focused = false;
+    let mut typed = vec![];
+    egui::ScrollArea::vertical()
+        .stick_to_bottom()
+        .show_viewport(ui, |ui, viewport| {
+            let size = vec2(cols as f32 * cell.x, lines as f32 * cell.y);
+            let (rect, response) = ui.allocate_exact_size(size, Sense::click());
+            // Escape lets go of the focus before anyone sees the key, the
+            // device should get it instead
+            let escaped = response.lost_focus() && ui.input().key_pressed(Key::Escape);
+            if response.clicked() || escaped {
+                response.request_focus();
             }
-            let result = read_byte(serial_port);
-            if result.len() > 0 {
-                console_text.push_str(&result);
-                ui.scroll_to_cursor(Some(Align::BOTTOM));
+            focused = response.has_focus();
+            if focused {
+                // Tab goes to the device rather than the next widget
+                ui.memory().lock_focus(response.id, true);
             }
+            let painter = ui.painter();
+            let visuals = ui.visuals();
+            painter.rect_filled(rect, 0.0, visuals.extreme_bg_color);
+            let first = (viewport.min.y / cell.y).max(0.0) as usize;
+            let last = ((viewport.max.y / cell.y).ceil() as usize).min(lines);
+            for index in first..last {
+                let line = match screen.line(index) {
+                    Some(line) => line,
+                    None => continue,
+                };
+                let cursor = match index.checked_sub(screen.scrollback_len()) {
+                    Some(row) if focused && screen.cursor_visible() && row == screen.cursor().0 => {
+                        Some(screen.cursor().1)
+                    }
+                    _ => None,
+                };
+                let origin = rect.min + vec2(0.0, index as f32 * cell.y);
+                paint_line(painter, visuals, &font, origin, cell, line, cursor);
+            }
+            response.context_menu(|ui| {
+                if ui.button("Copy Screen").clicked() {
+                    ui.output().copied_text = screen.text();
+                    ui.close_menu();
+                }
+                // Keys the window system doesn't pass on
+                ui.menu_button("Send Key", |ui| {
+                    for (index, sequence) in FUNCTION_KEYS.iter().enumerate() {
+                        if ui.button(format!("F{}", index + 1)).clicked() {
+                            typed.extend_from_slice(sequence);
+                            ui.close_menu();
+                        }
+                    }
+                    if ui.button("Ctrl+]").clicked() {
+                        typed.push(0x1D);
+                        ui.close_menu();
+                    }
+                });
+            });
+        });
+
+    if focused {
+        let application_cursor = console.screen().application_cursor();
+        let events = ui.input().events.clone(); // avoid dead-lock by cloning. TODO: optimize
+        for event in &events {
+            match event {
+                Event::Text(text) => {
+                    // Newlines are handled by `Key::Enter`.
+                    if !text.is_empty() && text != "\n" && text != "\r" {
+                        typed.extend_from_slice(text.as_bytes());
+                    }
+                }
+                Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                } => {
+                    if let Some(sequence) =
+                        key_sequence(*key, *modifiers, application_cursor, newline)
+                    {
+                        typed.extend(sequence);
+                    }
+                }
+                _ => (),
+            };
         }
-    });
+    }
+    if !typed.is_empty() {
+        let _ = serial_port.write_all(&typed);
+    }
+    detected
+}
+
+/// Paints a line of cells in runs of the same style, the cursor inverted
+fn paint_line(
+    painter: &egui::Painter,
+    visuals: &egui::Visuals,
+    font: &egui::FontId,
+    origin: egui::Pos2,
+    cell: egui::Vec2,
+    line: &[Cell],
+    cursor: Option<usize>,
+) {
+    let mut start = 0;
+    while start < line.len() {
+        let inverted = cursor == Some(start);
+        let style = line[start].style;
+        let mut end = start + 1;
+        while end < line.len() && line[end].style == style && cursor != Some(end) && !inverted {
+            end += 1;
+        }
+        let (mut fg, mut bg) = cell_colors(&style, visuals);
+        if inverted {
+            std::mem::swap(&mut fg, &mut bg);
+        }
+        let rect = egui::Rect::from_min_size(
+            origin + vec2(start as f32 * cell.x, 0.0),
+            vec2((end - start) as f32 * cell.x, cell.y),
+        );
+        if bg != visuals.extreme_bg_color {
+            painter.rect_filled(rect, 0.0, bg);
+        }
+        let text: String = line[start..end].iter().map(|cell| cell.ch).collect();
+        if !text.trim_end().is_empty() {
+            painter.text(rect.min, egui::Align2::LEFT_TOP, text, font.clone(), fg);
+        }
+        if style.underline {
+            painter.line_segment(
+                [rect.left_bottom(), rect.right_bottom()],
+                egui::Stroke::new(1.0, fg),
+            );
+        }
+        start = end;
+    }
+}
+
+/// Foreground and background of a cell on the theme's colors
+fn cell_colors(style: &Style, visuals: &egui::Visuals) -> (Color32, Color32) {
+    let fg = match style.fg {
+        // Bold shows the bright variant of the first eight colors
+        Color::Indexed(index) if style.bold && index < 8 => Color::Indexed(index + 8),
+        color => color,
+    };
+    let mut fg = color32(fg, visuals.text_color());
+    if style.dim {
+        fg = fg.linear_multiply(0.6);
+    }
+    let bg = color32(style.bg, visuals.extreme_bg_color);
+    if style.reverse {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    }
+}
+
+/// xterm's colors for the 256 color palette
+fn color32(color: Color, default: Color32) -> Color32 {
+    const ANSI: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match color {
+        Color::Default => default,
+        Color::Indexed(index @ 0..=15) => {
+            let (r, g, b) = ANSI[index as usize];
+            Color32::from_rgb(r, g, b)
+        }
+        Color::Indexed(index @ 16..=231) => {
+            let index = (index - 16) as usize;
+            Color32::from_rgb(LEVELS[index / 36], LEVELS[index / 6 % 6], LEVELS[index % 6])
+        }
+        Color::Indexed(index) => Color32::from_gray(8 + (index - 232) * 10),
+        Color::Rgb(r, g, b) => Color32::from_rgb(r, g, b),
+    }
+}
+
+/// Asks whether to run a transfer the device started,
+/// returns the answer once one of the buttons is clicked.
+pub fn autostart_window(ctx: &egui::Context, transfer: AutoStart) -> Option<bool> {
+    let mut answer = None;
+    egui::Window::new("Transfer Requested")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, vec2(0.0, 0.0))
+        .show(ctx, |ui| {
+            match transfer {
+                AutoStart::ZModemReceive => ui.label("The device is sending files with zModem."),
+                AutoStart::YModemSend => ui.label("The device is waiting for files over yModem."),
+            };
+            ui.horizontal(|ui| {
+                if ui.button(transfer.title()).clicked() {
+                    answer = Some(true);
+                }
+                if ui.button("Decline").clicked() {
+                    answer = Some(false);
+                }
+            });
+        });
+    answer
 }
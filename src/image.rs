@@ -0,0 +1,402 @@
+use std::path::Path;
+
+/// Largest span a flattened image may cover, a gap between far apart
+/// regions would otherwise be filled out to a huge binary.
+const MAX_FLAT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Errors loading or flattening a firmware image
+#[derive(Debug)]
+pub enum ImageError {
+    Io(std::io::Error),
+    /// The extension isn't one of the supported formats
+    UnknownFormat,
+    /// A record that can't be parsed, with its line number
+    Record {
+        line: usize,
+        reason: &'static str,
+    },
+    /// A record whose checksum doesn't match its contents
+    Checksum {
+        line: usize,
+    },
+    /// Two records write the same address
+    Overlap(u32),
+    Empty,
+    /// The regions span more than can be flattened
+    TooLarge(u64),
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "IO error: {err}"),
+            ImageError::UnknownFormat => write!(f, "Not an Intel HEX or S-record file"),
+            ImageError::Record { line, reason } => write!(f, "Line {line}: {reason}"),
+            ImageError::Checksum { line } => write!(f, "Line {line}: checksum mismatch"),
+            ImageError::Overlap(address) => write!(f, "Data overlaps at {address:#010x}"),
+            ImageError::Empty => write!(f, "The image holds no data"),
+            ImageError::TooLarge(size) => {
+                write!(f, "The image spans {size} bytes, too large to flatten")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<std::io::Error> for ImageError {
+    fn from(err: std::io::Error) -> Self {
+        ImageError::Io(err)
+    }
+}
+
+/// Contiguous data at an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// A firmware image loaded from Intel HEX or Motorola S-records
+#[derive(Clone, Debug, Default)]
+pub struct Image {
+    /// Segments sorted by address, none overlap or touch
+    pub segments: Vec<Segment>,
+    /// Start address from the file, if it has one
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    /// True if the file extension is a format `load` reads
+    pub fn is_image(path: &Path) -> bool {
+        Format::of(path).is_some()
+    }
+
+    /// Loads the file, picking the format from its extension
+    pub fn load(path: &Path) -> Result<Self, ImageError> {
+        let format = Format::of(path).ok_or(ImageError::UnknownFormat)?;
+        let text = std::fs::read_to_string(path)?;
+        match format {
+            Format::IntelHex => Self::parse_ihex(&text),
+            Format::SRecord => Self::parse_srec(&text),
+        }
+    }
+
+    pub fn parse_ihex(text: &str) -> Result<Self, ImageError> {
+        let mut image = Builder::default();
+        let mut base: u32 = 0;
+        for (index, line) in text.lines().enumerate() {
+            let line_num = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line.strip_prefix(':').ok_or(ImageError::Record {
+                line: line_num,
+                reason: "missing ':' start code",
+            })?;
+            let bytes = decode_hex(record, line_num)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(ImageError::Record {
+                    line: line_num,
+                    reason: "length doesn't match the byte count",
+                });
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(ImageError::Checksum { line: line_num });
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            let bad_length = ImageError::Record {
+                line: line_num,
+                reason: "wrong length for the record type",
+            };
+            match bytes[3] {
+                0x00 => image.push(base.wrapping_add(offset), data),
+                0x01 => break,
+                0x02 if data.len() == 2 => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+                }
+                0x04 if data.len() == 2 => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+                }
+                0x03 if data.len() == 4 => {
+                    // CS:IP, the real mode address it stands for
+                    let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                    image.entry = Some((cs << 4) + ip);
+                }
+                0x05 if data.len() == 4 => {
+                    image.entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                }
+                0x02..=0x05 => return Err(bad_length),
+                _ => {
+                    return Err(ImageError::Record {
+                        line: line_num,
+                        reason: "unknown record type",
+                    })
+                }
+            }
+        }
+        image.finish()
+    }
+
+    pub fn parse_srec(text: &str) -> Result<Self, ImageError> {
+        let mut image = Builder::default();
+        for (index, line) in text.lines().enumerate() {
+            let line_num = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut chars = line.chars();
+            let kind = match (chars.next(), chars.next()) {
+                (Some('S'), Some(kind)) => kind,
+                _ => {
+                    return Err(ImageError::Record {
+                        line: line_num,
+                        reason: "missing 'S' start code",
+                    })
+                }
+            };
+            let bytes = decode_hex(chars.as_str(), line_num)?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(ImageError::Record {
+                    line: line_num,
+                    reason: "length doesn't match the byte count",
+                });
+            }
+            let sum = bytes[..bytes.len() - 1]
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if !sum != bytes[bytes.len() - 1] {
+                return Err(ImageError::Checksum { line: line_num });
+            }
+            let address_length = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => {
+                    return Err(ImageError::Record {
+                        line: line_num,
+                        reason: "unknown record type",
+                    })
+                }
+            };
+            if bytes.len() < address_length + 2 {
+                return Err(ImageError::Record {
+                    line: line_num,
+                    reason: "record too short for its address",
+                });
+            }
+            let address = bytes[1..1 + address_length]
+                .iter()
+                .fold(0u32, |address, byte| (address << 8) | *byte as u32);
+            let data = &bytes[1 + address_length..bytes.len() - 1];
+            match kind {
+                '1' | '2' | '3' => image.push(address, data),
+                '7' | '8' | '9' => image.entry = Some(address),
+                // Header and record counts carry no data
+                _ => (),
+            }
+        }
+        image.finish()
+    }
+
+    /// Address ranges covered by the image, end exclusive
+    pub fn ranges(&self) -> Vec<(u32, u64)> {
+        self.segments
+            .iter()
+            .map(|segment| (segment.address, segment.end()))
+            .collect()
+    }
+
+    /// Bytes of data in the image, gaps not counted
+    pub fn data_size(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.data.len() as u64)
+            .sum()
+    }
+
+    /// One binary from the lowest address to the highest, gaps filled
+    /// with the fill byte. Returns the start address with the binary.
+    pub fn flatten(&self, fill: u8) -> Result<(u32, Vec<u8>), ImageError> {
+        let (first, last) = match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(ImageError::Empty),
+        };
+        let size = last.end() - first.address as u64;
+        if size > MAX_FLAT_SIZE {
+            return Err(ImageError::TooLarge(size));
+        }
+        let mut binary = vec![fill; size as usize];
+        for segment in &self.segments {
+            let start = (segment.address - first.address) as usize;
+            binary[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Ok((first.address, binary))
+    }
+}
+
+enum Format {
+    IntelHex,
+    SRecord,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "hex" | "ihex" | "ihx" => Some(Format::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(Format::SRecord),
+            _ => None,
+        }
+    }
+}
+
+/// Collects data records into segments as a file is parsed
+#[derive(Default)]
+struct Builder {
+    segments: Vec<Segment>,
+    entry: Option<u32>,
+}
+
+impl Builder {
+    fn push(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        // Records usually follow on from the one before
+        if let Some(last) = self.segments.last_mut() {
+            if last.end() == address as u64 {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+        self.segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Sorts the segments and joins the ones that touch
+    fn finish(mut self) -> Result<Image, ImageError> {
+        if self.segments.is_empty() {
+            return Err(ImageError::Empty);
+        }
+        self.segments.sort_by_key(|segment| segment.address);
+        let mut segments: Vec<Segment> = vec![];
+        for segment in self.segments {
+            match segments.last_mut() {
+                Some(last) if (segment.address as u64) < last.end() => {
+                    return Err(ImageError::Overlap(segment.address));
+                }
+                Some(last) if segment.address as u64 == last.end() => {
+                    last.data.extend_from_slice(&segment.data);
+                }
+                _ => segments.push(segment),
+            }
+        }
+        Ok(Image {
+            segments,
+            entry: self.entry,
+        })
+    }
+}
+
+fn decode_hex(text: &str, line: usize) -> Result<Vec<u8>, ImageError> {
+    if !text.is_ascii() {
+        return Err(ImageError::Record {
+            line,
+            reason: "invalid hex digit",
+        });
+    }
+    if text.len() & 1 == 1 {
+        return Err(ImageError::Record {
+            line,
+            reason: "odd number of hex digits",
+        });
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| ImageError::Record {
+                line,
+                reason: "invalid hex digit",
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_intel_hex() {
+    let text = "\
+:020000040800F2
+:10000000000102030405060708090A0B0C0D0E0F78
+:0400100010111213A6
+:02002000AABB79
+:0400000508000101ED
+:00000001FF
+";
+    let image = Image::parse_ihex(text).unwrap();
+    assert_eq!(
+        image.ranges(),
+        vec![(0x0800_0000, 0x0800_0014), (0x0800_0020, 0x0800_0022)]
+    );
+    assert_eq!(image.entry, Some(0x0800_0101));
+    assert_eq!(image.data_size(), 22);
+    let (address, binary) = image.flatten(0xFF).unwrap();
+    assert_eq!(address, 0x0800_0000);
+    assert_eq!(binary.len(), 0x22);
+    assert_eq!(binary[0x13], 0x13);
+    assert_eq!(binary[0x14..0x20], [0xFF; 12]);
+    assert_eq!(binary[0x20..], [0xAA, 0xBB]);
+
+    let corrupt = text.replace(":0400100010111213A6", ":0400100010111213A7");
+    assert!(matches!(
+        Image::parse_ihex(&corrupt),
+        Err(ImageError::Checksum { line: 3 })
+    ));
+    // A non-ASCII digit is an invalid record, not a panic
+    assert!(matches!(
+        Image::parse_ihex(":\u{e9}0000"),
+        Err(ImageError::Record { line: 1, .. })
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn test_srec() {
+    let text = "\
+S00600004844521B
+S1130000000102030405060708090A0B0C0D0E0F74
+S10700200A0B0C0DAA
+S5030002FA
+S9030000FC
+";
+    let image = Image::parse_srec(text).unwrap();
+    assert_eq!(image.ranges(), vec![(0x00, 0x10), (0x20, 0x24)]);
+    assert_eq!(image.entry, Some(0));
+    let (_, binary) = image.flatten(0x00).unwrap();
+    assert_eq!(binary.len(), 0x24);
+    assert_eq!(binary[0x20..], [0x0A, 0x0B, 0x0C, 0x0D]);
+
+    let corrupt = text.replace("S10700200A0B0C0DAA", "S10700200A0B0C0EAA");
+    assert!(matches!(
+        Image::parse_srec(&corrupt),
+        Err(ImageError::Checksum { line: 3 })
+    ));
+    // Not text a record can start with, rather than a panic
+    assert!(matches!(
+        Image::parse_srec("S\u{e9}0300"),
+        Err(ImageError::Record { line: 1, .. })
+    ));
+}
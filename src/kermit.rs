@@ -0,0 +1,903 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use crate::ymodem::file_name;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct Kermit {
+    /// Maximum retries
+    retries: i32,
+    /// Time to wait for a packet before it counts as lost
+    timeout: Duration,
+    /// Longest packet we accept, packets over 94 bytes need long packets
+    max_length: usize,
+    /// Largest sliding window we offer
+    window_size: usize,
+    /// Parameters agreed on with the peer
+    params: Params,
+    /// Sequence number of the next packet we send
+    seq: u8,
+    /// Bytes read from the device but not used yet
+    rx: VecDeque<u8>,
+    /// Progress of the batch and its cancel token
+    reporter: Reporter,
+}
+
+/// Transfer parameters, the defaults are the ones in effect before the
+/// Send-Init exchange.
+struct Params {
+    /// Longest packet the peer accepts
+    max_length: usize,
+    /// Character the peer wants after each packet
+    eol: u8,
+    /// Prefix the peer quotes control characters with
+    qctl: u8,
+    /// Prefix for 8th bit quoting, only if both sides agreed to it
+    qbin: Option<u8>,
+    /// Block check type, 1 or 3
+    check: u8,
+    /// Sliding window size
+    window: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            max_length: 80,
+            eol: CR,
+            qctl: QCTL,
+            qbin: None,
+            check: 1,
+            window: 1,
+        }
+    }
+}
+
+/// A packet with its data field still encoded
+struct Packet {
+    seq: u8,
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// A data packet waiting in the window for its ACK
+struct Slot {
+    seq: u8,
+    frame: Vec<u8>,
+    /// File bytes carried by the packet, before encoding
+    len: usize,
+    acked: bool,
+    retries: i32,
+}
+
+const MARK: u8 = 0x01;
+const CR: u8 = 0x0d;
+const QCTL: u8 = b'#';
+
+// Packet types
+const SEND_INIT: u8 = b'S';
+const FILE_HEADER: u8 = b'F';
+const DATA: u8 = b'D';
+const EOF: u8 = b'Z';
+const BREAK: u8 = b'B';
+const ACK: u8 = b'Y';
+const NAK: u8 = b'N';
+const ERROR: u8 = b'E';
+
+// CAPAS bits
+const CAPAS_LONG: u8 = 0x02;
+const CAPAS_WINDOWS: u8 = 0x04;
+
+/// Longest packet the protocol can describe
+const MAX_LONG_LENGTH: usize = 9024;
+/// Bytes skipped while looking for a packet before giving up
+const MAX_GARBAGE: usize = 16384;
+
+impl Kermit {
+    pub fn new() -> Self {
+        Self {
+            retries: 10,
+            timeout: Duration::from_secs(5),
+            max_length: 1024,
+            window_size: 16,
+            params: Params::default(),
+            seq: 0,
+            rx: VecDeque::new(),
+            reporter: Reporter::default(),
+        }
+    }
+
+    /// Sets the function called every time the transfer progresses
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    /// Token that cancels the transfer from another thread
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Sends a batch of files over the Kermit protocol
+    pub fn send(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        let total = files
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        self.reporter.set_total(Some(total));
+        self.reporter.start();
+        let result = self.send_files(device, files);
+        self.reporter.finish(result)
+    }
+
+    fn send_files(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        self.reset();
+        let init = self.init_data();
+        let reply = self.send_and_wait(device, SEND_INIT, &init)?;
+        self.negotiate(&reply.data);
+        println!(
+            "Kermit parameters: packet length {}, window {}, check type {}",
+            self.params.max_length, self.params.window, self.params.check
+        );
+
+        for path in files {
+            let name = file_name(path)?;
+            let mut file = File::open(path).map_err(TransferError::StreamRead)?;
+            println!("Kermit Send: {name}");
+            let name = encode(name.as_bytes(), QCTL, self.params.qbin);
+            self.send_and_wait(device, FILE_HEADER, &name)?;
+            self.reporter.set_state(TransferState::Transferring);
+            self.send_data(device, &mut file)?;
+            self.send_and_wait(device, EOF, &[])?;
+        }
+        self.reporter.set_state(TransferState::Finishing);
+        self.send_and_wait(device, BREAK, &[])?;
+        Ok(())
+    }
+
+    /// Receives a batch of files over the Kermit protocol into a directory,
+    /// returns the paths of the received files.
+    pub fn receive(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        self.reporter.set_total(None);
+        self.reporter.start();
+        let result = self.receive_files(device, directory);
+        self.reporter.finish(result)
+    }
+
+    fn receive_files(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        self.reset();
+        let mut errors = 0;
+        let init = loop {
+            self.check_cancel(device)?;
+            match self.read_packet(device) {
+                Ok(packet) if packet.kind == SEND_INIT => break packet,
+                Ok(packet) if packet.kind == ERROR => return Err(self.peer_error(&packet)),
+                Ok(_) => (),
+                Err(err) if err.is_recoverable() => {
+                    println!("Error Count: {errors}, Error: {err}");
+                    self.send_packet(device, 0, NAK, &[])?;
+                }
+                Err(err) => return Err(err),
+            }
+            errors += 1;
+            if errors > self.retries {
+                return Err(TransferError::SyncTimeout);
+            }
+        };
+        let init_ack = self.init_data();
+        self.send_packet(device, init.seq, ACK, &init_ack)?;
+        self.negotiate(&init.data);
+        println!(
+            "Kermit parameters: packet length {}, window {}, check type {}",
+            self.params.max_length, self.params.window, self.params.check
+        );
+
+        let mut received = vec![];
+        let mut current: Option<(PathBuf, File)> = None;
+        // Packets that arrived ahead of a lost one, kept until the gap is filled
+        let mut pending: HashMap<u8, Packet> = HashMap::new();
+        let mut expected = next_seq(init.seq);
+        errors = 0;
+        loop {
+            self.check_cancel(device)?;
+            if errors > self.retries {
+                self.send_error(device, "Too many retries");
+                return Err(TransferError::TooManyRetries);
+            }
+            let packet = match self.read_packet(device) {
+                Ok(packet) => packet,
+                Err(err) if err.is_recoverable() => {
+                    errors += 1;
+                    self.reporter.count_retry();
+                    println!("Error Count: {errors}, Error: {err}");
+                    self.send_packet(device, expected, NAK, &[])?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            if packet.seq != expected {
+                let ahead = (packet.seq + 64 - expected) % 64;
+                if packet.kind == SEND_INIT {
+                    // Our ACK to the Send-Init was lost, it always uses block check 1
+                    let check = self.params.check;
+                    self.params.check = 1;
+                    self.send_packet(device, packet.seq, ACK, &init_ack)?;
+                    self.params.check = check;
+                } else if ahead < self.params.window as u8 {
+                    self.send_packet(device, packet.seq, ACK, &[])?;
+                    self.send_packet(device, expected, NAK, &[])?;
+                    pending.insert(packet.seq, packet);
+                } else {
+                    // A packet we already have, the ACK for it was lost
+                    self.send_packet(device, packet.seq, ACK, &[])?;
+                }
+                continue;
+            }
+
+            errors = 0;
+            let mut packet = packet;
+            // Packets taken from the pending buffer were ACKed when they arrived
+            let mut acked = false;
+            loop {
+                let seq = packet.seq;
+                match packet.kind {
+                    FILE_HEADER => {
+                        let name = decode(&packet.data, self.params.qctl, self.params.qbin);
+                        let name = String::from_utf8_lossy(&name);
+                        // Only keep the last path component, the sender's directories are ignored
+                        let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+                        println!("Kermit Receive: {name}");
+                        let path = directory.join(name);
+                        let file = File::create(&path).map_err(TransferError::StreamWrite)?;
+                        current = Some((path, file));
+                        self.reporter.set_state(TransferState::Transferring);
+                    }
+                    DATA => {
+                        let data = decode(&packet.data, self.params.qctl, self.params.qbin);
+                        if let Some((_, file)) = current.as_mut() {
+                            file.write_all(&data).map_err(TransferError::StreamWrite)?;
+                        }
+                        self.reporter.count_packet(data.len());
+                    }
+                    EOF => {
+                        if let Some((path, _)) = current.take() {
+                            // A 'D' in the EOF packet means the sender discarded the file
+                            if packet.data.first() == Some(&b'D') {
+                                let _ = std::fs::remove_file(&path);
+                            } else {
+                                received.push(path);
+                            }
+                        }
+                    }
+                    BREAK => {
+                        if !acked {
+                            self.send_packet(device, seq, ACK, &[])?;
+                        }
+                        println!("Kermit batch complete");
+                        return Ok(received);
+                    }
+                    ERROR => return Err(self.peer_error(&packet)),
+                    _ => (),
+                }
+                if !acked {
+                    self.send_packet(device, seq, ACK, &[])?;
+                }
+                expected = next_seq(seq);
+                match pending.remove(&expected) {
+                    Some(next) => {
+                        packet = next;
+                        acked = true;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.params = Params::default();
+        self.seq = 0;
+        self.rx.clear();
+    }
+
+    /// Data field of our Send-Init packet, or of the ACK to the peer's
+    fn init_data(&self) -> Vec<u8> {
+        vec![
+            tochar(94),
+            tochar(self.timeout.as_secs().min(94) as u8),
+            tochar(0),
+            ctl(0),
+            tochar(CR),
+            QCTL,
+            b'Y',
+            b'3',
+            b' ',
+            tochar(CAPAS_LONG | CAPAS_WINDOWS),
+            tochar(self.window_size as u8),
+            tochar((self.max_length / 95) as u8),
+            tochar((self.max_length % 95) as u8),
+        ]
+    }
+
+    /// Settles the parameters from the peer's Send-Init data,
+    /// fields the peer left out keep their defaults.
+    fn negotiate(&mut self, peer: &[u8]) {
+        let field = |index: usize| peer.get(index).copied();
+        let mut params = Params::default();
+        if let Some(max_length) = field(0).map(unchar) {
+            params.max_length = (max_length as usize).clamp(10, 94);
+        }
+        if let Some(eol) = field(4).map(unchar) {
+            params.eol = eol;
+        }
+        if let Some(qctl) = field(5).filter(|&c| is_prefix(c)) {
+            params.qctl = qctl;
+        }
+        // We answer 'Y', so 8th bit quoting is on only if the peer asks for it
+        params.qbin = field(6).filter(|&c| is_prefix(c));
+        if field(7) == Some(b'3') {
+            params.check = 3;
+        }
+        let mut index = 9;
+        let capas = field(index).map(unchar).unwrap_or(0);
+        while field(index).map(unchar).unwrap_or(0) & 0x01 != 0 {
+            index += 1;
+        }
+        if capas & CAPAS_WINDOWS != 0 {
+            let window = field(index + 1).map(unchar).unwrap_or(1) as usize;
+            params.window = window.clamp(1, 31).min(self.window_size);
+        }
+        if capas & CAPAS_LONG != 0 {
+            let max_length = match (field(index + 2), field(index + 3)) {
+                (Some(hi), Some(lo)) => unchar(hi) as usize * 95 + unchar(lo) as usize,
+                _ => 500,
+            };
+            params.max_length = max_length.clamp(10, MAX_LONG_LENGTH);
+        }
+        self.params = params;
+    }
+
+    /// Sends the file as data packets, keeping up to a window of them
+    /// waiting for their ACKs.
+    fn send_data(
+        &mut self,
+        device: &mut dyn Transport,
+        file: &mut File,
+    ) -> Result<(), TransferError> {
+        let mut window: VecDeque<Slot> = VecDeque::new();
+        let mut buffer: VecDeque<u8> = VecDeque::new();
+        let mut eof = false;
+        loop {
+            self.check_cancel(device)?;
+            while !eof && window.len() < self.params.window {
+                let (data, len) = self.next_data(file, &mut buffer)?;
+                if data.is_empty() {
+                    eof = true;
+                    break;
+                }
+                let frame = self.make_packet(self.seq, DATA, &data);
+                self.write(device, &frame)?;
+                window.push_back(Slot {
+                    seq: self.seq,
+                    frame,
+                    len,
+                    acked: false,
+                    retries: 0,
+                });
+                self.seq = next_seq(self.seq);
+            }
+            if window.is_empty() {
+                return Ok(());
+            }
+
+            let resend = match self.read_packet(device) {
+                Ok(packet) => match packet.kind {
+                    ACK => {
+                        if let Some(slot) = window.iter_mut().find(|s| s.seq == packet.seq) {
+                            slot.acked = true;
+                        }
+                        while window.front().is_some_and(|s| s.acked) {
+                            if let Some(slot) = window.pop_front() {
+                                self.reporter.count_packet(slot.len);
+                            }
+                        }
+                        None
+                    }
+                    // A NAK for the packet after the window stands for an ACK of the window
+                    NAK if packet.seq == self.seq && window.iter().all(|s| s.seq != packet.seq) => {
+                        for slot in window.drain(..) {
+                            self.reporter.count_packet(slot.len);
+                        }
+                        None
+                    }
+                    NAK => window.iter().position(|s| s.seq == packet.seq && !s.acked),
+                    ERROR => return Err(self.peer_error(&packet)),
+                    _ => None,
+                },
+                Err(err) if err.is_recoverable() => {
+                    println!("Error: {err}");
+                    window.iter().position(|s| !s.acked)
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(index) = resend {
+                self.reporter.count_retry();
+                let slot = &mut window[index];
+                slot.retries += 1;
+                if slot.retries > self.retries {
+                    self.send_error(device, "Too many retries");
+                    return Err(TransferError::TooManyRetries);
+                }
+                println!("Resending packet {}", slot.seq);
+                let frame = slot.frame.clone();
+                self.write(device, &frame)?;
+            }
+        }
+    }
+
+    /// Encodes as much of the file as fits in one data packet,
+    /// returns the encoded data and the number of file bytes in it.
+    fn next_data(
+        &mut self,
+        file: &mut File,
+        buffer: &mut VecDeque<u8>,
+    ) -> Result<(Vec<u8>, usize), TransferError> {
+        let capacity = self.data_capacity();
+        let mut data = vec![];
+        let mut len = 0;
+        let mut encoded = vec![];
+        loop {
+            if buffer.is_empty() {
+                let mut chunk = [0; 4096];
+                let read = file.read(&mut chunk).map_err(TransferError::StreamRead)?;
+                if read == 0 {
+                    return Ok((data, len));
+                }
+                buffer.extend(&chunk[..read]);
+            }
+            encoded.clear();
+            encode_byte(buffer[0], QCTL, self.params.qbin, &mut encoded);
+            if data.len() + encoded.len() > capacity {
+                return Ok((data, len));
+            }
+            data.extend_from_slice(&encoded);
+            buffer.pop_front();
+            len += 1;
+        }
+    }
+
+    /// Room for encoded data in a packet of the peer's maximum length
+    fn data_capacity(&self) -> usize {
+        let check_length = self.params.check as usize;
+        if self.params.max_length > 94 {
+            self.params.max_length - 5 - check_length
+        } else {
+            self.params.max_length - 2 - check_length
+        }
+    }
+
+    /// Sends a packet and waits for its ACK, resending it on NAKs and timeouts.
+    /// Returns the ACK, the reply to a Send-Init carries the peer's parameters.
+    fn send_and_wait(
+        &mut self,
+        device: &mut dyn Transport,
+        kind: u8,
+        data: &[u8],
+    ) -> Result<Packet, TransferError> {
+        let seq = self.seq;
+        let frame = self.make_packet(seq, kind, data);
+        let mut errors = 0;
+        loop {
+            self.check_cancel(device)?;
+            self.write(device, &frame)?;
+            match self.read_packet(device) {
+                Ok(packet) if packet.kind == ACK && packet.seq == seq => {
+                    self.seq = next_seq(seq);
+                    return Ok(packet);
+                }
+                // A NAK for the next packet means this one arrived
+                Ok(packet) if packet.kind == NAK && packet.seq == next_seq(seq) => {
+                    self.seq = next_seq(seq);
+                    return Ok(packet);
+                }
+                Ok(packet) if packet.kind == ERROR => return Err(self.peer_error(&packet)),
+                Ok(_) => errors += 1,
+                Err(err) if err.is_recoverable() => {
+                    errors += 1;
+                    println!("Error Count: {errors}, Error: {err}");
+                }
+                Err(err) => return Err(err),
+            }
+            if errors > self.retries {
+                self.send_error(device, "Too many retries");
+                return Err(TransferError::TooManyRetries);
+            }
+        }
+    }
+
+    /// The message of an Error packet from the peer
+    fn peer_error(&self, packet: &Packet) -> TransferError {
+        let message = decode(&packet.data, self.params.qctl, self.params.qbin);
+        TransferError::Remote(String::from_utf8_lossy(&message).to_string())
+    }
+
+    /// Ends the transfer with an Error packet if it was cancelled on this end
+    fn check_cancel(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        if !self.reporter.is_cancelled() {
+            return Ok(());
+        }
+        println!("Transfer cancelled, sending error packet");
+        self.send_error(device, "Transfer cancelled");
+        Err(TransferError::Aborted)
+    }
+
+    /// Tells the peer the transfer is over with an Error packet
+    fn send_error(&mut self, device: &mut dyn Transport, message: &str) {
+        let data = encode(message.as_bytes(), QCTL, self.params.qbin);
+        let seq = self.seq;
+        let _ = self.send_packet(device, seq, ERROR, &data);
+    }
+
+    fn send_packet(
+        &mut self,
+        device: &mut dyn Transport,
+        seq: u8,
+        kind: u8,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let frame = self.make_packet(seq, kind, data);
+        self.write(device, &frame)
+    }
+
+    /// Frames a packet, switching to the long packet format when the
+    /// data doesn't fit the LEN field.
+    fn make_packet(&self, seq: u8, kind: u8, data: &[u8]) -> Vec<u8> {
+        let check = if kind == SEND_INIT {
+            1
+        } else {
+            self.params.check
+        };
+        let check_length = check as usize;
+        let mut covered = if data.len() + 2 + check_length <= 94 {
+            vec![
+                tochar((data.len() + 2 + check_length) as u8),
+                tochar(seq),
+                kind,
+            ]
+        } else {
+            let length = data.len() + check_length;
+            let mut header = vec![
+                tochar(0),
+                tochar(seq),
+                kind,
+                tochar((length / 95) as u8),
+                tochar((length % 95) as u8),
+            ];
+            header.push(check_1(&header));
+            header
+        };
+        covered.extend_from_slice(data);
+
+        let mut frame = vec![MARK];
+        frame.extend_from_slice(&covered);
+        frame.extend(block_check(&covered, check));
+        frame.push(self.params.eol);
+        frame
+    }
+
+    /// Reads the next packet, skipping anything before its MARK
+    fn read_packet(&mut self, device: &mut dyn Transport) -> Result<Packet, TransferError> {
+        let mut garbage = 0;
+        while self.read_raw(device)? != MARK {
+            garbage += 1;
+            if garbage > MAX_GARBAGE {
+                return Err(TransferError::Protocol("No packet found"));
+            }
+        }
+        let mut covered = vec![];
+        for _ in 0..3 {
+            covered.push(self.read_raw(device)?);
+        }
+        let kind = covered[2];
+        let length = match unchar(covered[0]) as usize {
+            0 => {
+                for _ in 0..2 {
+                    covered.push(self.read_raw(device)?);
+                }
+                let header_check = self.read_raw(device)?;
+                if header_check != check_1(&covered) {
+                    return Err(TransferError::Protocol("Header checksum error"));
+                }
+                covered.push(header_check);
+                unchar(covered[3]) as usize * 95 + unchar(covered[4]) as usize
+            }
+            length if length >= 2 => length - 2,
+            _ => return Err(TransferError::Protocol("Bad packet length")),
+        };
+        if length > MAX_LONG_LENGTH {
+            return Err(TransferError::Protocol("Bad packet length"));
+        }
+        // The Send-Init always uses a type 1 block check
+        let check = if kind == SEND_INIT {
+            1
+        } else {
+            self.params.check
+        };
+        let check_length = check as usize;
+        if length < check_length {
+            return Err(TransferError::Protocol("Bad packet length"));
+        }
+        let start = covered.len();
+        for _ in 0..length - check_length {
+            covered.push(self.read_raw(device)?);
+        }
+        let mut received_check = vec![];
+        for _ in 0..check_length {
+            received_check.push(self.read_raw(device)?);
+        }
+        if block_check(&covered, check) != received_check {
+            return Err(TransferError::Protocol("Block check error"));
+        }
+        Ok(Packet {
+            seq: unchar(covered[1]) & 0x3f,
+            kind,
+            data: covered[start..].to_vec(),
+        })
+    }
+
+    /// Reads a byte from the device, waiting up to the timeout for it
+    fn read_raw(&mut self, device: &mut dyn Transport) -> Result<u8, TransferError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(byte) = self.rx.pop_front() {
+                return Ok(byte);
+            }
+            let mut buf = [0; 1024];
+            match device.read(&mut buf) {
+                // A closed stream, wait out the timeout like a silent line
+                Ok(0) => std::thread::sleep(Duration::from_millis(1)),
+                Ok(len) => self.rx.extend(&buf[..len]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if self.rx.is_empty() && Instant::now() > deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+    }
+
+    fn write(&mut self, device: &mut dyn Transport, bytes: &[u8]) -> Result<(), TransferError> {
+        device.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+fn tochar(value: u8) -> u8 {
+    value + 32
+}
+
+fn unchar(value: u8) -> u8 {
+    value.wrapping_sub(32)
+}
+
+fn ctl(value: u8) -> u8 {
+    value ^ 64
+}
+
+fn next_seq(seq: u8) -> u8 {
+    (seq + 1) % 64
+}
+
+/// Printable characters that may be used as a prefix
+fn is_prefix(c: u8) -> bool {
+    (33..=62).contains(&c) || (96..=126).contains(&c)
+}
+
+/// Quotes a byte into the data field of a packet
+fn encode_byte(byte: u8, qctl: u8, qbin: Option<u8>, data: &mut Vec<u8>) {
+    let mut byte = byte;
+    if let Some(qbin) = qbin {
+        if byte & 0x80 != 0 {
+            data.push(qbin);
+            byte &= 0x7f;
+        }
+    }
+    let low = byte & 0x7f;
+    if low < 32 || low == 127 {
+        data.push(qctl);
+        data.push(ctl(byte));
+    } else if low == qctl || Some(low) == qbin {
+        data.push(qctl);
+        data.push(byte);
+    } else {
+        data.push(byte);
+    }
+}
+
+fn encode(bytes: &[u8], qctl: u8, qbin: Option<u8>) -> Vec<u8> {
+    let mut data = vec![];
+    for &byte in bytes {
+        encode_byte(byte, qctl, qbin, &mut data);
+    }
+    data
+}
+
+/// Undoes the control and 8th bit quoting of a data field
+fn decode(data: &[u8], qctl: u8, qbin: Option<u8>) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut iter = data.iter().copied();
+    while let Some(mut c) = iter.next() {
+        let mut high = 0;
+        if Some(c) == qbin {
+            high = 0x80;
+            c = match iter.next() {
+                Some(c) => c,
+                None => break,
+            };
+        }
+        if c == qctl {
+            c = match iter.next() {
+                Some(c) => c,
+                None => break,
+            };
+            // Quoted prefix characters are sent as they are
+            if (63..=95).contains(&(c & 0x7f)) {
+                c = ctl(c);
+            }
+        }
+        bytes.push(c | high);
+    }
+    bytes
+}
+
+/// Block check of the given type over the packet from LEN to the end of DATA
+fn block_check(covered: &[u8], check: u8) -> Vec<u8> {
+    if check == 3 {
+        let crc = crc_kermit(covered);
+        vec![
+            tochar(((crc >> 12) & 0x0f) as u8),
+            tochar(((crc >> 6) & 0x3f) as u8),
+            tochar((crc & 0x3f) as u8),
+        ]
+    } else {
+        vec![check_1(covered)]
+    }
+}
+
+/// Single character 6bit checksum
+fn check_1(covered: &[u8]) -> u8 {
+    let sum: u32 = covered.iter().map(|&val| val as u32).sum();
+    tochar(((sum + ((sum & 0xc0) >> 6)) & 0x3f) as u8)
+}
+
+/// Calculate 16bit Kermit CRC (CCITT, reflected)
+fn crc_kermit(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &val in data {
+        crc ^= val as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0x8408;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+#[test]
+fn test_crc_kermit() {
+    assert_eq!(crc_kermit(b"123456789"), 0x2189);
+}
+
+#[cfg(test)]
+#[test]
+fn test_quoting() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    let data = encode(&bytes, QCTL, Some(b'&'));
+    assert!(data.iter().all(|&c| (32..127).contains(&c)));
+    assert_eq!(decode(&data, QCTL, Some(b'&')), bytes);
+    let data = encode(&bytes, QCTL, None);
+    assert_eq!(decode(&data, QCTL, None), bytes);
+}
+
+#[cfg(test)]
+#[test]
+fn test_transfer_over_tcp() {
+    let (mut sender, mut receiver) = crate::transport::tcp_pair();
+    sender
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("terminalrs_kermit_{}", std::process::id()));
+    let source = root.join("source");
+    let target = root.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let data: Vec<u8> = (0..30000).map(|i| (i * 17 % 256) as u8).collect();
+    std::fs::write(source.join("image.bin"), &data).unwrap();
+    let files = vec![source.join("image.bin")];
+    let handle = std::thread::spawn(move || Kermit::new().send(&mut sender, &files));
+
+    let received = Kermit::new().receive(&mut receiver, &target).unwrap();
+    handle.join().unwrap().unwrap();
+
+    assert_eq!(received, vec![target.join("image.bin")]);
+    assert_eq!(std::fs::read(target.join("image.bin")).unwrap(), data);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_window_recovers_from_lost_and_corrupt_packets() {
+    use crate::fault::{Fault, Faulty, Policy};
+    use crate::transport::loopback_pair;
+    let (a, mut receiver) = loopback_pair();
+    // Write 0 is the Send-Init and write 1 the file header, the six data
+    // packets follow in one window. Losing the second fills the pending
+    // buffer and draws NAKs, the corrupt fourth and lost last one are only
+    // resent once the sender times out.
+    let script = vec![
+        (3, Fault::Lose),
+        (
+            5,
+            Fault::Corrupt {
+                offset: 200,
+                mask: 0x04,
+            },
+        ),
+        (7, Fault::Lose),
+    ];
+    let mut sender = Faulty::new(a, Policy::Script(script));
+    let root =
+        std::env::temp_dir().join(format!("terminalrs_kermit_faults_{}", std::process::id()));
+    let source = root.join("source");
+    let target = root.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    // Printable bytes need no quoting, so 5500 of them make six packets
+    let data: Vec<u8> = (0..5500).map(|i| b'a' + (i % 26) as u8).collect();
+    std::fs::write(source.join("window.txt"), &data).unwrap();
+    let files = vec![source.join("window.txt")];
+    let handle = std::thread::spawn(move || {
+        let mut kermit = Kermit::new();
+        kermit.timeout = Duration::from_millis(300);
+        let result = kermit.send(&mut sender, &files);
+        (result, sender.injected)
+    });
+
+    let mut kermit = Kermit::new();
+    kermit.timeout = Duration::from_secs(3);
+    let received = kermit.receive(&mut receiver, &target).unwrap();
+    let (result, injected) = handle.join().unwrap();
+    result.unwrap();
+
+    assert_eq!(received, vec![target.join("window.txt")]);
+    assert_eq!(std::fs::read(target.join("window.txt")).unwrap(), data);
+    assert_eq!(injected.len(), 3);
+    std::fs::remove_dir_all(&root).unwrap();
+}
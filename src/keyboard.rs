@@ -0,0 +1,217 @@
+use eframe::egui::{Key, Modifiers};
+use std::fmt;
+
+/// What Enter sends
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Newline {
+    Cr,
+    Lf,
+    #[default]
+    CrLf,
+}
+
+impl Newline {
+    pub const ALL: [Newline; 3] = [Newline::Cr, Newline::Lf, Newline::CrLf];
+
+    pub fn bytes(&self) -> &'static [u8] {
+        match self {
+            Newline::Cr => b"\r",
+            Newline::Lf => b"\n",
+            Newline::CrLf => b"\r\n",
+        }
+    }
+}
+
+impl fmt::Display for Newline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Newline::Cr => write!(f, "CR"),
+            Newline::Lf => write!(f, "LF"),
+            Newline::CrLf => write!(f, "CR LF"),
+        }
+    }
+}
+
+/// What xterm sends for F1 to F12
+pub const FUNCTION_KEYS: [&[u8]; 12] = [
+    b"\x1BOP",
+    b"\x1BOQ",
+    b"\x1BOR",
+    b"\x1BOS",
+    b"\x1B[15~",
+    b"\x1B[17~",
+    b"\x1B[18~",
+    b"\x1B[19~",
+    b"\x1B[20~",
+    b"\x1B[21~",
+    b"\x1B[23~",
+    b"\x1B[24~",
+];
+
+/// The bytes a VT100/xterm sends for a key that doesn't type text, text
+/// arrives on its own. In application mode the cursor keys send SS3
+/// sequences, which full screen programs ask for. Enter sends the newline.
+pub fn key_sequence(
+    key: Key,
+    modifiers: Modifiers,
+    application_cursor: bool,
+    newline: Newline,
+) -> Option<Vec<u8>> {
+    // xterm's modifier parameter, 1 is none
+    let modifier = 1 + modifiers.shift as u8 + 2 * modifiers.alt as u8 + 4 * modifiers.ctrl as u8;
+    let cursor = |code: char| {
+        if modifier > 1 {
+            format!("\x1B[1;{modifier}{code}").into_bytes()
+        } else if application_cursor {
+            format!("\x1BO{code}").into_bytes()
+        } else {
+            format!("\x1B[{code}").into_bytes()
+        }
+    };
+    let tilde = |code: u8| {
+        if modifier > 1 {
+            format!("\x1B[{code};{modifier}~").into_bytes()
+        } else {
+            format!("\x1B[{code}~").into_bytes()
+        }
+    };
+    let bytes = match key {
+        Key::ArrowUp => cursor('A'),
+        Key::ArrowDown => cursor('B'),
+        Key::ArrowRight => cursor('C'),
+        Key::ArrowLeft => cursor('D'),
+        Key::Home => cursor('H'),
+        Key::End => cursor('F'),
+        Key::Insert => tilde(2),
+        Key::Delete => tilde(3),
+        Key::PageUp => tilde(5),
+        Key::PageDown => tilde(6),
+        Key::Enter => newline.bytes().to_vec(),
+        Key::Escape => vec![0x1B],
+        Key::Tab if modifiers.shift => b"\x1B[Z".to_vec(),
+        Key::Tab => vec![b'\t'],
+        Key::Backspace if modifiers.ctrl => vec![0x08],
+        Key::Backspace => vec![0x7F],
+        // Ctrl and Alt together is AltGr, which types text
+        _ if modifiers.ctrl && !modifiers.alt => vec![control(key)?],
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// The control character of Ctrl and a key, the digits as xterm has them
+fn control(key: Key) -> Option<u8> {
+    const LETTERS: [Key; 26] = [
+        Key::A,
+        Key::B,
+        Key::C,
+        Key::D,
+        Key::E,
+        Key::F,
+        Key::G,
+        Key::H,
+        Key::I,
+        Key::J,
+        Key::K,
+        Key::L,
+        Key::M,
+        Key::N,
+        Key::O,
+        Key::P,
+        Key::Q,
+        Key::R,
+        Key::S,
+        Key::T,
+        Key::U,
+        Key::V,
+        Key::W,
+        Key::X,
+        Key::Y,
+        Key::Z,
+    ];
+    if let Some(index) = LETTERS.iter().position(|&letter| letter == key) {
+        return Some(index as u8 + 1);
+    }
+    match key {
+        Key::Space | Key::Num2 => Some(0x00),
+        Key::Num3 => Some(0x1B),
+        Key::Num4 => Some(0x1C),
+        // Ctrl+] on keyboards that have no ] key of their own
+        Key::Num5 => Some(0x1D),
+        Key::Num6 => Some(0x1E),
+        Key::Num7 => Some(0x1F),
+        Key::Num8 => Some(0x7F),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_key_sequences() {
+    let none = Modifiers::NONE;
+    let ctrl = Modifiers {
+        ctrl: true,
+        command: true,
+        ..Modifiers::NONE
+    };
+    let shift = Modifiers {
+        shift: true,
+        ..Modifiers::NONE
+    };
+    assert_eq!(
+        key_sequence(Key::ArrowUp, none, false, Newline::CrLf).unwrap(),
+        b"\x1B[A"
+    );
+    assert_eq!(
+        key_sequence(Key::ArrowUp, none, true, Newline::CrLf).unwrap(),
+        b"\x1BOA"
+    );
+    assert_eq!(
+        key_sequence(Key::Home, none, true, Newline::CrLf).unwrap(),
+        b"\x1BOH"
+    );
+    assert_eq!(
+        key_sequence(Key::ArrowLeft, ctrl, true, Newline::CrLf).unwrap(),
+        b"\x1B[1;5D"
+    );
+    assert_eq!(
+        key_sequence(Key::Delete, none, false, Newline::CrLf).unwrap(),
+        b"\x1B[3~"
+    );
+    assert_eq!(
+        key_sequence(Key::PageDown, shift, false, Newline::CrLf).unwrap(),
+        b"\x1B[6;2~"
+    );
+    assert_eq!(
+        key_sequence(Key::Tab, shift, false, Newline::CrLf).unwrap(),
+        b"\x1B[Z"
+    );
+    assert_eq!(
+        key_sequence(Key::Backspace, none, false, Newline::CrLf).unwrap(),
+        [0x7F]
+    );
+    assert_eq!(
+        key_sequence(Key::Enter, none, false, Newline::CrLf).unwrap(),
+        b"\r\n"
+    );
+    assert_eq!(
+        key_sequence(Key::Enter, none, false, Newline::Cr).unwrap(),
+        b"\r"
+    );
+    assert_eq!(
+        key_sequence(Key::C, ctrl, false, Newline::CrLf).unwrap(),
+        [0x03]
+    );
+    assert_eq!(
+        key_sequence(Key::Z, ctrl, false, Newline::CrLf).unwrap(),
+        [0x1A]
+    );
+    assert_eq!(
+        key_sequence(Key::Num5, ctrl, false, Newline::CrLf).unwrap(),
+        [0x1D]
+    );
+    // Letters without Ctrl, and AltGr, type text
+    assert_eq!(key_sequence(Key::C, none, false, Newline::CrLf), None);
+    let alt_gr = Modifiers { alt: true, ..ctrl };
+    assert_eq!(key_sequence(Key::Q, alt_gr, false, Newline::CrLf), None);
+}
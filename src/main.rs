@@ -1,15 +1,45 @@
+mod autostart;
+mod encoding;
+mod error;
+mod esp;
+#[cfg(test)]
+mod fault;
 mod gui;
+mod image;
+mod kermit;
+mod keyboard;
+mod progress;
+mod reader;
+mod smp;
+mod stk500;
+mod stm32;
+mod transfer;
+mod transport;
+mod upgrade;
+mod verify;
+mod vt100;
 mod xmodem;
+mod ymodem;
+mod zmodem;
 
+use autostart::{AutoStart, Detector};
 use eframe::{
     egui::{self, Event, Key},
     emath::Align,
 };
+use encoding::Console;
 use gui::*;
+use image::Image;
+use keyboard::Newline;
+use reader::SerialReader;
 use serialport::SerialPort;
-use std::fs::File;
+use smp::SmpCommand;
+use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
-use xmodem::XModem;
+use transfer::{TransferJob, TransferRequest};
+use upgrade::Manifest;
+use xmodem::BlockLength;
 
 fn main() {
     let options = eframe::NativeOptions::default();
@@ -24,11 +54,21 @@ struct Terminal {
     selected_comport: String,
     comports: Vec<String>,
     buadrates: Vec<u32>,
-    console_text: String,
+    console: Console,
+    newline: Newline,
     serial_settings_flag: bool,
     serial_port: Option<Box<dyn SerialPort>>,
+    reader: Option<SerialReader>,
     port_connected: bool,
     port_settings: SerialPortSettings,
+    transfer: Option<TransferJob>,
+    transfer_options: TransferOptions,
+    xmodem_options_flag: bool,
+    bootloader_options: BootloaderOptions,
+    image_offer: Option<ImageOffer>,
+    detector: Detector,
+    autostart: bool,
+    autostart_offer: Option<AutoStart>,
 }
 
 impl Terminal {
@@ -47,43 +87,261 @@ impl Terminal {
                 110, 300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 38400, 57600, 115200, 230400,
                 460800, 921600,
             ],
-            console_text: "".to_owned(),
+            console: Console::default(),
+            newline: Newline::default(),
             serial_settings_flag: false,
             serial_port: None,
+            reader: None,
             port_connected: false,
             port_settings: SerialPortSettings::default(),
+            transfer: None,
+            transfer_options: TransferOptions::default(),
+            xmodem_options_flag: false,
+            bootloader_options: BootloaderOptions::default(),
+            image_offer: None,
+            detector: Detector::default(),
+            autostart: true,
+            autostart_offer: None,
         }
     }
 }
 
 impl eframe::App for Terminal {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if let Some(job) = self.transfer.as_mut() {
+            if let Some(port) = job.poll() {
+                // The worker hands the port back when the transfer ends
+                self.port_connected = true;
+                self.serial_port = Some(port);
+            }
+        }
+
+        let mut request = None;
         egui::TopBottomPanel::top("Menu").show(ctx, |ui| {
             ui.menu_button("Transfer", |ui| {
+                if ui.button("Transfer Options").clicked() {
+                    self.xmodem_options_flag = !self.xmodem_options_flag;
+                }
+                ui.checkbox(&mut self.autostart, "Auto-start transfers");
+                ui.separator();
+                if self.transfer.is_some() {
+                    ui.label("A transfer is running");
+                    return;
+                }
+                if self.serial_port.is_none() {
+                    ui.label("Connect a port first");
+                    return;
+                }
+                if ui.button("Run Upgrade Manifest").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Manifest", &["toml", "yaml", "yml"])
+                        .pick_file()
+                    {
+                        match Manifest::load(&path) {
+                            Ok(manifest) => {
+                                let options = self.transfer_options.xmodem.builder();
+                                request = Some(TransferRequest::Upgrade { manifest, options });
+                            }
+                            Err(err) => println!("Can't load {}: {err}", path.display()),
+                        }
+                    }
+                }
+                ui.separator();
                 if ui.button("xModem Send").clicked() {
                     if let Some(path) = rfd::FileDialog::new().pick_file() {
-                        let picked_path = path.display().to_string();
-                        let port = self.serial_port.as_mut().unwrap();
-                        let stream = File::open(picked_path).unwrap();
-                        match XModem::new().send(port, Box::new(stream)) {
-                            Ok(()) => println!("File Send success"),
-                            Err(err) => println!("Error: {err}"),
+                        let options = self.transfer_options.xmodem.builder();
+                        if Image::is_image(&path) {
+                            self.image_offer = Some(ImageOffer::new(path, options));
+                        } else {
+                            request = Some(TransferRequest::XModemSend { path, options });
+                        }
+                    }
+                }
+                if ui.button("xModem-1K Send").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        let options = self
+                            .transfer_options
+                            .xmodem
+                            .builder()
+                            .block_length(BlockLength::OneK);
+                        if Image::is_image(&path) {
+                            self.image_offer = Some(ImageOffer::new(path, options));
+                        } else {
+                            request = Some(TransferRequest::XModemSend { path, options });
                         }
                     }
                 }
                 if ui.button("xModem Receive").clicked() {
                     if let Some(path) = rfd::FileDialog::new().save_file() {
-                        let picked_path = path.display().to_string();
-                        let port = self.serial_port.as_mut().unwrap();
-                        let stream = File::create(picked_path).unwrap();
-                        match XModem::new().receive(port, Box::new(stream), false) {
-                            Ok(bytes) => println!("File Receive success, Bytes: {bytes} read."),
-                            Err(err) => println!("Error: {err}"),
+                        let options = self.transfer_options.xmodem.builder();
+                        request = Some(TransferRequest::XModemReceive { path, options });
+                    }
+                }
+                if ui.button("yModem Send").clicked() {
+                    if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                        let options = self.transfer_options.xmodem.builder();
+                        request = Some(TransferRequest::YModemSend { paths, options });
+                    }
+                }
+                if ui.button("yModem Receive").clicked() {
+                    if let Some(directory) = rfd::FileDialog::new().pick_folder() {
+                        let options = self.transfer_options.xmodem.builder();
+                        request = Some(TransferRequest::YModemReceive { directory, options });
+                    }
+                }
+                if ui.button("zModem Send").clicked() {
+                    if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                        request = Some(TransferRequest::ZModemSend {
+                            paths,
+                            resume: self.transfer_options.zmodem.resume,
+                        });
+                    }
+                }
+                if ui.button("zModem Receive").clicked() {
+                    if let Some(directory) = rfd::FileDialog::new().pick_folder() {
+                        request = Some(TransferRequest::ZModemReceive(directory));
+                    }
+                }
+                if ui.button("Kermit Send").clicked() {
+                    if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                        request = Some(TransferRequest::KermitSend(paths));
+                    }
+                }
+                if ui.button("Kermit Receive").clicked() {
+                    if let Some(directory) = rfd::FileDialog::new().pick_folder() {
+                        request = Some(TransferRequest::KermitReceive(directory));
+                    }
+                }
+                ui.separator();
+                if ui.button("STM32 Flash").clicked() {
+                    match self.bootloader_options.stm32.address() {
+                        Some(address) => {
+                            if let Some(path) = rfd::FileDialog::new().pick_file() {
+                                request = Some(TransferRequest::Stm32Flash {
+                                    path,
+                                    address,
+                                    mass_erase: self.bootloader_options.stm32.mass_erase,
+                                    start: self.bootloader_options.stm32.start,
+                                });
+                            }
+                        }
+                        None => println!("Invalid STM32 address in Transfer Options"),
+                    }
+                }
+                if ui.button("ESP Flash").clicked() {
+                    match self.bootloader_options.esp.offset() {
+                        Some(offset) => {
+                            if let Some(path) = rfd::FileDialog::new().pick_file() {
+                                request = Some(TransferRequest::EspFlash {
+                                    path,
+                                    offset,
+                                    baud: self.bootloader_options.esp.baud(),
+                                    run: self.bootloader_options.esp.run,
+                                });
+                            }
                         }
+                        None => println!("Invalid ESP offset in Transfer Options"),
+                    }
+                }
+                if ui.button("AVR Upload").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        request = Some(TransferRequest::AvrUpload {
+                            path,
+                            version: self.bootloader_options.avr.version,
+                            baud: self.bootloader_options.avr.baud(),
+                            reset: self.bootloader_options.avr.reset,
+                        });
+                    }
+                }
+                ui.separator();
+                if ui.button("MCUboot Upload").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        request = Some(TransferRequest::Smp(SmpCommand::Upload {
+                            path,
+                            chunk: self.bootloader_options.smp.chunk,
+                            test: self.bootloader_options.smp.test,
+                            reset: self.bootloader_options.smp.reset,
+                        }));
                     }
                 }
+                if ui.button("MCUboot Image List").clicked() {
+                    request = Some(TransferRequest::Smp(SmpCommand::List));
+                }
+                if ui.button("MCUboot Confirm").clicked() {
+                    request = Some(TransferRequest::Smp(SmpCommand::Confirm));
+                }
+                if ui.button("MCUboot Reset").clicked() {
+                    request = Some(TransferRequest::Smp(SmpCommand::Reset));
+                }
             });
         });
+        if let Some(transfer) = self.autostart_offer {
+            if let Some(accepted) = autostart_window(ctx, transfer) {
+                self.autostart_offer = None;
+                request = match (accepted, transfer) {
+                    (false, _) => None,
+                    (true, AutoStart::ZModemReceive) => rfd::FileDialog::new()
+                        .pick_folder()
+                        .map(TransferRequest::ZModemReceive),
+                    (true, AutoStart::YModemSend) => {
+                        rfd::FileDialog::new().pick_files().map(|paths| {
+                            let options = self.transfer_options.xmodem.builder();
+                            TransferRequest::YModemSend { paths, options }
+                        })
+                    }
+                };
+                if request.is_none() {
+                    // Stop the device's side so it doesn't wait or keep polling
+                    if let Some(port) = self.serial_port.as_mut() {
+                        let _ = port.write_all(autostart::DECLINE);
+                    }
+                    println!("Declined {}", transfer.title());
+                }
+            }
+        }
+        if let Some(offer) = self.image_offer.as_mut() {
+            if let Some(choice) = image_window(ctx, offer) {
+                let offer = self.image_offer.take().unwrap();
+                request = match (choice, offer.image) {
+                    (ImageChoice::Text, _) => Some(TransferRequest::TextSend(offer.path)),
+                    (ImageChoice::Flattened, Ok(image)) => match image.flatten(offer.fill) {
+                        Ok((address, binary)) => {
+                            println!("Flattened image at {address:#010x}, {} bytes", binary.len());
+                            Some(TransferRequest::XModemSendImage {
+                                path: offer.path,
+                                binary,
+                                options: offer.options,
+                            })
+                        }
+                        Err(err) => {
+                            println!("Can't flatten image: {err}");
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+            }
+        }
+        if let Some(request) = request {
+            // The transfer has the port to itself
+            self.reader = None;
+            if let Some(port) = self.serial_port.take() {
+                let readback = self.transfer_options.readback.readback();
+                self.transfer = Some(TransferJob::start(request, port, readback));
+            }
+        }
+        // The reader runs while the port is ours and no handshake waits in it
+        match self.serial_port.as_deref() {
+            Some(port) if self.reader.is_none() && self.autostart_offer.is_none() => {
+                let ctx = ctx.clone();
+                match SerialReader::start(port, Arc::new(move || ctx.request_repaint())) {
+                    Ok(reader) => self.reader = Some(reader),
+                    Err(err) => println!("Can't read the port: {err}"),
+                }
+            }
+            Some(_) if self.autostart_offer.is_none() => (),
+            _ => self.reader = None,
+        }
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
@@ -98,9 +356,16 @@ impl eframe::App for Terminal {
                 }
                 buadrate_setting_combo_box(ui, &mut self.port_settings.baud_rate, &self.buadrates);
                 if self.port_connected {
-                    if ui.button("Disconnect").clicked() {
+                    // The port belongs to the transfer until it ends
+                    let disconnect = egui::Button::new("Disconnect");
+                    if ui
+                        .add_enabled(self.transfer.is_none(), disconnect)
+                        .clicked()
+                    {
+                        self.reader = None;
                         self.serial_port = None;
                         self.port_connected = false;
+                        self.autostart_offer = None;
                         println!("Disconnected Port");
                     }
                 } else {
@@ -129,11 +394,32 @@ impl eframe::App for Terminal {
                 if ui.button("Settings").clicked() {
                     self.serial_settings_flag = !self.serial_settings_flag;
                 }
+                encoding_combo_box(ui, &mut self.console);
+                newline_combo_box(ui, &mut self.newline);
             });
             ui.separator();
             match self.serial_port.as_mut() {
+                // The handshake waits in the port until the offer is answered
+                Some(_) if self.autostart_offer.is_some() => (),
                 Some(serial_port) => {
-                    terminal(ui, &mut self.console_text, serial_port);
+                    let received = self
+                        .reader
+                        .as_ref()
+                        .map(SerialReader::drain)
+                        .unwrap_or_default();
+                    let detected = terminal(
+                        ui,
+                        &mut self.console,
+                        serial_port,
+                        &received,
+                        &mut self.detector,
+                        self.newline,
+                    );
+                    if self.autostart && detected.is_some() {
+                        // Leave the rest of the handshake in the port
+                        self.reader = None;
+                        self.autostart_offer = detected;
+                    }
                 }
                 None => (),
             }
@@ -147,6 +433,20 @@ impl eframe::App for Terminal {
             &mut self.port_settings,
             &mut self.serial_settings_flag,
         );
-        ctx.request_repaint();
+        transfer_options_window(
+            ctx,
+            &mut self.transfer_options,
+            &mut self.bootloader_options,
+            &mut self.xmodem_options_flag,
+        );
+        if let Some(job) = self.transfer.as_ref() {
+            if transfer_window(ctx, job) {
+                self.transfer = None;
+            }
+        }
+        // The reader wakes the UI when data arrives, a transfer's progress doesn't
+        if matches!(self.transfer.as_ref().map(TransferJob::outcome), Some(None)) {
+            ctx.request_repaint();
+        }
     }
 }
@@ -0,0 +1,170 @@
+use crate::error::TransferError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Stage a transfer is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for the peer to start the transfer
+    Synchronizing,
+    /// Sending or receiving packets
+    Transferring,
+    /// Waiting for the peer to acknowledge the end of transmission
+    Finishing,
+    /// The transfer finished successfully
+    Complete,
+    /// The transfer was cancelled by either end
+    Cancelled,
+    /// The transfer stopped on an error
+    Failed,
+}
+
+/// Snapshot of a transfer's progress, passed to the progress observer
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// Packets acknowledged so far
+    pub packets: usize,
+    /// Data bytes acknowledged so far, not counting padding
+    pub bytes: u64,
+    /// Size of the transfer if known
+    pub total: Option<u64>,
+    /// Packets that were retried, on timeouts, NAKs or bad packets
+    pub retries: usize,
+    /// Current stage of the transfer
+    pub state: TransferState,
+}
+
+impl Progress {
+    pub fn new(total: Option<u64>) -> Self {
+        Self {
+            packets: 0,
+            bytes: 0,
+            total,
+            retries: 0,
+            state: TransferState::Synchronizing,
+        }
+    }
+
+    /// Fraction of the transfer done, from 0.0 to 1.0, if the size is known
+    pub fn fraction(&self) -> Option<f32> {
+        match self.total {
+            Some(0) => None,
+            Some(total) => Some((self.bytes as f64 / total as f64).min(1.0) as f32),
+            None => None,
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Called with the progress every time it changes
+pub type ProgressObserver = Box<dyn FnMut(&Progress) + Send>;
+
+/// Keeps the progress of a transfer, tells the observer about every change
+/// and holds the token that cancels it. Each protocol owns one.
+#[derive(Default)]
+pub struct Reporter {
+    progress: Progress,
+    observer: Option<ProgressObserver>,
+    cancel: CancelToken,
+}
+
+impl Reporter {
+    pub fn set_observer(&mut self, observer: ProgressObserver) {
+        self.observer = Some(observer);
+    }
+
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.progress.total = total;
+    }
+
+    /// Adds the size of another file of a batch to the total
+    pub fn add_total(&mut self, size: u64) {
+        self.progress.total = Some(self.progress.total.unwrap_or(0) + size);
+        self.report();
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Shares a token made elsewhere, so one cancel stops several transfers
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Starts the progress of a new transfer, keeping the total if one was set
+    pub fn start(&mut self) {
+        let total = self.progress.total;
+        self.progress = Progress::new(total);
+        self.report();
+    }
+
+    pub fn set_state(&mut self, state: TransferState) {
+        self.progress.state = state;
+        self.report();
+    }
+
+    /// Counts a packet acknowledged with `len` bytes of data
+    pub fn count_packet(&mut self, len: usize) {
+        self.progress.packets += 1;
+        self.progress.bytes += len as u64;
+        self.report();
+    }
+
+    /// Counts a packet for protocols that track the position in the data
+    /// rather than adding up packets, a resend can move it back.
+    pub fn count_position(&mut self, bytes: u64) {
+        self.progress.packets += 1;
+        self.progress.bytes = bytes;
+        self.report();
+    }
+
+    pub fn count_retry(&mut self) {
+        self.progress.retries += 1;
+        self.report();
+    }
+
+    /// Sets the final state from the result of a transfer
+    pub fn finish<T>(&mut self, result: Result<T, TransferError>) -> Result<T, TransferError> {
+        let state = match &result {
+            Ok(_) => TransferState::Complete,
+            Err(TransferError::Cancelled | TransferError::Aborted) => TransferState::Cancelled,
+            Err(_) => TransferState::Failed,
+        };
+        self.set_state(state);
+        result
+    }
+
+    fn report(&mut self) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(&self.progress);
+        }
+    }
+}
+
+/// Shared flag that cancels a running transfer from another thread.
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Asks the transfer to stop, it aborts at the next packet
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,107 @@
+use crate::transport::is_timeout;
+use serialport::SerialPort;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Wakes the UI when something arrived for it
+pub type Wake = Arc<dyn Fn() + Send + Sync>;
+
+/// Reads a port on its own thread so nothing the device sends waits on the
+/// UI. The thread reads a clone of the port, it stops when this is dropped
+/// so a transfer can have the port to itself.
+pub struct SerialReader {
+    received: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SerialReader {
+    /// Starts reading the port, waking the UI each time data arrives.
+    /// A read waits at most the port's timeout, which bounds how long
+    /// stopping takes.
+    pub fn start(port: &dyn SerialPort, wake: Wake) -> io::Result<Self> {
+        let mut port = port.try_clone().map_err(io::Error::from)?;
+        let (sender, received) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let worker = std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while !stopped.load(Ordering::Relaxed) {
+                match port.read(&mut buffer) {
+                    Ok(0) => (),
+                    Ok(len) => {
+                        if sender.send(buffer[..len].to_vec()).is_err() {
+                            break;
+                        }
+                        wake();
+                    }
+                    Err(err) if is_timeout(&err) => (),
+                    Err(err) => {
+                        println!("Serial read failed: {err}");
+                        wake();
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            received,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    /// Everything received since the last call
+    pub fn drain(&self) -> Vec<u8> {
+        self.received.try_iter().flatten().collect()
+    }
+}
+
+impl Drop for SerialReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_reader_over_pty() {
+    use serialport::TTYPort;
+    use std::io::Write;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+    let (mut device, port) = TTYPort::pair().unwrap();
+    let wakes = Arc::new(AtomicUsize::new(0));
+    let woken = wakes.clone();
+    let reader = SerialReader::start(
+        &port,
+        Arc::new(move || {
+            woken.fetch_add(1, Ordering::Relaxed);
+        }),
+    )
+    .unwrap();
+    assert!(reader.drain().is_empty());
+    device.write_all(b"hello ").unwrap();
+    device.write_all(b"world").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut received = vec![];
+    while received.len() < 11 && Instant::now() < deadline {
+        received.extend(reader.drain());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(received, b"hello world");
+    assert!(wakes.load(Ordering::Relaxed) > 0);
+    // Stops within a read timeout, after which the port is free again
+    drop(reader);
+    device.write_all(b"later").unwrap();
+    let mut port: Box<dyn SerialPort> = Box::new(port);
+    let mut buffer = [0; 5];
+    port.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"later");
+}
@@ -0,0 +1,550 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use crate::xmodem::crc;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ciborium::value::Value;
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// MCUmgr SMP over a serial console, as spoken by MCUboot serial recovery and
+// Zephyr applications. A packet is sent as base64 lines, the first starting
+// with FRAME_START and the rest with FRAME_CONTINUE. Decoded, it is a length,
+// an 8 byte header, a CBOR body and the CRC16 of header and body.
+const FRAME_START: [u8; 2] = [0x06, 0x09];
+const FRAME_CONTINUE: [u8; 2] = [0x04, 0x14];
+/// Longest line a frame takes up, its marker and newline included
+const MAX_FRAME: usize = 127;
+
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 2;
+
+const GROUP_OS: u16 = 0;
+const GROUP_IMAGE: u16 = 1;
+
+const OS_RESET: u8 = 5;
+const IMAGE_STATE: u8 = 0;
+const IMAGE_UPLOAD: u8 = 1;
+
+/// Image bytes per upload packet, small enough for MCUboot's input buffer
+pub const CHUNK: usize = 128;
+
+/// An SMP request picked from the Transfer menu
+pub enum SmpCommand {
+    /// Uploads a signed image, marks it for a test boot and resets if asked
+    Upload {
+        path: PathBuf,
+        chunk: usize,
+        test: bool,
+        reset: bool,
+    },
+    /// Lists the images in the slots
+    List,
+    /// Confirms the running image so it stays after the next reset
+    Confirm,
+    Reset,
+}
+
+impl SmpCommand {
+    pub fn title(&self) -> &'static str {
+        match self {
+            SmpCommand::Upload { .. } => "MCUboot Upload",
+            SmpCommand::List => "MCUboot Image List",
+            SmpCommand::Confirm => "MCUboot Confirm",
+            SmpCommand::Reset => "MCUboot Reset",
+        }
+    }
+}
+
+/// An image slot as the image state command reports it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageSlot {
+    pub image: u64,
+    pub slot: u64,
+    pub version: String,
+    pub hash: Vec<u8>,
+    pub bootable: bool,
+    pub pending: bool,
+    pub confirmed: bool,
+    pub active: bool,
+    pub permanent: bool,
+}
+
+impl ImageSlot {
+    fn parse(value: &Value) -> Self {
+        let flag = |key| field(value, key).and_then(Value::as_bool).unwrap_or(false);
+        Self {
+            image: field(value, "image").and_then(as_u64).unwrap_or(0),
+            slot: field(value, "slot").and_then(as_u64).unwrap_or(0),
+            version: field(value, "version")
+                .and_then(Value::as_text)
+                .unwrap_or_default()
+                .to_string(),
+            hash: field(value, "hash")
+                .and_then(Value::as_bytes)
+                .cloned()
+                .unwrap_or_default(),
+            bootable: flag("bootable"),
+            pending: flag("pending"),
+            confirmed: flag("confirmed"),
+            active: flag("active"),
+            permanent: flag("permanent"),
+        }
+    }
+}
+
+impl fmt::Display for ImageSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "image {} slot {}: {}",
+            self.image, self.slot, self.version
+        )?;
+        for (set, name) in [
+            (self.active, "active"),
+            (self.confirmed, "confirmed"),
+            (self.pending, "pending"),
+            (self.permanent, "permanent"),
+        ] {
+            if set {
+                write!(f, " {name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Talks SMP to MCUboot or an MCUmgr enabled application
+pub struct Smp {
+    timeout: Duration,
+    retries: u32,
+    sequence: u8,
+    reporter: Reporter,
+}
+
+impl Smp {
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(3),
+            retries: 3,
+            sequence: 0,
+            reporter: Reporter::default(),
+        }
+    }
+
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Runs a command, returns a message describing the result
+    pub fn run(
+        &mut self,
+        device: &mut dyn Transport,
+        command: SmpCommand,
+    ) -> Result<String, TransferError> {
+        self.reporter.start();
+        let result = self.run_command(device, command);
+        self.reporter.finish(result)
+    }
+
+    fn run_command(
+        &mut self,
+        device: &mut dyn Transport,
+        command: SmpCommand,
+    ) -> Result<String, TransferError> {
+        match command {
+            SmpCommand::Upload {
+                path,
+                chunk,
+                test,
+                reset,
+            } => {
+                let image = std::fs::read(&path).map_err(TransferError::StreamRead)?;
+                self.reporter.set_total(Some(image.len() as u64));
+                self.upload(device, &image, chunk)?;
+                if test {
+                    let slots = self.list(device)?;
+                    let slot = slots
+                        .iter()
+                        .find(|slot| !slot.active && !slot.hash.is_empty())
+                        .ok_or(TransferError::Protocol("No uploaded image to test"))?;
+                    self.set_state(device, Some(&slot.hash), false)?;
+                }
+                if reset {
+                    self.reset(device)?;
+                }
+                Ok(format!(
+                    "Uploaded {} bytes: {}",
+                    image.len(),
+                    path.display()
+                ))
+            }
+            SmpCommand::List => {
+                let slots = self.list(device)?;
+                let slots: Vec<String> = slots.iter().map(ImageSlot::to_string).collect();
+                Ok(format!("Images: {}", slots.join(", ")))
+            }
+            SmpCommand::Confirm => {
+                self.set_state(device, None, true)?;
+                Ok("Running image confirmed".to_string())
+            }
+            SmpCommand::Reset => {
+                self.reset(device)?;
+                Ok("Device reset".to_string())
+            }
+        }
+    }
+
+    /// Reads the state of the image slots
+    pub fn list(&mut self, device: &mut dyn Transport) -> Result<Vec<ImageSlot>, TransferError> {
+        let body = self.request(device, OP_READ, GROUP_IMAGE, IMAGE_STATE, map(vec![]))?;
+        Ok(slots(&body))
+    }
+
+    /// Confirms an image, or marks it for a single test boot. Without a
+    /// hash it applies to the running image.
+    pub fn set_state(
+        &mut self,
+        device: &mut dyn Transport,
+        hash: Option<&[u8]>,
+        confirm: bool,
+    ) -> Result<Vec<ImageSlot>, TransferError> {
+        let mut entries = vec![("confirm", Value::Bool(confirm))];
+        if let Some(hash) = hash {
+            entries.push(("hash", Value::Bytes(hash.to_vec())));
+        }
+        let body = self.request(device, OP_WRITE, GROUP_IMAGE, IMAGE_STATE, map(entries))?;
+        Ok(slots(&body))
+    }
+
+    pub fn reset(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        self.request(device, OP_WRITE, GROUP_OS, OS_RESET, map(vec![]))?;
+        Ok(())
+    }
+
+    /// Uploads an image in chunks, carrying on from the offset the device
+    /// asks for next
+    pub fn upload(
+        &mut self,
+        device: &mut dyn Transport,
+        image: &[u8],
+        chunk: usize,
+    ) -> Result<(), TransferError> {
+        let sha = Sha256::digest(image).to_vec();
+        let mut offset = 0;
+        let mut retries = 0;
+        self.reporter.set_state(TransferState::Transferring);
+        while offset < image.len() {
+            if self.reporter.is_cancelled() {
+                return Err(TransferError::Aborted);
+            }
+            let end = image.len().min(offset + chunk.max(1));
+            let mut entries = vec![
+                ("off", Value::from(offset as u64)),
+                ("data", Value::Bytes(image[offset..end].to_vec())),
+            ];
+            if offset == 0 {
+                entries.push(("image", Value::from(0u64)));
+                entries.push(("len", Value::from(image.len() as u64)));
+                entries.push(("sha", Value::Bytes(sha.clone())));
+            }
+            let body = match self.request(device, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, map(entries))
+            {
+                Ok(body) => body,
+                Err(err) if err.is_recoverable() && retries < self.retries => {
+                    retries += 1;
+                    self.reporter.count_retry();
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            retries = 0;
+            let next = field(&body, "off")
+                .and_then(as_u64)
+                .ok_or(TransferError::Protocol("Upload reply without an offset"))?;
+            offset = (next as usize).min(image.len());
+            self.reporter.count_position(offset as u64);
+        }
+        Ok(())
+    }
+
+    /// Sends a request and waits for its response, fails on a non-zero rc
+    fn request(
+        &mut self,
+        device: &mut dyn Transport,
+        op: u8,
+        group: u16,
+        id: u8,
+        body: Value,
+    ) -> Result<Value, TransferError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let mut packet = vec![op, 0, 0, 0];
+        packet.extend_from_slice(&group.to_be_bytes());
+        packet.extend_from_slice(&[self.sequence, id]);
+        ciborium::ser::into_writer(&body, &mut packet)
+            .map_err(|_| TransferError::Protocol("Failed to encode CBOR body"))?;
+        let len = (packet.len() - 8) as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        device.write_all(&encode_frames(&packet))?;
+        device.flush()?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let packet = self.read_packet(device, deadline)?;
+            if packet.len() < 8
+                || packet[0] != op + 1
+                || packet[4..6] != group.to_be_bytes()
+                || packet[6] != self.sequence
+                || packet[7] != id
+            {
+                // A late response to an earlier request
+                continue;
+            }
+            let body: Value = ciborium::de::from_reader(&packet[8..])
+                .map_err(|_| TransferError::Protocol("Invalid CBOR body"))?;
+            return match field(&body, "rc").and_then(as_u64) {
+                Some(0) | None => Ok(body),
+                Some(rc) => Err(TransferError::Remote(format!("MCUmgr error {rc}"))),
+            };
+        }
+    }
+
+    /// Reads console lines until a whole packet arrived, skipping log output
+    fn read_packet(
+        &mut self,
+        device: &mut dyn Transport,
+        deadline: Instant,
+    ) -> Result<Vec<u8>, TransferError> {
+        let mut decoded: Option<Vec<u8>> = None;
+        loop {
+            let line = self.read_line(device, deadline)?;
+            match decode_line(&line, &mut decoded)? {
+                Some(packet) => return Ok(packet),
+                None => continue,
+            }
+        }
+    }
+
+    fn read_line(
+        &mut self,
+        device: &mut dyn Transport,
+        deadline: Instant,
+    ) -> Result<Vec<u8>, TransferError> {
+        let mut line = vec![];
+        let mut byte = [0; 1];
+        loop {
+            if self.reporter.is_cancelled() {
+                return Err(TransferError::Aborted);
+            }
+            match device.read(&mut byte) {
+                Ok(0) => return Err(TransferError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                Ok(_) if byte[0] == b'\n' => return Ok(line),
+                Ok(_) => line.push(byte[0]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+    }
+}
+
+/// Frames a packet as base64 console lines
+fn encode_frames(packet: &[u8]) -> Vec<u8> {
+    let mut raw = ((packet.len() + 2) as u16).to_be_bytes().to_vec();
+    raw.extend_from_slice(packet);
+    raw.extend_from_slice(&crc(packet).to_be_bytes());
+    let encoded = STANDARD.encode(raw);
+    let mut frames = vec![];
+    // A multiple of 4 so every line decodes on its own
+    for (index, part) in encoded.as_bytes().chunks(MAX_FRAME - 3).enumerate() {
+        frames.extend_from_slice(if index == 0 {
+            &FRAME_START
+        } else {
+            &FRAME_CONTINUE
+        });
+        frames.extend_from_slice(part);
+        frames.push(b'\n');
+    }
+    frames
+}
+
+/// Adds a console line to the packet being decoded, returns the packet
+/// once it is complete and its CRC checks out
+fn decode_line(
+    line: &[u8],
+    decoded: &mut Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>, TransferError> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let data = if let Some(data) = line.strip_prefix(&FRAME_START) {
+        *decoded = Some(vec![]);
+        data
+    } else if let Some(data) = line.strip_prefix(&FRAME_CONTINUE) {
+        data
+    } else {
+        return Ok(None);
+    };
+    let buffer = match decoded {
+        Some(buffer) => buffer,
+        None => return Ok(None),
+    };
+    let bytes = STANDARD
+        .decode(data)
+        .map_err(|_| TransferError::Protocol("Invalid base64 in frame"))?;
+    buffer.extend_from_slice(&bytes);
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if buffer.len() < len + 2 {
+        return Ok(None);
+    }
+    let packet = decoded.take().unwrap_or_default();
+    // The CRC over the packet and its own CRC comes out as zero
+    if len < 2 || crc(&packet[2..len + 2]) != 0 {
+        return Err(TransferError::Protocol("Bad CRC in packet"));
+    }
+    Ok(Some(packet[2..len].to_vec()))
+}
+
+fn map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (Value::Text(key.to_string()), value))
+            .collect(),
+    )
+}
+
+/// Looks up a key of a CBOR map
+fn field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    value
+        .as_map()?
+        .iter()
+        .find(|(name, _)| name.as_text() == Some(key))
+        .map(|(_, value)| value)
+}
+
+fn as_u64(value: &Value) -> Option<u64> {
+    value.as_integer()?.try_into().ok()
+}
+
+fn slots(body: &Value) -> Vec<ImageSlot> {
+    field(body, "images")
+        .and_then(Value::as_array)
+        .map(|images| images.iter().map(ImageSlot::parse).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+#[test]
+fn test_frames() {
+    let packet: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    let frames = encode_frames(&packet);
+    let lines: Vec<&[u8]> = frames.split(|&byte| byte == b'\n').collect();
+    assert!(lines.len() > 3);
+    assert!(lines.iter().all(|line| line.len() < MAX_FRAME));
+    let mut decoded = None;
+    // Log output between frames is skipped
+    assert_eq!(
+        decode_line(b"[00:00:01] booting", &mut decoded).unwrap(),
+        None
+    );
+    let mut result = None;
+    for line in lines {
+        if let Some(packet) = decode_line(line, &mut decoded).unwrap() {
+            result = Some(packet);
+        }
+    }
+    assert_eq!(result, Some(packet));
+}
+
+/// A device with two image slots, simulated well enough to upload to
+#[cfg(test)]
+fn simulate_device(device: &mut crate::transport::Loopback) -> Vec<u8> {
+    use std::io::Write;
+    let mut smp = Smp::new();
+    let mut image = vec![];
+    let mut pending = false;
+    loop {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let packet = match smp.read_packet(device, deadline) {
+            Ok(packet) => packet,
+            Err(_) => return image,
+        };
+        let body: Value = ciborium::de::from_reader(&packet[8..]).unwrap();
+        let group = u16::from_be_bytes([packet[4], packet[5]]);
+        let reply = match (group, packet[7]) {
+            (GROUP_IMAGE, IMAGE_UPLOAD) => {
+                let offset = field(&body, "off").and_then(as_u64).unwrap() as usize;
+                assert_eq!(offset, image.len());
+                image.extend_from_slice(field(&body, "data").and_then(Value::as_bytes).unwrap());
+                map(vec![
+                    ("rc", Value::from(0u64)),
+                    ("off", Value::from(image.len() as u64)),
+                ])
+            }
+            (GROUP_IMAGE, IMAGE_STATE) => {
+                if packet[0] == OP_WRITE {
+                    pending = field(&body, "confirm") == Some(&Value::Bool(false));
+                }
+                let slot = |slot: u64, active, pending| {
+                    map(vec![
+                        ("slot", Value::from(slot)),
+                        ("version", Value::Text(format!("1.0.{slot}"))),
+                        ("hash", Value::Bytes(vec![slot as u8; 32])),
+                        ("active", Value::Bool(active)),
+                        ("pending", Value::Bool(pending)),
+                    ])
+                };
+                map(vec![(
+                    "images",
+                    Value::Array(vec![slot(0, true, false), slot(1, false, pending)]),
+                )])
+            }
+            (GROUP_OS, OS_RESET) => {
+                assert!(pending);
+                map(vec![])
+            }
+            _ => map(vec![("rc", Value::from(8u64))]),
+        };
+        let mut response = packet[..8].to_vec();
+        response[0] += 1;
+        ciborium::ser::into_writer(&reply, &mut response).unwrap();
+        let len = (response.len() - 8) as u16;
+        response[2..4].copy_from_slice(&len.to_be_bytes());
+        device.write_all(&encode_frames(&response)).unwrap();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_upload_simulated_device() {
+    use crate::transport::loopback_pair;
+    let (mut device, mut peer) = loopback_pair();
+    let image: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let path = std::env::temp_dir().join(format!("terminalrs_smp_{}.bin", std::process::id()));
+    std::fs::write(&path, &image).unwrap();
+    let handle = std::thread::spawn(move || simulate_device(&mut peer));
+    let mut smp = Smp::new();
+    let command = SmpCommand::Upload {
+        path: path.clone(),
+        chunk: CHUNK,
+        test: true,
+        reset: true,
+    };
+    smp.run(&mut device, command).unwrap();
+    let message = smp.run(&mut device, SmpCommand::List).unwrap();
+    assert!(message.contains("slot 1: 1.0.1 pending"));
+    drop(device);
+    assert_eq!(handle.join().unwrap(), image);
+    let _ = std::fs::remove_file(path);
+}
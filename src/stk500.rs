@@ -0,0 +1,602 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+// STK500 version 1, as spoken by Optiboot and the other ATmega Arduino
+// bootloaders. Commands end with EOP, replies come between INSYNC and OK.
+const INSYNC: u8 = 0x14;
+const OK: u8 = 0x10;
+const EOP: u8 = 0x20;
+
+const GET_SYNC: u8 = 0x30;
+const SET_PARAMETER: u8 = 0x40;
+const GET_PARAMETER: u8 = 0x41;
+const ENTER_PROGMODE: u8 = 0x50;
+const LEAVE_PROGMODE: u8 = 0x51;
+const LOAD_ADDRESS: u8 = 0x55;
+const PROG_PAGE: u8 = 0x64;
+const READ_PAGE: u8 = 0x74;
+const READ_SIGN: u8 = 0x75;
+
+const HW_VER: u8 = 0x80;
+const SW_MAJOR: u8 = 0x81;
+const SW_MINOR: u8 = 0x82;
+
+// STK500 version 2, as spoken by the ATmega2560 bootloader. Messages are
+// MESSAGE_START, sequence, size, TOKEN, body and the XOR of all of it.
+const MESSAGE_START: u8 = 0x1B;
+const TOKEN: u8 = 0x0E;
+const STATUS_CMD_OK: u8 = 0x00;
+
+const CMD_SIGN_ON: u8 = 0x01;
+const CMD_SET_PARAMETER: u8 = 0x02;
+const CMD_GET_PARAMETER: u8 = 0x03;
+const CMD_LOAD_ADDRESS: u8 = 0x06;
+const CMD_ENTER_PROGMODE_ISP: u8 = 0x10;
+const CMD_LEAVE_PROGMODE_ISP: u8 = 0x11;
+const CMD_PROGRAM_FLASH_ISP: u8 = 0x13;
+const CMD_READ_FLASH_ISP: u8 = 0x14;
+const CMD_READ_SIGNATURE_ISP: u8 = 0x1B;
+
+const PARAM_SW_MAJOR: u8 = 0x91;
+const PARAM_SW_MINOR: u8 = 0x92;
+const PARAM_RESET_POLARITY: u8 = 0x9E;
+
+/// Flash page size of the parts Arduino boards carry, by signature
+const PAGE_SIZES: [([u8; 3], usize); 5] = [
+    ([0x1E, 0x95, 0x0F], 128), // ATmega328P
+    ([0x1E, 0x95, 0x14], 128), // ATmega328
+    ([0x1E, 0x94, 0x06], 128), // ATmega168
+    ([0x1E, 0x95, 0x87], 128), // ATmega32U4
+    ([0x1E, 0x98, 0x01], 256), // ATmega2560
+];
+const DEFAULT_PAGE_SIZE: usize = 128;
+
+/// Which protocol the bootloader speaks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// Talks to an AVR bootloader
+pub struct Stk500 {
+    version: Version,
+    timeout: Duration,
+    sequence: u8,
+    reporter: Reporter,
+}
+
+impl Stk500 {
+    pub fn new(version: Version) -> Self {
+        Self {
+            version,
+            timeout: Duration::from_millis(500),
+            sequence: 0,
+            reporter: Reporter::default(),
+        }
+    }
+
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Pulses DTR, which Arduino boards couple to the reset pin, so the
+    /// bootloader runs
+    pub fn reset(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.set_control_lines(false, false)?;
+        std::thread::sleep(Duration::from_millis(250));
+        device.set_control_lines(true, true)?;
+        std::thread::sleep(Duration::from_millis(50));
+        Ok(())
+    }
+
+    /// Gets in sync with the bootloader, which may still be starting up
+    pub fn sync(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        for attempt in 1..=10 {
+            self.check_cancel()?;
+            device.clear_input()?;
+            let result = match self.version {
+                Version::V1 => self.command(device, &[GET_SYNC], 0).map(|_| ()),
+                Version::V2 => self.sign_on(device),
+            };
+            match result {
+                Ok(()) => {
+                    println!("In sync with the bootloader after {attempt} attempts");
+                    return Ok(());
+                }
+                Err(err) if err.is_recoverable() => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(TransferError::SyncTimeout)
+    }
+
+    fn sign_on(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let reply = self.message(device, &[CMD_SIGN_ON])?;
+        let name = reply.get(1..).unwrap_or_default();
+        println!("Programmer {}", String::from_utf8_lossy(name));
+        Ok(())
+    }
+
+    pub fn get_parameter(
+        &mut self,
+        device: &mut dyn Transport,
+        parameter: u8,
+    ) -> Result<u8, TransferError> {
+        let reply = match self.version {
+            Version::V1 => self.command(device, &[GET_PARAMETER, parameter], 1)?,
+            Version::V2 => self.message(device, &[CMD_GET_PARAMETER, parameter])?,
+        };
+        reply
+            .first()
+            .copied()
+            .ok_or(TransferError::Protocol("Parameter reply without a value"))
+    }
+
+    pub fn set_parameter(
+        &mut self,
+        device: &mut dyn Transport,
+        parameter: u8,
+        value: u8,
+    ) -> Result<(), TransferError> {
+        match self.version {
+            Version::V1 => self.command(device, &[SET_PARAMETER, parameter, value], 0)?,
+            Version::V2 => self.message(device, &[CMD_SET_PARAMETER, parameter, value])?,
+        };
+        Ok(())
+    }
+
+    pub fn read_signature(&mut self, device: &mut dyn Transport) -> Result<[u8; 3], TransferError> {
+        let mut signature = [0; 3];
+        match self.version {
+            Version::V1 => {
+                let reply = self.command(device, &[READ_SIGN], 3)?;
+                signature.copy_from_slice(&reply);
+            }
+            Version::V2 => {
+                for (index, byte) in signature.iter_mut().enumerate() {
+                    let reply = self.message(
+                        device,
+                        &[CMD_READ_SIGNATURE_ISP, 4, 0x30, 0, index as u8, 0],
+                    )?;
+                    *byte = *reply
+                        .first()
+                        .ok_or(TransferError::Protocol("Signature reply without a value"))?;
+                }
+            }
+        }
+        Ok(signature)
+    }
+
+    pub fn enter_progmode(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        match self.version {
+            Version::V1 => self.command(device, &[ENTER_PROGMODE], 0)?,
+            // Timing and ISP command for a programmer, the bootloader ignores them
+            Version::V2 => self.message(
+                device,
+                &[
+                    CMD_ENTER_PROGMODE_ISP,
+                    200,
+                    100,
+                    25,
+                    32,
+                    0,
+                    0x53,
+                    3,
+                    0xAC,
+                    0x53,
+                    0,
+                    0,
+                ],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Leaves programming mode, the bootloader starts the application
+    pub fn leave_progmode(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        match self.version {
+            Version::V1 => self.command(device, &[LEAVE_PROGMODE], 0)?,
+            Version::V2 => self.message(device, &[CMD_LEAVE_PROGMODE_ISP, 1, 1])?,
+        };
+        Ok(())
+    }
+
+    /// Sets the byte address the next page goes to or comes from
+    pub fn load_address(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+    ) -> Result<(), TransferError> {
+        // Flash is addressed in words
+        let word = address >> 1;
+        match self.version {
+            Version::V1 => {
+                if word > 0xFFFF {
+                    return Err(TransferError::Protocol("Address out of STK500v1 range"));
+                }
+                let [low, high, ..] = word.to_le_bytes();
+                self.command(device, &[LOAD_ADDRESS, low, high], 0)?;
+            }
+            Version::V2 => {
+                // The top bit has the bootloader set the extended address
+                let word = if word > 0xFFFF {
+                    word | 0x8000_0000
+                } else {
+                    word
+                };
+                let mut message = vec![CMD_LOAD_ADDRESS];
+                message.extend_from_slice(&word.to_be_bytes());
+                self.message(device, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a flash page at the loaded address
+    pub fn program_page(
+        &mut self,
+        device: &mut dyn Transport,
+        page: &[u8],
+    ) -> Result<(), TransferError> {
+        let [high, low] = (page.len() as u16).to_be_bytes();
+        let mut message = match self.version {
+            Version::V1 => vec![PROG_PAGE, high, low, b'F'],
+            // Page mode and the ISP commands of a programmer
+            Version::V2 => vec![
+                CMD_PROGRAM_FLASH_ISP,
+                high,
+                low,
+                0xC1,
+                10,
+                0x40,
+                0x4C,
+                0x20,
+                0,
+                0,
+            ],
+        };
+        message.extend_from_slice(page);
+        match self.version {
+            Version::V1 => self.command(device, &message, 0)?,
+            Version::V2 => self.message(device, &message)?,
+        };
+        Ok(())
+    }
+
+    /// Reads a flash page from the loaded address
+    pub fn read_page(
+        &mut self,
+        device: &mut dyn Transport,
+        len: usize,
+    ) -> Result<Vec<u8>, TransferError> {
+        let [high, low] = (len as u16).to_be_bytes();
+        match self.version {
+            Version::V1 => self.command(device, &[READ_PAGE, high, low, b'F'], len),
+            Version::V2 => {
+                let mut reply = self.message(device, &[CMD_READ_FLASH_ISP, high, low, 0x20])?;
+                // The data is followed by a second status
+                if reply.pop() != Some(STATUS_CMD_OK) || reply.len() != len {
+                    return Err(TransferError::Protocol("Bad flash read reply"));
+                }
+                Ok(reply)
+            }
+        }
+    }
+
+    /// Resets the board if asked, writes the image at the address and
+    /// reads it back, then starts the application
+    pub fn upload(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        image: &[u8],
+        reset: bool,
+    ) -> Result<(), TransferError> {
+        self.reporter.set_total(Some(image.len() as u64));
+        self.reporter.start();
+        let result = self.upload_image(device, address, image, reset);
+        self.reporter.finish(result)
+    }
+
+    fn upload_image(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        image: &[u8],
+        reset: bool,
+    ) -> Result<(), TransferError> {
+        if reset {
+            // A board without DTR wired can still be reset by hand
+            match self.reset(device) {
+                Err(TransferError::Io(err)) if err.kind() == ErrorKind::Unsupported => {
+                    println!("No DTR, reset the board by hand")
+                }
+                Err(err) => println!("DTR reset failed: {err}"),
+                Ok(()) => (),
+            }
+        }
+        self.sync(device)?;
+        match self.version {
+            Version::V1 => {
+                let hardware = self.get_parameter(device, HW_VER)?;
+                let major = self.get_parameter(device, SW_MAJOR)?;
+                let minor = self.get_parameter(device, SW_MINOR)?;
+                println!("Bootloader hardware {hardware}, version {major}.{minor}");
+            }
+            Version::V2 => {
+                let major = self.get_parameter(device, PARAM_SW_MAJOR)?;
+                let minor = self.get_parameter(device, PARAM_SW_MINOR)?;
+                println!("Bootloader version {major}.{minor}");
+                // As avrdude does, the bootloader accepts and ignores it
+                self.set_parameter(device, PARAM_RESET_POLARITY, 1)?;
+            }
+        }
+        self.enter_progmode(device)?;
+        let signature = self.read_signature(device)?;
+        let page_size = PAGE_SIZES
+            .iter()
+            .find(|(known, _)| *known == signature)
+            .map(|(_, size)| *size)
+            .unwrap_or(DEFAULT_PAGE_SIZE);
+        println!("Signature {signature:02X?}, {page_size} byte pages");
+        // Page sizes are powers of two
+        if address as usize & (page_size - 1) != 0 {
+            return Err(TransferError::Protocol("Image does not start on a page"));
+        }
+
+        self.reporter.set_state(TransferState::Transferring);
+        for (index, page) in image.chunks(page_size).enumerate() {
+            self.check_cancel()?;
+            self.load_address(device, address + (index * page_size) as u32)?;
+            self.program_page(device, page)?;
+            self.reporter.count_packet(page.len());
+        }
+
+        self.reporter.set_state(TransferState::Finishing);
+        for (index, page) in image.chunks(page_size).enumerate() {
+            self.check_cancel()?;
+            let page_address = address + (index * page_size) as u32;
+            self.load_address(device, page_address)?;
+            if self.read_page(device, page.len())? != page {
+                return Err(TransferError::Remote(format!(
+                    "Verify failed in page at {page_address:#06x}"
+                )));
+            }
+        }
+        self.leave_progmode(device)
+    }
+
+    /// The bootloader has no abort, it times out and runs what is in flash
+    fn check_cancel(&self) -> Result<(), TransferError> {
+        if self.reporter.is_cancelled() {
+            return Err(TransferError::Aborted);
+        }
+        Ok(())
+    }
+
+    /// Sends a version 1 command and returns the reply between INSYNC and OK
+    fn command(
+        &mut self,
+        device: &mut dyn Transport,
+        command: &[u8],
+        reply_len: usize,
+    ) -> Result<Vec<u8>, TransferError> {
+        device.write_all(command)?;
+        device.write_all(&[EOP])?;
+        device.flush()?;
+        let deadline = Instant::now() + self.timeout;
+        if self.read_byte(device, deadline)? != INSYNC {
+            return Err(TransferError::Protocol("Bootloader not in sync"));
+        }
+        let mut reply = vec![0; reply_len];
+        for byte in reply.iter_mut() {
+            *byte = self.read_byte(device, deadline)?;
+        }
+        if self.read_byte(device, deadline)? != OK {
+            return Err(TransferError::Protocol("Bootloader failed the command"));
+        }
+        Ok(reply)
+    }
+
+    /// Sends a version 2 message, returns the reply body after the command
+    /// and status
+    fn message(
+        &mut self,
+        device: &mut dyn Transport,
+        body: &[u8],
+    ) -> Result<Vec<u8>, TransferError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        device.write_all(&encode_message(self.sequence, body))?;
+        device.flush()?;
+        let deadline = Instant::now() + self.timeout;
+        let (sequence, reply) = self.read_message(device, deadline)?;
+        if sequence != self.sequence || reply.first() != body.first() {
+            return Err(TransferError::Protocol("Reply to another message"));
+        }
+        match reply.get(1) {
+            Some(&STATUS_CMD_OK) => Ok(reply[2..].to_vec()),
+            Some(status) => Err(TransferError::Remote(format!(
+                "Command {:#04x} failed with status {status:#04x}",
+                body[0]
+            ))),
+            None => Err(TransferError::Protocol("Reply without a status")),
+        }
+    }
+
+    /// Reads a version 2 message, returns its sequence number and body
+    fn read_message(
+        &mut self,
+        device: &mut dyn Transport,
+        deadline: Instant,
+    ) -> Result<(u8, Vec<u8>), TransferError> {
+        while self.read_byte(device, deadline)? != MESSAGE_START {}
+        let mut header = [MESSAGE_START, 0, 0, 0, 0];
+        for byte in header[1..].iter_mut() {
+            *byte = self.read_byte(device, deadline)?;
+        }
+        if header[4] != TOKEN {
+            return Err(TransferError::Protocol("Message without a token"));
+        }
+        let size = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0; size];
+        for byte in body.iter_mut() {
+            *byte = self.read_byte(device, deadline)?;
+        }
+        let checksum = self.read_byte(device, deadline)?;
+        if xor(&header) ^ xor(&body) != checksum {
+            return Err(TransferError::Protocol("Bad message checksum"));
+        }
+        Ok((header[1], body))
+    }
+
+    fn read_byte(
+        &mut self,
+        device: &mut dyn Transport,
+        deadline: Instant,
+    ) -> Result<u8, TransferError> {
+        let mut byte = [0; 1];
+        loop {
+            match device.read(&mut byte) {
+                Ok(0) => return Err(TransferError::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(_) => return Ok(byte[0]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+    }
+}
+
+fn encode_message(sequence: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![MESSAGE_START, sequence];
+    message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    message.push(TOKEN);
+    message.extend_from_slice(body);
+    message.push(xor(&message));
+    message
+}
+
+fn xor(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// Optiboot or the ATmega2560 bootloader on an ATmega328P, simulated on the
+/// far end of a PTY. Returns the flash once the application is started.
+#[cfg(all(test, unix))]
+fn simulate_bootloader(device: &mut dyn Transport, version: Version) -> Vec<u8> {
+    let mut loader = Stk500::new(version);
+    let mut flash = vec![0xFF; 0x8000];
+    let mut address = 0;
+    let deadline = || Instant::now() + Duration::from_secs(2);
+    loop {
+        let (sequence, request) = match version {
+            Version::V1 => {
+                let mut request = vec![];
+                loop {
+                    match loader.read_byte(device, deadline()) {
+                        Ok(EOP) if request.len() >= expected_v1_len(&request) => break,
+                        Ok(byte) => request.push(byte),
+                        Err(_) => return flash,
+                    }
+                }
+                (0, request)
+            }
+            Version::V2 => match loader.read_message(device, deadline()) {
+                Ok(message) => message,
+                Err(_) => return flash,
+            },
+        };
+        let mut reply = vec![];
+        let mut done = false;
+        match (version, request[0]) {
+            (Version::V1, LOAD_ADDRESS) => {
+                address = u16::from_le_bytes([request[1], request[2]]) as usize * 2
+            }
+            (Version::V2, CMD_LOAD_ADDRESS) => {
+                let word = u32::from_be_bytes(request[1..5].try_into().unwrap()) & 0x7FFF_FFFF;
+                address = word as usize * 2;
+            }
+            (Version::V1, PROG_PAGE) => {
+                flash[address..address + request.len() - 4].copy_from_slice(&request[4..])
+            }
+            (Version::V2, CMD_PROGRAM_FLASH_ISP) => {
+                flash[address..address + request.len() - 10].copy_from_slice(&request[10..])
+            }
+            (Version::V1, READ_PAGE) | (Version::V2, CMD_READ_FLASH_ISP) => {
+                let len = u16::from_be_bytes([request[1], request[2]]) as usize;
+                reply.extend_from_slice(&flash[address..address + len]);
+            }
+            (Version::V1, READ_SIGN) => reply.extend_from_slice(&[0x1E, 0x95, 0x0F]),
+            (Version::V2, CMD_READ_SIGNATURE_ISP) => {
+                reply.push([0x1E, 0x95, 0x0F][request[4] as usize])
+            }
+            (Version::V1, GET_PARAMETER) | (Version::V2, CMD_GET_PARAMETER) => reply.push(8),
+            (Version::V2, CMD_SIGN_ON) => reply.extend_from_slice(b"\x08AVRISP_2"),
+            (Version::V1, LEAVE_PROGMODE) | (Version::V2, CMD_LEAVE_PROGMODE_ISP) => done = true,
+            _ => (),
+        }
+        let response = match version {
+            Version::V1 => [&[INSYNC][..], &reply, &[OK]].concat(),
+            Version::V2 => {
+                if request[0] == CMD_READ_FLASH_ISP {
+                    reply.push(STATUS_CMD_OK);
+                }
+                let body = [&[request[0], STATUS_CMD_OK][..], &reply].concat();
+                encode_message(sequence, &body)
+            }
+        };
+        device.write_all(&response).unwrap();
+        if done {
+            return flash;
+        }
+    }
+}
+
+/// Bytes a version 1 command takes before its EOP, as addresses and data
+/// may hold EOP bytes too
+#[cfg(all(test, unix))]
+fn expected_v1_len(request: &[u8]) -> usize {
+    match request.first() {
+        Some(&PROG_PAGE) if request.len() >= 3 => {
+            4 + u16::from_be_bytes([request[1], request[2]]) as usize
+        }
+        Some(&PROG_PAGE) => usize::MAX,
+        Some(&LOAD_ADDRESS) | Some(&SET_PARAMETER) => 3,
+        Some(&READ_PAGE) => 4,
+        Some(&GET_PARAMETER) => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_upload_over_pty() {
+    use serialport::{SerialPort, TTYPort};
+    // Spans pages and ends in a part page, with EOP bytes in the data
+    let image: Vec<u8> = (0..700).map(|i| (i * 3) as u8).collect();
+    for version in [Version::V1, Version::V2] {
+        let (slave, master) = TTYPort::pair().unwrap();
+        let mut bootloader: Box<dyn SerialPort> = Box::new(master);
+        let mut device: Box<dyn SerialPort> = Box::new(slave);
+        // The bootloader end stays open until the upload is done with it
+        let handle = std::thread::spawn(move || {
+            let flash = simulate_bootloader(&mut bootloader, version);
+            (flash, bootloader)
+        });
+        let mut stk500 = Stk500::new(version);
+        stk500.upload(&mut device, 0x100, &image, true).unwrap();
+        let (flash, _) = handle.join().unwrap();
+        assert_eq!(flash[0x100..0x100 + image.len()], image[..]);
+        assert_eq!(flash[0x100 + image.len()], 0xFF);
+    }
+}
@@ -0,0 +1,590 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use std::time::{Duration, Instant};
+
+// STM32 USART bootloader protocol, ST application note AN3155.
+// The line runs 8 data bits with even parity.
+const INIT: u8 = 0x7F;
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1F;
+
+const GET: u8 = 0x00;
+const GET_ID: u8 = 0x02;
+const READ_MEMORY: u8 = 0x11;
+const GO: u8 = 0x21;
+const WRITE_MEMORY: u8 = 0x31;
+const ERASE: u8 = 0x43;
+const EXTENDED_ERASE: u8 = 0x44;
+
+/// Most bytes a read or write command moves
+pub const CHUNK: usize = 256;
+
+/// Flash on most parts, where `flash` writes a plain binary to
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// How a part's flash divides into the units Erase takes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashLayout {
+    /// Pages of the same size
+    Pages(u32),
+    /// Sectors of these sizes in KiB, in address order
+    Sectors(&'static [u32]),
+}
+
+const F4_SECTORS: [u32; 12] = [16, 16, 16, 16, 64, 128, 128, 128, 128, 128, 128, 128];
+/// Both banks of the 2 MiB parts
+const F42_SECTORS: [u32; 24] = [
+    16, 16, 16, 16, 64, 128, 128, 128, 128, 128, 128, 128, 16, 16, 16, 16, 64, 128, 128, 128, 128,
+    128, 128, 128,
+];
+const F74_SECTORS: [u32; 8] = [32, 32, 32, 32, 128, 256, 256, 256];
+const F76_SECTORS: [u32; 12] = [32, 32, 32, 32, 128, 256, 256, 256, 256, 256, 256, 256];
+const F72_SECTORS: [u32; 8] = [16, 16, 16, 16, 64, 128, 128, 128];
+
+impl FlashLayout {
+    /// The layout of a part by the product ID Get ID reports
+    pub fn of(product_id: u16) -> Option<Self> {
+        let layout = match product_id {
+            // F0, F1, F3
+            0x440 | 0x444 | 0x445 | 0x410 | 0x412 | 0x420 => FlashLayout::Pages(1024),
+            0x442 | 0x448 | 0x414 | 0x418 | 0x428 | 0x430 => FlashLayout::Pages(2048),
+            0x422 | 0x432 | 0x438 | 0x439 | 0x446 => FlashLayout::Pages(2048),
+            // G0, G4, L4
+            0x460 | 0x466 | 0x468 | 0x469 => FlashLayout::Pages(2048),
+            0x415 | 0x435 | 0x461 | 0x462 | 0x464 => FlashLayout::Pages(2048),
+            // L0
+            0x417 | 0x425 | 0x447 | 0x457 => FlashLayout::Pages(128),
+            // H7
+            0x450 => FlashLayout::Pages(128 * 1024),
+            // F2, F4
+            0x411 | 0x413 | 0x421 | 0x423 | 0x431 | 0x433 | 0x441 | 0x458 | 0x463 => {
+                FlashLayout::Sectors(&F4_SECTORS)
+            }
+            0x419 | 0x434 => FlashLayout::Sectors(&F42_SECTORS),
+            // F7
+            0x449 => FlashLayout::Sectors(&F74_SECTORS),
+            0x451 => FlashLayout::Sectors(&F76_SECTORS),
+            0x452 => FlashLayout::Sectors(&F72_SECTORS),
+            _ => return None,
+        };
+        Some(layout)
+    }
+
+    /// Numbers of the pages or sectors that bytes at the address touch
+    pub fn pages(&self, address: u32, len: usize) -> Result<Vec<u16>, TransferError> {
+        if address < FLASH_BASE {
+            return Err(TransferError::Protocol("Image starts below the flash"));
+        }
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let start = (address - FLASH_BASE) as u64;
+        let end = start + len as u64;
+        let pages = match *self {
+            FlashLayout::Pages(size) => {
+                let size = size as u64;
+                (start / size..=(end - 1) / size).collect::<Vec<_>>()
+            }
+            FlashLayout::Sectors(sizes) => {
+                let mut offset = 0;
+                let mut pages = vec![];
+                for (index, size) in sizes.iter().enumerate() {
+                    let next = offset + *size as u64 * 1024;
+                    if offset < end && next > start {
+                        pages.push(index as u64);
+                    }
+                    offset = next;
+                }
+                if offset < end {
+                    return Err(TransferError::Protocol("Image runs past the flash"));
+                }
+                pages
+            }
+        };
+        pages
+            .into_iter()
+            .map(|page| {
+                u16::try_from(page).map_err(|_| TransferError::Protocol("Page out of range"))
+            })
+            .collect()
+    }
+}
+
+/// What the bootloader reports to the Get command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootloaderInfo {
+    /// Protocol version, 0x31 is 3.1
+    pub version: u8,
+    /// Commands it supports
+    pub commands: Vec<u8>,
+}
+
+/// Talks to the STM32 system bootloader
+pub struct Stm32 {
+    /// Wait for the reply to a command
+    timeout: Duration,
+    /// Wait for an erase to finish, a mass erase takes many seconds
+    erase_timeout: Duration,
+    info: BootloaderInfo,
+    reporter: Reporter,
+}
+
+impl Stm32 {
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            erase_timeout: Duration::from_secs(30),
+            info: BootloaderInfo::default(),
+            reporter: Reporter::default(),
+        }
+    }
+
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Sends 0x7F so the bootloader detects the baud rate. A NACK means it
+    /// did so earlier and took the byte for a command, it is still ready.
+    pub fn connect(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.clear_input()?;
+        device.write_all(&[INIT])?;
+        match self.read_byte(device, self.timeout)? {
+            ACK | NACK => Ok(()),
+            _ => Err(TransferError::Protocol("Unexpected reply to autobaud")),
+        }
+    }
+
+    /// Reads the bootloader version and the commands it supports
+    pub fn get(&mut self, device: &mut dyn Transport) -> Result<BootloaderInfo, TransferError> {
+        self.command(device, GET)?;
+        let len = self.read_byte(device, self.timeout)? as usize;
+        let mut reply = vec![0; len + 1];
+        self.read_exact(device, &mut reply)?;
+        self.expect_ack(device, self.timeout)?;
+        self.info = BootloaderInfo {
+            version: reply[0],
+            commands: reply[1..].to_vec(),
+        };
+        println!(
+            "Bootloader version {:#04x}, commands {:02x?}",
+            self.info.version, self.info.commands
+        );
+        Ok(self.info.clone())
+    }
+
+    /// Reads the product ID of the chip
+    pub fn get_id(&mut self, device: &mut dyn Transport) -> Result<u16, TransferError> {
+        self.command(device, GET_ID)?;
+        let len = self.read_byte(device, self.timeout)? as usize;
+        let mut reply = vec![0; len + 1];
+        self.read_exact(device, &mut reply)?;
+        self.expect_ack(device, self.timeout)?;
+        if reply.len() < 2 {
+            return Err(TransferError::Protocol("Product ID too short"));
+        }
+        let id = u16::from_be_bytes([reply[0], reply[1]]);
+        println!("Product ID {id:#06x}");
+        Ok(id)
+    }
+
+    /// Reads up to 256 bytes of memory
+    pub fn read_memory(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, TransferError> {
+        if len == 0 || len > CHUNK {
+            return Err(TransferError::Protocol("Read length must be 1 to 256"));
+        }
+        self.command(device, READ_MEMORY)?;
+        self.send_address(device, address)?;
+        let count = (len - 1) as u8;
+        device.write_all(&[count, !count])?;
+        self.expect_ack(device, self.timeout)?;
+        let mut data = vec![0; len];
+        self.read_exact(device, &mut data)?;
+        Ok(data)
+    }
+
+    /// Writes up to 256 bytes of memory, padded with 0xFF to a multiple of 4
+    pub fn write_memory(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        if data.is_empty() || data.len() > CHUNK {
+            return Err(TransferError::Protocol("Write length must be 1 to 256"));
+        }
+        let mut block = data.to_vec();
+        block.resize((data.len() + 3) & !3, 0xFF);
+        self.command(device, WRITE_MEMORY)?;
+        self.send_address(device, address)?;
+        let mut frame = vec![(block.len() - 1) as u8];
+        frame.extend_from_slice(&block);
+        frame.push(xor(&frame));
+        device.write_all(&frame)?;
+        self.expect_ack(device, self.timeout)
+    }
+
+    /// Erases the whole flash, with Extended Erase if the bootloader has it
+    pub fn mass_erase(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        if self.info.commands.contains(&EXTENDED_ERASE) {
+            self.command(device, EXTENDED_ERASE)?;
+            device.write_all(&[0xFF, 0xFF, 0x00])?;
+        } else {
+            self.command(device, ERASE)?;
+            device.write_all(&[0xFF, 0x00])?;
+        }
+        self.expect_ack(device, self.erase_timeout)
+    }
+
+    /// Erases the pages, or sectors, with Extended Erase if the bootloader
+    /// has it
+    pub fn erase_pages(
+        &mut self,
+        device: &mut dyn Transport,
+        pages: &[u16],
+    ) -> Result<(), TransferError> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let mut frame = vec![];
+        if self.info.commands.contains(&EXTENDED_ERASE) {
+            // Larger requests can be refused, erase in batches
+            for batch in pages.chunks(CHUNK / 2) {
+                frame.clear();
+                frame.extend_from_slice(&(batch.len() as u16 - 1).to_be_bytes());
+                for page in batch {
+                    frame.extend_from_slice(&page.to_be_bytes());
+                }
+                frame.push(xor(&frame));
+                self.command(device, EXTENDED_ERASE)?;
+                device.write_all(&frame)?;
+                self.expect_ack(device, self.erase_timeout)?;
+            }
+            return Ok(());
+        }
+        for batch in pages.chunks(CHUNK - 1) {
+            frame.clear();
+            frame.push(batch.len() as u8 - 1);
+            for &page in batch {
+                let page = u8::try_from(page)
+                    .map_err(|_| TransferError::Protocol("Page out of range for Erase"))?;
+                frame.push(page);
+            }
+            frame.push(xor(&frame));
+            self.command(device, ERASE)?;
+            device.write_all(&frame)?;
+            self.expect_ack(device, self.erase_timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Starts the code at the address
+    pub fn go(&mut self, device: &mut dyn Transport, address: u32) -> Result<(), TransferError> {
+        self.command(device, GO)?;
+        self.send_address(device, address)
+    }
+
+    /// Erases the pages the image covers, or the whole flash if asked to,
+    /// writes the image at the address and reads it back to compare.
+    /// Starts it afterwards if asked to.
+    pub fn flash(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        image: &[u8],
+        mass_erase: bool,
+        start: bool,
+    ) -> Result<(), TransferError> {
+        self.reporter.set_total(Some(image.len() as u64 * 2));
+        self.reporter.start();
+        let result = self.flash_image(device, address, image, mass_erase, start);
+        self.reporter.finish(result)
+    }
+
+    fn flash_image(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+        image: &[u8],
+        mass_erase: bool,
+        start: bool,
+    ) -> Result<(), TransferError> {
+        self.connect(device)?;
+        self.get(device)?;
+        let id = self.get_id(device)?;
+        if mass_erase {
+            println!("Erasing flash");
+            self.mass_erase(device)?;
+        } else {
+            // Whatever else is in flash, a bootloader of our own too, stays
+            let layout = FlashLayout::of(id).ok_or(TransferError::Protocol(
+                "Unknown flash layout, mass erase instead",
+            ))?;
+            let pages = layout.pages(address, image.len())?;
+            println!("Erasing pages {pages:?}");
+            self.erase_pages(device, &pages)?;
+        }
+
+        self.reporter.set_state(TransferState::Transferring);
+        for (index, chunk) in image.chunks(CHUNK).enumerate() {
+            self.check_cancel()?;
+            let offset = (index * CHUNK) as u32;
+            self.write_memory(device, address + offset, chunk)?;
+            self.reporter.count_packet(chunk.len());
+        }
+
+        self.reporter.set_state(TransferState::Finishing);
+        for (index, chunk) in image.chunks(CHUNK).enumerate() {
+            self.check_cancel()?;
+            let offset = (index * CHUNK) as u32;
+            let data = self.read_memory(device, address + offset, chunk.len())?;
+            if data != chunk {
+                println!("Verify failed at {:#010x}", address + offset);
+                return Err(TransferError::Remote(format!(
+                    "Verify failed at {:#010x}",
+                    address + offset
+                )));
+            }
+            self.reporter.count_packet(chunk.len());
+        }
+
+        if start {
+            println!("Starting code at {address:#010x}");
+            self.go(device, address)?;
+        }
+        Ok(())
+    }
+
+    /// The bootloader has no abort, stopping between commands leaves it
+    /// ready for the next one.
+    fn check_cancel(&self) -> Result<(), TransferError> {
+        if self.reporter.is_cancelled() {
+            return Err(TransferError::Aborted);
+        }
+        Ok(())
+    }
+
+    /// Sends a command byte with its complement and waits for the ACK
+    fn command(&mut self, device: &mut dyn Transport, command: u8) -> Result<(), TransferError> {
+        device.write_all(&[command, !command])?;
+        self.expect_ack(device, self.timeout)
+    }
+
+    fn send_address(
+        &mut self,
+        device: &mut dyn Transport,
+        address: u32,
+    ) -> Result<(), TransferError> {
+        let mut frame = address.to_be_bytes().to_vec();
+        frame.push(xor(&frame));
+        device.write_all(&frame)?;
+        self.expect_ack(device, self.timeout)
+    }
+
+    fn expect_ack(
+        &mut self,
+        device: &mut dyn Transport,
+        timeout: Duration,
+    ) -> Result<(), TransferError> {
+        match self.read_byte(device, timeout)? {
+            ACK => Ok(()),
+            NACK => Err(TransferError::Remote("Bootloader sent NACK".to_owned())),
+            _ => Err(TransferError::Protocol("Expected ACK from bootloader")),
+        }
+    }
+
+    fn read_byte(
+        &mut self,
+        device: &mut dyn Transport,
+        timeout: Duration,
+    ) -> Result<u8, TransferError> {
+        let mut byte = [0; 1];
+        self.read_exact_within(device, &mut byte, timeout)?;
+        Ok(byte[0])
+    }
+
+    fn read_exact(
+        &mut self,
+        device: &mut dyn Transport,
+        buf: &mut [u8],
+    ) -> Result<(), TransferError> {
+        self.read_exact_within(device, buf, self.timeout)
+    }
+
+    fn read_exact_within(
+        &mut self,
+        device: &mut dyn Transport,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(), TransferError> {
+        let deadline = Instant::now() + timeout;
+        let mut len = 0;
+        while len < buf.len() {
+            self.check_cancel()?;
+            match device.read(&mut buf[len..]) {
+                Ok(0) => return Err(TransferError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                Ok(n) => len += n,
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if len < buf.len() && Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// XOR of the bytes, the checksum of addresses and data frames
+fn xor(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// A bootloader simulated well enough to flash and read back an image
+#[cfg(test)]
+fn simulate_bootloader(device: &mut crate::transport::Loopback, memory: &mut [u8], base: u32) {
+    use std::io::{Read, Write};
+    let read = |device: &mut crate::transport::Loopback, len: usize| -> Option<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let mut got = 0;
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while got < len {
+            match device.read(&mut buf[got..]) {
+                Ok(n) => got += n,
+                Err(_) if Instant::now() < deadline => (),
+                Err(_) => return None,
+            }
+        }
+        Some(buf)
+    };
+    let address = |frame: &[u8]| {
+        assert_eq!(xor(&frame[..4]), frame[4]);
+        u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) - base
+    };
+    assert_eq!(read(device, 1).unwrap(), [INIT]);
+    device.write_all(&[ACK]).unwrap();
+    while let Some(command) = read(device, 2) {
+        assert_eq!(command[0], !command[1]);
+        device.write_all(&[ACK]).unwrap();
+        match command[0] {
+            GET => {
+                let commands = [GET, GET_ID, READ_MEMORY, GO, WRITE_MEMORY, EXTENDED_ERASE];
+                device.write_all(&[commands.len() as u8, 0x31]).unwrap();
+                device.write_all(&commands).unwrap();
+                device.write_all(&[ACK]).unwrap();
+            }
+            // An F1 with 1 KiB pages
+            GET_ID => device.write_all(&[1, 0x04, 0x10, ACK]).unwrap(),
+            EXTENDED_ERASE => {
+                let count = read(device, 2).unwrap();
+                if count == [0xFF, 0xFF] {
+                    assert_eq!(read(device, 1).unwrap(), [0x00]);
+                    memory.fill(0xFF);
+                } else {
+                    let len = u16::from_be_bytes([count[0], count[1]]) as usize + 1;
+                    let pages = read(device, len * 2 + 1).unwrap();
+                    let mut frame = count.clone();
+                    frame.extend_from_slice(&pages[..len * 2]);
+                    assert_eq!(xor(&frame), pages[len * 2]);
+                    for page in pages[..len * 2].chunks(2) {
+                        let start = u16::from_be_bytes([page[0], page[1]]) as usize * 1024;
+                        memory[start..start + 1024].fill(0xFF);
+                    }
+                }
+                device.write_all(&[ACK]).unwrap();
+            }
+            WRITE_MEMORY => {
+                let start = address(&read(device, 5).unwrap()) as usize;
+                device.write_all(&[ACK]).unwrap();
+                let len = read(device, 1).unwrap()[0] as usize + 1;
+                let data = read(device, len + 1).unwrap();
+                let mut frame = vec![(len - 1) as u8];
+                frame.extend_from_slice(&data[..len]);
+                assert_eq!(xor(&frame), data[len]);
+                memory[start..start + len].copy_from_slice(&data[..len]);
+                device.write_all(&[ACK]).unwrap();
+            }
+            READ_MEMORY => {
+                let start = address(&read(device, 5).unwrap()) as usize;
+                device.write_all(&[ACK]).unwrap();
+                let count = read(device, 2).unwrap();
+                assert_eq!(count[0], !count[1]);
+                device.write_all(&[ACK]).unwrap();
+                let len = count[0] as usize + 1;
+                device.write_all(&memory[start..start + len]).unwrap();
+            }
+            GO => {
+                read(device, 5).unwrap();
+                device.write_all(&[ACK]).unwrap();
+                return;
+            }
+            _ => panic!("unexpected command {:#04x}", command[0]),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_flash_simulated_bootloader() {
+    use crate::transport::loopback_pair;
+    let (mut device, mut peer) = loopback_pair();
+    let image: Vec<u8> = (0..1000).map(|i| (i * 7 + i / 256) as u8).collect();
+    let handle = std::thread::spawn(move || {
+        let mut memory = vec![0; 4096];
+        simulate_bootloader(&mut peer, &mut memory, FLASH_BASE);
+        memory
+    });
+    let mut stm32 = Stm32::new();
+    stm32
+        .flash(&mut device, FLASH_BASE, &image, true, true)
+        .unwrap();
+    let memory = handle.join().unwrap();
+    assert_eq!(memory[..1000], image[..]);
+    // The last write is padded out to a whole word
+    assert_eq!(memory[1000..1004], [0xFF; 4]);
+    assert_eq!(stm32.info.version, 0x31);
+}
+
+#[cfg(test)]
+#[test]
+fn test_flash_erases_only_image_pages() {
+    use crate::transport::loopback_pair;
+    let (mut device, mut peer) = loopback_pair();
+    let image = vec![0x5A; 1500];
+    let handle = std::thread::spawn(move || {
+        // A bootloader of our own in the first page, data in the last
+        let mut memory = vec![0xAA; 4096];
+        simulate_bootloader(&mut peer, &mut memory, FLASH_BASE);
+        memory
+    });
+    let mut stm32 = Stm32::new();
+    stm32
+        .flash(&mut device, FLASH_BASE + 0x400, &image, false, true)
+        .unwrap();
+    let memory = handle.join().unwrap();
+    assert_eq!(memory[..0x400], [0xAA; 0x400]);
+    assert_eq!(memory[0x400..0x400 + 1500], image[..]);
+    assert_eq!(memory[0x400 + 1500..0xC00], [0xFF; 0xC00 - 0x400 - 1500]);
+    assert_eq!(memory[0xC00..], [0xAA; 0x400]);
+
+    let sectors = FlashLayout::of(0x413).unwrap();
+    assert_eq!(sectors.pages(0x0800_4000, 0x1_0000).unwrap(), [1, 2, 3, 4]);
+    assert_eq!(sectors.pages(0x0802_0000, 1).unwrap(), [5]);
+    assert!(sectors.pages(0x0700_0000, 1).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_frames() {
+    assert_eq!(xor(&[0x08, 0x00, 0x00, 0x00]), 0x08);
+    assert_eq!(xor(&[0x01, 0x00, 0x05, 0x00, 0x07]), 0x03);
+}
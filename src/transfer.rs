@@ -0,0 +1,620 @@
+use crate::error::TransferError;
+use crate::esp::Esp;
+use crate::image::Image;
+use crate::kermit::Kermit;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::smp::{Smp, SmpCommand};
+use crate::stk500::{Stk500, Version};
+use crate::stm32::Stm32;
+use crate::transport::{is_timeout, Transport};
+use crate::upgrade::{Manifest, StepState, StepStatus, Upgrade, UpgradeError};
+use crate::verify::{Digest, Readback, Report};
+use crate::xmodem::XModemBuilder;
+use crate::ymodem::YModem;
+use crate::zmodem::ZModem;
+use serialport::{Parity, SerialPort};
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A file transfer picked from the Transfer menu
+pub enum TransferRequest {
+    XModemSend {
+        path: PathBuf,
+        options: XModemBuilder,
+    },
+    XModemReceive {
+        path: PathBuf,
+        options: XModemBuilder,
+    },
+    /// A firmware image flattened to a binary, sent over XModem
+    XModemSendImage {
+        path: PathBuf,
+        binary: Vec<u8>,
+        options: XModemBuilder,
+    },
+    /// A text file, such as Intel HEX, written to the console as it is
+    TextSend(PathBuf),
+    /// A binary, HEX or S-record image flashed through the STM32 system
+    /// bootloader. Binaries go to the address, images to their own.
+    Stm32Flash {
+        path: PathBuf,
+        address: u32,
+        mass_erase: bool,
+        start: bool,
+    },
+    /// A binary, HEX or S-record image flashed through the ESP ROM
+    /// bootloader, at a raised baud rate if one is given. Binaries go to
+    /// the offset, or the chip's application offset without one.
+    EspFlash {
+        path: PathBuf,
+        offset: Option<u32>,
+        baud: Option<u32>,
+        run: bool,
+    },
+    /// A binary or HEX image uploaded to an Arduino bootloader, at its own
+    /// baud rate if one is given
+    AvrUpload {
+        path: PathBuf,
+        version: Version,
+        baud: Option<u32>,
+        reset: bool,
+    },
+    /// An MCUmgr command to MCUboot serial recovery or an application
+    Smp(SmpCommand),
+    /// The steps of an upgrade manifest, XModem steps use the options
+    Upgrade {
+        manifest: Manifest,
+        options: XModemBuilder,
+    },
+    /// YModem uses the XModem options, with 1K blocks
+    YModemSend {
+        paths: Vec<PathBuf>,
+        options: XModemBuilder,
+    },
+    YModemReceive {
+        directory: PathBuf,
+        options: XModemBuilder,
+    },
+    ZModemSend {
+        paths: Vec<PathBuf>,
+        resume: bool,
+    },
+    ZModemReceive(PathBuf),
+    KermitSend(Vec<PathBuf>),
+    KermitReceive(PathBuf),
+}
+
+/// What a send puts on the device, to be checksummed once it succeeded
+enum Sent {
+    Files(Vec<PathBuf>),
+    Image(PathBuf, Digest),
+}
+
+/// Why a job failed
+#[derive(Debug)]
+pub enum JobError {
+    Transfer(TransferError),
+    Upgrade(UpgradeError),
+    /// The worker thread panicked, the port is still handed back
+    Panicked,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Transfer(err) => err.fmt(f),
+            JobError::Upgrade(err) => err.fmt(f),
+            JobError::Panicked => write!(f, "Transfer thread panicked"),
+        }
+    }
+}
+
+impl From<TransferError> for JobError {
+    fn from(err: TransferError) -> Self {
+        JobError::Transfer(err)
+    }
+}
+
+impl From<UpgradeError> for JobError {
+    fn from(err: UpgradeError) -> Self {
+        JobError::Upgrade(err)
+    }
+}
+
+/// Runs a transfer on the port, returns a message describing the result
+type Run = Box<dyn FnOnce(&mut dyn Transport) -> Result<String, JobError> + Send>;
+
+/// What the worker hands back, how the transfer ended and the
+/// verification of the files sent
+type Finished = (Result<String, JobError>, Option<Report>);
+
+/// A transfer running on a worker thread. The worker holds the port's lock
+/// until the transfer ends, then `poll` gives the port back. A panic
+/// poisons the lock but leaves the port in it.
+pub struct TransferJob {
+    title: &'static str,
+    progress: Arc<Mutex<Progress>>,
+    cancel: CancelToken,
+    started: Instant,
+    elapsed: Option<Duration>,
+    worker: Option<JoinHandle<Finished>>,
+    port: Option<Arc<Mutex<Box<dyn SerialPort>>>>,
+    /// Port settings to put back should the worker panic before it does
+    restore_parity: Option<Parity>,
+    restore_baud: Option<u32>,
+    outcome: Option<Result<String, JobError>>,
+    report: Option<Report>,
+    steps: Option<StepStatus>,
+}
+
+impl TransferJob {
+    /// Starts the transfer on a worker thread that takes the port. Files
+    /// sent are checksummed afterwards and checked with the readback, if any.
+    pub fn start(
+        request: TransferRequest,
+        mut port: Box<dyn SerialPort>,
+        readback: Option<Readback>,
+    ) -> Self {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let shared = progress.clone();
+        let observer = move |update: &Progress| {
+            if let Ok(mut progress) = shared.lock() {
+                *progress = update.clone();
+            }
+        };
+
+        let sent = match &request {
+            TransferRequest::XModemSend { path, .. } => Some(Sent::Files(vec![path.clone()])),
+            TransferRequest::XModemSendImage { path, binary, .. } => {
+                Digest::of_stream(&mut &binary[..])
+                    .ok()
+                    .map(|digest| Sent::Image(path.clone(), digest))
+            }
+            TransferRequest::YModemSend { paths, .. }
+            | TransferRequest::ZModemSend { paths, .. }
+            | TransferRequest::KermitSend(paths) => Some(Sent::Files(paths.clone())),
+            // A device sent text checksums what it made of it, not the text
+            _ => None,
+        };
+        let mut steps = None;
+        // The STM32 bootloader needs even parity, the port gets its own back after
+        let mut restore_parity = None;
+        // The ESP and AVR flashers change the baud rate, the port gets its own back after
+        let mut restore_baud = None;
+        let (title, cancel, run): (&'static str, CancelToken, Run) = match request {
+            TransferRequest::XModemSend { path, options } => {
+                let mut xmodem = options.build();
+                xmodem.on_progress(observer);
+                let cancel = xmodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let stream = File::open(&path).map_err(TransferError::StreamRead)?;
+                    xmodem.set_total(stream.metadata().ok().map(|metadata| metadata.len()));
+                    xmodem.send(device, Box::new(stream))?;
+                    Ok(format!("File Send success: {}", path.display()))
+                });
+                ("xModem Send", cancel, run)
+            }
+            TransferRequest::XModemReceive { path, options } => {
+                let mut xmodem = options.build();
+                xmodem.on_progress(observer);
+                let cancel = xmodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let stream = File::create(&path).map_err(TransferError::StreamWrite)?;
+                    let bytes = xmodem.receive(device, Box::new(stream))?;
+                    Ok(format!("File Receive success, Bytes: {bytes} read."))
+                });
+                ("xModem Receive", cancel, run)
+            }
+            TransferRequest::XModemSendImage {
+                path,
+                binary,
+                options,
+            } => {
+                let mut xmodem = options.build();
+                xmodem.on_progress(observer);
+                xmodem.set_total(Some(binary.len() as u64));
+                let cancel = xmodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let size = binary.len();
+                    xmodem.send(device, Box::new(std::io::Cursor::new(binary)))?;
+                    Ok(format!(
+                        "Image Send success: {}, {size} bytes",
+                        path.display()
+                    ))
+                });
+                ("xModem Send Image", cancel, run)
+            }
+            TransferRequest::TextSend(path) => {
+                let mut reporter = Reporter::default();
+                reporter.set_observer(Box::new(observer));
+                let cancel = reporter.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let text = std::fs::read_to_string(&path).map_err(TransferError::StreamRead)?;
+                    let total = text.lines().map(|line| line.len() + 2).sum::<usize>();
+                    reporter.set_total(Some(total as u64));
+                    reporter.start();
+                    let result = send_text(device, &text, &mut reporter);
+                    reporter.finish(result)?;
+                    Ok(format!("Text Send success: {}", path.display()))
+                });
+                ("Text Send", cancel, run)
+            }
+            TransferRequest::Stm32Flash {
+                path,
+                address,
+                mass_erase,
+                start,
+            } => {
+                restore_parity = port.parity().ok();
+                if let Err(err) = port.set_parity(Parity::Even) {
+                    println!("Can't set even parity: {err}");
+                }
+                let mut stm32 = Stm32::new();
+                stm32.on_progress(observer);
+                let cancel = stm32.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let (address, binary) = load_binary(&path, address)?;
+                    stm32.flash(device, address, &binary, mass_erase, start)?;
+                    Ok(format!(
+                        "Flashed {} bytes at {address:#010x}: {}",
+                        binary.len(),
+                        path.display()
+                    ))
+                });
+                ("STM32 Flash", cancel, run)
+            }
+            TransferRequest::EspFlash {
+                path,
+                offset,
+                baud,
+                run,
+            } => {
+                if baud.is_some() {
+                    restore_baud = port.baud_rate().ok();
+                }
+                let mut esp = Esp::new();
+                esp.on_progress(observer);
+                let cancel = esp.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let (address, binary) = load_binary(&path, offset.unwrap_or(0))?;
+                    let offset = if Image::is_image(&path) {
+                        Some(address)
+                    } else {
+                        offset
+                    };
+                    let offset = esp.flash(device, offset, &binary, baud, run)?;
+                    Ok(format!(
+                        "Flashed {} bytes at {offset:#x}: {}",
+                        binary.len(),
+                        path.display()
+                    ))
+                });
+                ("ESP Flash", cancel, run)
+            }
+            TransferRequest::AvrUpload {
+                path,
+                version,
+                baud,
+                reset,
+            } => {
+                if let Some(baud) = baud {
+                    restore_baud = port.baud_rate().ok();
+                    if let Err(err) = port.set_baud_rate(baud) {
+                        println!("Can't set the bootloader baud rate: {err}");
+                    }
+                }
+                let mut stk500 = Stk500::new(version);
+                stk500.on_progress(observer);
+                let cancel = stk500.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let (address, binary) = load_binary(&path, 0)?;
+                    stk500.upload(device, address, &binary, reset)?;
+                    Ok(format!(
+                        "Uploaded {} bytes at {address:#06x}: {}",
+                        binary.len(),
+                        path.display()
+                    ))
+                });
+                ("AVR Upload", cancel, run)
+            }
+            TransferRequest::Smp(command) => {
+                let title = command.title();
+                let mut smp = Smp::new();
+                smp.on_progress(observer);
+                let cancel = smp.cancel_token();
+                let run: Run = Box::new(move |device| Ok(smp.run(device, command)?));
+                (title, cancel, run)
+            }
+            TransferRequest::Upgrade { manifest, options } => {
+                let mut upgrade = Upgrade::new(manifest, options);
+                steps = Some(upgrade.status());
+                let cancel = upgrade.cancel_token();
+                let run: Run = Box::new(move |device| Ok(upgrade.run(device, observer)?));
+                ("Firmware Upgrade", cancel, run)
+            }
+            TransferRequest::YModemSend { paths, options } => {
+                let mut ymodem = YModem::from_builder(options);
+                ymodem.on_progress(observer);
+                let cancel = ymodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    ymodem.send(device, &paths)?;
+                    Ok(format!("Batch Send success, {} files", paths.len()))
+                });
+                ("yModem Send", cancel, run)
+            }
+            TransferRequest::YModemReceive { directory, options } => {
+                let mut ymodem = YModem::from_builder(options);
+                ymodem.on_progress(observer);
+                let cancel = ymodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let files = ymodem.receive(device, &directory)?;
+                    Ok(format!("Batch Receive success, Files: {files:?}"))
+                });
+                ("yModem Receive", cancel, run)
+            }
+            TransferRequest::ZModemSend { paths, resume } => {
+                let mut zmodem = ZModem::new();
+                zmodem.on_progress(observer);
+                zmodem.set_resume(resume);
+                let cancel = zmodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    zmodem.send(device, &paths)?;
+                    Ok(format!("Batch Send success, {} files", paths.len()))
+                });
+                ("zModem Send", cancel, run)
+            }
+            TransferRequest::ZModemReceive(directory) => {
+                let mut zmodem = ZModem::new();
+                zmodem.on_progress(observer);
+                let cancel = zmodem.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let files = zmodem.receive(device, &directory)?;
+                    Ok(format!("Batch Receive success, Files: {files:?}"))
+                });
+                ("zModem Receive", cancel, run)
+            }
+            TransferRequest::KermitSend(paths) => {
+                let mut kermit = Kermit::new();
+                kermit.on_progress(observer);
+                let cancel = kermit.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    kermit.send(device, &paths)?;
+                    Ok(format!("Batch Send success, {} files", paths.len()))
+                });
+                ("Kermit Send", cancel, run)
+            }
+            TransferRequest::KermitReceive(directory) => {
+                let mut kermit = Kermit::new();
+                kermit.on_progress(observer);
+                let cancel = kermit.cancel_token();
+                let run: Run = Box::new(move |device| {
+                    let files = kermit.receive(device, &directory)?;
+                    Ok(format!("Batch Receive success, Files: {files:?}"))
+                });
+                ("Kermit Receive", cancel, run)
+            }
+        };
+
+        let port = Arc::new(Mutex::new(port));
+        let shared = port.clone();
+        let worker = std::thread::spawn(move || {
+            let mut port = shared.lock().unwrap_or_else(PoisonError::into_inner);
+            let result = run(&mut *port);
+            if let Some(parity) = restore_parity {
+                let _ = port.set_parity(parity);
+            }
+            if let Some(baud) = restore_baud {
+                let _ = port.set_baud_rate(baud);
+            }
+            let files = match (&result, sent) {
+                (Ok(_), Some(Sent::Files(paths))) => paths
+                    .into_iter()
+                    .map(|path| Digest::of_file(&path).map(|digest| (path, digest)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| println!("Verification failed: {err}"))
+                    .ok(),
+                (Ok(_), Some(Sent::Image(path, digest))) => Some(vec![(path, digest)]),
+                _ => None,
+            };
+            let report = files.map(|files| Report::build(files, readback.as_ref(), &mut *port));
+            (result, report)
+        });
+        Self {
+            title,
+            progress,
+            cancel,
+            started: Instant::now(),
+            elapsed: None,
+            worker: Some(worker),
+            port: Some(port),
+            restore_parity,
+            restore_baud,
+            outcome: None,
+            report: None,
+            steps,
+        }
+    }
+
+    /// Checks if the worker is done. Returns the port once when it is,
+    /// even if the worker panicked.
+    pub fn poll(&mut self) -> Option<Box<dyn SerialPort>> {
+        if !self.worker.as_ref()?.is_finished() {
+            return None;
+        }
+        let worker = self.worker.take()?;
+        self.elapsed = Some(self.started.elapsed());
+        let panicked = match worker.join() {
+            Ok((result, report)) => {
+                match &result {
+                    Ok(message) => println!("{message}"),
+                    Err(err) => println!("Error: {err}"),
+                }
+                self.outcome = Some(result);
+                self.report = report;
+                false
+            }
+            Err(_) => {
+                println!("Transfer thread panicked");
+                self.outcome = Some(Err(JobError::Panicked));
+                true
+            }
+        };
+        // The worker has ended, so its handle on the port is gone
+        let port = Arc::try_unwrap(self.port.take()?).ok()?;
+        let mut port = port.into_inner().unwrap_or_else(PoisonError::into_inner);
+        if panicked {
+            if let Some(parity) = self.restore_parity {
+                let _ = port.set_parity(parity);
+            }
+            if let Some(baud) = self.restore_baud {
+                let _ = port.set_baud_rate(baud);
+            }
+        }
+        Some(port)
+    }
+
+    pub fn title(&self) -> &'static str {
+        self.title
+    }
+
+    /// Latest progress reported by the worker
+    pub fn progress(&self) -> Progress {
+        match self.progress.lock() {
+            Ok(progress) => progress.clone(),
+            Err(_) => Progress::default(),
+        }
+    }
+
+    /// Time the transfer has been running, or ran for once it finished
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.unwrap_or_else(|| self.started.elapsed())
+    }
+
+    /// Average throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed().as_secs_f64();
+        if seconds > 0.0 {
+            self.progress().bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// How the transfer ended, None while it is still running
+    pub fn outcome(&self) -> Option<&Result<String, JobError>> {
+        self.outcome.as_ref()
+    }
+
+    /// Checksums of the files sent and the readback verdict, once the send succeeded
+    pub fn report(&self) -> Option<&Report> {
+        self.report.as_ref()
+    }
+
+    /// Description and state of each step, for upgrade workflows
+    pub fn steps(&self) -> Option<Vec<(String, StepState)>> {
+        let steps = self.steps.as_ref()?.lock().ok()?;
+        Some(steps.clone())
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+/// Reads a binary to go at the address, or a HEX or S-record image
+/// flattened to go at its own
+fn load_binary(path: &Path, address: u32) -> Result<(u32, Vec<u8>), TransferError> {
+    if Image::is_image(path) {
+        Image::load(path)
+            .and_then(|image| image.flatten(0xFF))
+            .map_err(|err| {
+                TransferError::StreamRead(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    err.to_string(),
+                ))
+            })
+    } else {
+        let binary = std::fs::read(path).map_err(TransferError::StreamRead)?;
+        Ok((address, binary))
+    }
+}
+
+/// Longest a line waits for the device to echo it before the next one goes
+const LINE_PACE: Duration = Duration::from_millis(100);
+
+/// Writes text to the device a line at a time, for devices that take
+/// HEX or S-records typed at their console. Each line waits for the echo
+/// of its end or a prompt, so a console loader isn't overrun.
+fn send_text(
+    device: &mut dyn Transport,
+    text: &str,
+    reporter: &mut Reporter,
+) -> Result<(), TransferError> {
+    reporter.set_state(TransferState::Transferring);
+    for line in text.lines() {
+        if reporter.is_cancelled() {
+            return Err(TransferError::Aborted);
+        }
+        device.write_all(line.as_bytes())?;
+        device.write_all(b"\r\n")?;
+        device.flush()?;
+        reporter.count_packet(line.len() + 2);
+        wait_for_echo(device, LINE_PACE)?;
+    }
+    Ok(())
+}
+
+/// Reads what the device answers to a line until it ends a line or shows
+/// a prompt, or the time is up
+fn wait_for_echo(device: &mut dyn Transport, timeout: Duration) -> Result<(), TransferError> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 256];
+    while Instant::now() < deadline {
+        match device.read(&mut buffer) {
+            Ok(len) if buffer[..len].iter().any(|byte| b"\n>".contains(byte)) => break,
+            Ok(_) => (),
+            Err(err) if is_timeout(&err) => (),
+            Err(err) => return Err(TransferError::Io(err)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_send_text_waits_for_echo() {
+    use crate::transport::loopback_pair;
+    use std::io::{Read, Write};
+    let (mut device, mut console) = loopback_pair();
+    let handle = std::thread::spawn(move || {
+        // A loader that takes a while over each line, then echoes it
+        let mut received = vec![];
+        let mut buffer = [0; 64];
+        while received.len() < 29 {
+            if let Ok(len) = console.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..len]);
+                if received.ends_with(b"\r\n") {
+                    std::thread::sleep(Duration::from_millis(20));
+                    // Nothing of the next line came before the echo
+                    assert_eq!(console.bytes_to_read().unwrap(), 0);
+                    console.write_all(b"\r\n").unwrap();
+                }
+            }
+        }
+        received
+    });
+    let mut reporter = Reporter::default();
+    send_text(&mut device, ":0000\n:0001FF\n:00000001FF", &mut reporter).unwrap();
+    assert_eq!(
+        handle.join().unwrap(),
+        b":0000\r\n:0001FF\r\n:00000001FF\r\n"
+    );
+}
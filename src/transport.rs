@@ -0,0 +1,245 @@
+use serialport::SerialPort;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// A byte stream the transfer protocols run over. A read waits a bounded
+/// time for data, then fails with an error `is_timeout` accepts.
+pub trait Transport: Read + Write {
+    /// Number of received bytes that can be read without waiting
+    fn bytes_to_read(&self) -> io::Result<usize>;
+
+    /// Discards received bytes that haven't been read
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// Changes the line speed, only serial ports have one
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Drives the DTR and RTS lines, which boards wire to reset and boot pins
+    fn set_control_lines(&mut self, _dtr: bool, _rts: bool) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// True if the error is a read running out of time rather than a failure.
+/// Serial ports report `TimedOut`, sockets report `WouldBlock` on Unix.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+/// How long the line must stay quiet before a drain ends
+const DRAIN_QUIET: Duration = Duration::from_millis(100);
+
+/// Longest a drain waits for a peer that keeps sending
+const DRAIN_LIMIT: Duration = Duration::from_secs(2);
+
+/// Reads and drops whatever the peer still sends until the line has been
+/// quiet for a moment, used after a transfer was cancelled.
+pub fn drain(device: &mut dyn Transport) {
+    let deadline = Instant::now() + DRAIN_LIMIT;
+    let mut quiet_since = Instant::now();
+    let mut buf = [0; 256];
+    while Instant::now() < deadline {
+        match device.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => quiet_since = Instant::now(),
+            Err(err) if is_timeout(&err) => {
+                if quiet_since.elapsed() >= DRAIN_QUIET {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    let _ = device.clear_input();
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn bytes_to_read(&self) -> io::Result<usize> {
+        match SerialPort::bytes_to_read(self.as_ref()) {
+            Ok(count) => Ok(count as usize),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.clear(serialport::ClearBuffer::Input)
+            .map_err(io::Error::from)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        SerialPort::set_baud_rate(self.as_mut(), baud_rate).map_err(io::Error::from)
+    }
+
+    fn set_control_lines(&mut self, dtr: bool, rts: bool) -> io::Result<()> {
+        self.write_data_terminal_ready(dtr)?;
+        self.write_request_to_send(rts)?;
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn bytes_to_read(&self) -> io::Result<usize> {
+        self.set_nonblocking(true)?;
+        let mut buf = [0; 4096];
+        let count = match self.peek(&mut buf) {
+            Ok(count) => Ok(count),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(err),
+        };
+        self.set_nonblocking(false)?;
+        count
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        while Transport::bytes_to_read(self)? > 0 {
+            let _ = self.read(&mut buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// A connected pair of sockets on the loopback interface
+#[cfg(test)]
+pub fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    client.set_nodelay(true).unwrap();
+    server.set_nodelay(true).unwrap();
+    (client, server)
+}
+
+/// Bytes travelling one way through a loopback pair
+#[cfg(test)]
+type Pipe = std::sync::Arc<(
+    std::sync::Mutex<std::collections::VecDeque<u8>>,
+    std::sync::Condvar,
+)>;
+
+/// One end of an in-memory connection made by `loopback_pair`. A read waits
+/// up to the timeout for data, like a serial port does.
+#[cfg(test)]
+pub struct Loopback {
+    rx: Pipe,
+    tx: Pipe,
+    timeout: std::time::Duration,
+}
+
+/// A connected pair of in-memory transports
+#[cfg(test)]
+pub fn loopback_pair() -> (Loopback, Loopback) {
+    let a: Pipe = Default::default();
+    let b: Pipe = Default::default();
+    let timeout = std::time::Duration::from_millis(10);
+    (
+        Loopback {
+            rx: a.clone(),
+            tx: b.clone(),
+            timeout,
+        },
+        Loopback {
+            rx: b,
+            tx: a,
+            timeout,
+        },
+    )
+}
+
+#[cfg(test)]
+impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (queue, ready) = &*self.rx;
+        let queue = queue.lock().unwrap();
+        let (mut queue, _) = ready
+            .wait_timeout_while(queue, self.timeout, |queue| queue.is_empty())
+            .unwrap();
+        if queue.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let len = buf.len().min(queue.len());
+        for (byte, value) in buf.iter_mut().zip(queue.drain(..len)) {
+            *byte = value;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (queue, ready) = &*self.tx;
+        queue.lock().unwrap().extend(buf);
+        ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Transport for Loopback {
+    fn bytes_to_read(&self) -> io::Result<usize> {
+        Ok(self.rx.0.lock().unwrap().len())
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.rx.0.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Both ends always run at the same speed
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A stream a test can read back after a receiver is done with it
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Shared {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_tcp_transport() {
+    use std::time::Duration;
+    let (mut a, mut b) = tcp_pair();
+    b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+    let mut buf = [0; 4];
+    let err = b.read(&mut buf).unwrap_err();
+    assert!(is_timeout(&err));
+
+    a.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(Transport::bytes_to_read(&b).unwrap(), 4);
+    b.clear_input().unwrap();
+    assert_eq!(Transport::bytes_to_read(&b).unwrap(), 0);
+}
@@ -0,0 +1,552 @@
+use crate::error::TransferError;
+use crate::image::Image;
+use crate::progress::{CancelToken, Progress};
+use crate::transport::{is_timeout, Transport};
+use crate::xmodem::{BlockLength, XModemBuilder};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Seconds a wait step waits when neither it nor the manifest says
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Received text kept to show what arrived when a wait times out
+const TAIL_LENGTH: usize = 80;
+
+/// An upgrade workflow read from a TOML or YAML file
+#[derive(Clone, Debug, Deserialize)]
+pub struct Manifest {
+    pub name: Option<String>,
+    /// Seconds wait steps wait unless they set their own timeout
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+    /// Directory files named by the steps are relative to
+    #[serde(skip)]
+    pub base: PathBuf,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+fn default_fill() -> u8 {
+    0xFF
+}
+
+/// One step of an upgrade
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Step {
+    /// Sends a line of text, followed by CR LF
+    Send { text: String },
+    /// Waits until the device sends the pattern
+    Wait {
+        pattern: String,
+        timeout: Option<u64>,
+    },
+    /// Switches the port to another baud rate
+    Baud { rate: u32 },
+    /// Pauses, for devices that need time to reboot or erase
+    Delay { millis: u64 },
+    /// Sends a file over XModem, HEX and S-record images are flattened first
+    #[serde(rename = "xmodem")]
+    XModem {
+        file: PathBuf,
+        #[serde(default)]
+        one_k: bool,
+        #[serde(default = "default_fill")]
+        fill: u8,
+    },
+    /// Sends a command and checks its reply holds the expected version
+    Version {
+        command: String,
+        expect: String,
+        timeout: Option<u64>,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Send { text } => write!(f, "Send {text:?}"),
+            Step::Wait { pattern, .. } => write!(f, "Wait for {pattern:?}"),
+            Step::Baud { rate } => write!(f, "Switch to {rate} baud"),
+            Step::Delay { millis } => write!(f, "Delay {millis} ms"),
+            Step::XModem { file, .. } => write!(f, "xModem send {}", file.display()),
+            Step::Version {
+                command, expect, ..
+            } => write!(f, "Check {command:?} reports {expect:?}"),
+        }
+    }
+}
+
+/// Why a manifest couldn't be loaded
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    /// The manifest has no steps
+    Empty,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io(err) => write!(f, "IO error: {err}"),
+            ManifestError::Toml(err) => write!(f, "Invalid TOML: {err}"),
+            ManifestError::Yaml(err) => write!(f, "Invalid YAML: {err}"),
+            ManifestError::Empty => write!(f, "The manifest has no steps"),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// Why an upgrade stopped, steps count from 1
+#[derive(Debug)]
+pub enum UpgradeError {
+    /// The transfer of a step, or talking to the device, failed
+    Transfer { step: usize, error: TransferError },
+    /// A step didn't get what it waited for, or couldn't load its file
+    Step { step: usize, message: String },
+}
+
+impl UpgradeError {
+    /// The same error, at the step
+    fn at(self, step: usize) -> Self {
+        match self {
+            UpgradeError::Transfer { error, .. } => UpgradeError::Transfer { step, error },
+            UpgradeError::Step { message, .. } => UpgradeError::Step { step, message },
+        }
+    }
+
+    /// What went wrong, without the step
+    pub fn message(&self) -> String {
+        match self {
+            UpgradeError::Transfer { error, .. } => error.to_string(),
+            UpgradeError::Step { message, .. } => message.clone(),
+        }
+    }
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpgradeError::Transfer { step, error } => write!(f, "Step {step} failed: {error}"),
+            UpgradeError::Step { step, message } => write!(f, "Step {step} failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpgradeError::Transfer { error, .. } => Some(error),
+            UpgradeError::Step { .. } => None,
+        }
+    }
+}
+
+impl From<TransferError> for UpgradeError {
+    fn from(error: TransferError) -> Self {
+        UpgradeError::Transfer { step: 0, error }
+    }
+}
+
+impl From<std::io::Error> for UpgradeError {
+    fn from(err: std::io::Error) -> Self {
+        TransferError::Io(err).into()
+    }
+}
+
+impl Manifest {
+    /// Loads a manifest, YAML if the extension says so and TOML otherwise
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        let text = std::fs::read_to_string(path).map_err(ManifestError::Io)?;
+        let yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml" | "yml")
+        );
+        let mut manifest = if yaml {
+            Self::from_yaml(&text)?
+        } else {
+            Self::from_toml(&text)?
+        };
+        manifest.base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(manifest)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ManifestError> {
+        let manifest: Self = toml::from_str(text).map_err(ManifestError::Toml)?;
+        manifest.check()
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, ManifestError> {
+        let manifest: Self = serde_yaml::from_str(text).map_err(ManifestError::Yaml)?;
+        manifest.check()
+    }
+
+    fn check(self) -> Result<Self, ManifestError> {
+        if self.steps.is_empty() {
+            return Err(ManifestError::Empty);
+        }
+        Ok(self)
+    }
+
+    pub fn title(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| "Firmware Upgrade".to_owned())
+    }
+}
+
+/// Where a step of a running upgrade is at
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StepState {
+    Pending,
+    Running,
+    Done(String),
+    Failed(String),
+}
+
+/// Description and state of every step, shared with the GUI
+pub type StepStatus = Arc<Mutex<Vec<(String, StepState)>>>;
+
+/// Runs the steps of a manifest on a port
+pub struct Upgrade {
+    manifest: Manifest,
+    options: XModemBuilder,
+    status: StepStatus,
+    cancel: CancelToken,
+}
+
+impl Upgrade {
+    /// XModem steps use the options, with their own block length
+    pub fn new(manifest: Manifest, options: XModemBuilder) -> Self {
+        let status = manifest
+            .steps
+            .iter()
+            .map(|step| (step.to_string(), StepState::Pending))
+            .collect();
+        Self {
+            manifest,
+            options,
+            status: Arc::new(Mutex::new(status)),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Description and state of every step, updated as the upgrade runs
+    pub fn status(&self) -> StepStatus {
+        self.status.clone()
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Runs the steps in order, stopping at the first that fails.
+    /// XModem steps report their progress to the observer.
+    pub fn run(
+        &mut self,
+        device: &mut dyn Transport,
+        observer: impl FnMut(&Progress) + Clone + Send + 'static,
+    ) -> Result<String, UpgradeError> {
+        let steps = self.manifest.steps.clone();
+        for (index, step) in steps.iter().enumerate() {
+            println!("Step {}: {step}", index + 1);
+            self.set_state(index, StepState::Running);
+            let result = if self.cancel.is_cancelled() {
+                Err(TransferError::Aborted.into())
+            } else {
+                self.run_step(device, step, observer.clone())
+            };
+            match result {
+                Ok(detail) => self.set_state(index, StepState::Done(detail)),
+                Err(err) => {
+                    let err = err.at(index + 1);
+                    println!("Error: {err}");
+                    self.set_state(index, StepState::Failed(err.message()));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(format!(
+            "{} complete, {} steps",
+            self.manifest.title(),
+            steps.len()
+        ))
+    }
+
+    fn set_state(&self, index: usize, state: StepState) {
+        if let Ok(mut status) = self.status.lock() {
+            status[index].1 = state;
+        }
+    }
+
+    fn run_step(
+        &mut self,
+        device: &mut dyn Transport,
+        step: &Step,
+        observer: impl FnMut(&Progress) + Send + 'static,
+    ) -> Result<String, UpgradeError> {
+        match step {
+            Step::Send { text } => {
+                send_line(device, text)?;
+                Ok("Sent".to_owned())
+            }
+            Step::Wait { pattern, timeout } => {
+                self.wait_for(device, pattern, self.timeout(*timeout))?;
+                Ok(format!("Received {pattern:?}"))
+            }
+            Step::Baud { rate } => {
+                device.flush()?;
+                device.set_baud_rate(*rate)?;
+                device.clear_input()?;
+                Ok(format!("{rate} baud"))
+            }
+            Step::Delay { millis } => {
+                let deadline = Instant::now() + Duration::from_millis(*millis);
+                while Instant::now() < deadline {
+                    if self.cancel.is_cancelled() {
+                        return Err(TransferError::Aborted.into());
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Ok("Done".to_owned())
+            }
+            Step::XModem { file, one_k, fill } => {
+                let path = self.manifest.base.join(file);
+                let binary = if Image::is_image(&path) {
+                    let image = Image::load(&path).map_err(|err| failed(err.to_string()))?;
+                    let (_, binary) = image
+                        .flatten(*fill)
+                        .map_err(|err| failed(err.to_string()))?;
+                    binary
+                } else {
+                    std::fs::read(&path).map_err(TransferError::StreamRead)?
+                };
+                let mut options = self.options.clone();
+                if *one_k {
+                    options = options.block_length(BlockLength::OneK);
+                }
+                let mut xmodem = options.build();
+                xmodem.on_progress(observer);
+                xmodem.set_cancel_token(self.cancel.clone());
+                xmodem.set_total(Some(binary.len() as u64));
+                let size = binary.len();
+                xmodem.send(device, Box::new(std::io::Cursor::new(binary)))?;
+                Ok(format!("Sent {size} bytes"))
+            }
+            Step::Version {
+                command,
+                expect,
+                timeout,
+            } => {
+                device.clear_input()?;
+                send_line(device, command)?;
+                self.wait_for(device, expect, self.timeout(*timeout))?;
+                Ok(format!("Version {expect}"))
+            }
+        }
+    }
+
+    fn timeout(&self, timeout: Option<u64>) -> Duration {
+        Duration::from_secs(timeout.unwrap_or(self.manifest.timeout))
+    }
+
+    /// Reads until the device sends the pattern
+    fn wait_for(
+        &mut self,
+        device: &mut dyn Transport,
+        pattern: &str,
+        timeout: Duration,
+    ) -> Result<(), UpgradeError> {
+        let deadline = Instant::now() + timeout;
+        let mut received = vec![];
+        let mut buf = [0; 256];
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(TransferError::Aborted.into());
+            }
+            match device.read(&mut buf) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(len) => {
+                    received.extend_from_slice(&buf[..len]);
+                    if String::from_utf8_lossy(&received).contains(pattern) {
+                        return Ok(());
+                    }
+                }
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(err.into()),
+            }
+            if Instant::now() >= deadline {
+                let text = String::from_utf8_lossy(&received);
+                let tail: String = text
+                    .chars()
+                    .skip(text.chars().count().saturating_sub(TAIL_LENGTH))
+                    .collect();
+                return Err(failed(format!(
+                    "no {pattern:?} within {} s, received {tail:?}",
+                    timeout.as_secs()
+                )));
+            }
+        }
+    }
+}
+
+/// A step failure, `Upgrade::run` fills in the step number
+fn failed(message: String) -> UpgradeError {
+    UpgradeError::Step { step: 0, message }
+}
+
+fn send_line(device: &mut dyn Transport, text: &str) -> Result<(), TransferError> {
+    device.write_all(text.as_bytes())?;
+    device.write_all(b"\r\n")?;
+    device.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_manifests() {
+    let toml = r#"
+name = "651R2/A"
+timeout = 5
+
+[[step]]
+action = "send"
+text = "upgrade"
+
+[[step]]
+action = "wait"
+pattern = "Ready"
+timeout = 30
+
+[[step]]
+action = "baud"
+rate = 460800
+
+[[step]]
+action = "xmodem"
+file = "firmware.hex"
+one_k = true
+
+[[step]]
+action = "version"
+command = "version"
+expect = "1.2.3"
+"#;
+    let manifest = Manifest::from_toml(toml).unwrap();
+    assert_eq!(manifest.steps.len(), 5);
+    assert_eq!(manifest.timeout, 5);
+    assert!(matches!(
+        manifest.steps[3],
+        Step::XModem {
+            one_k: true,
+            fill: 0xFF,
+            ..
+        }
+    ));
+    let yaml = "
+step:
+  - action: send
+    text: upgrade
+  - action: wait
+    pattern: OK
+";
+    let manifest = Manifest::from_yaml(yaml).unwrap();
+    assert_eq!(manifest.timeout, DEFAULT_TIMEOUT);
+    assert_eq!(manifest.steps[1].to_string(), "Wait for \"OK\"");
+    assert!(matches!(
+        Manifest::from_toml("step = []"),
+        Err(ManifestError::Empty)
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_upgrade() {
+    use crate::transport::{loopback_pair, Shared};
+    use crate::xmodem::XModem;
+    use std::io::{Read, Write};
+
+    fn read_line(device: &mut crate::transport::Loopback) -> String {
+        let mut line = vec![];
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\r\n") {
+            if let Ok(1) = device.read(&mut byte) {
+                line.push(byte[0]);
+            }
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    let directory = std::env::temp_dir().join(format!("upgrade-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let image: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    std::fs::write(directory.join("firmware.bin"), &image).unwrap();
+    let mut manifest = Manifest::from_toml(
+        r#"
+[[step]]
+action = "send"
+text = "upgrade"
+[[step]]
+action = "wait"
+pattern = "Ready"
+[[step]]
+action = "baud"
+rate = 460800
+[[step]]
+action = "xmodem"
+file = "firmware.bin"
+[[step]]
+action = "wait"
+pattern = "OK"
+[[step]]
+action = "version"
+command = "version"
+expect = "1.2.3"
+"#,
+    )
+    .unwrap();
+    manifest.base = directory.clone();
+
+    let (mut device, mut peer) = loopback_pair();
+    let output = Shared::default();
+    let written = output.clone();
+    let handle = std::thread::spawn(move || {
+        assert_eq!(read_line(&mut peer), "upgrade\r\n");
+        peer.write_all(b"Erasing...\r\nReady\r\n").unwrap();
+        // The baud step drops the first poll, so poll again soon
+        XModem::builder()
+            .crc_mode(true)
+            .sync_timeout(Duration::from_millis(200))
+            .build()
+            .receive(&mut peer, Box::new(written))
+            .unwrap();
+        peer.write_all(b"Flash OK\r\n").unwrap();
+        assert_eq!(read_line(&mut peer), "version\r\n");
+        peer.write_all(b"version\r\nfirmware 1.2.3\r\n> ").unwrap();
+    });
+
+    let options = XModem::builder().packet_timeout(Duration::from_millis(500));
+    let mut upgrade = Upgrade::new(manifest, options);
+    let status = upgrade.status();
+    let result = upgrade.run(&mut device, |_: &Progress| ());
+    handle.join().unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert_eq!(result.unwrap(), "Firmware Upgrade complete, 6 steps");
+    assert_eq!(output.bytes(), image);
+    let status = status.lock().unwrap();
+    assert!(status
+        .iter()
+        .all(|(_, state)| matches!(state, StepState::Done(_))));
+    assert_eq!(status[3].1, StepState::Done("Sent 300 bytes".to_owned()));
+}
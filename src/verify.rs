@@ -0,0 +1,293 @@
+use crate::error::TransferError;
+use crate::transport::{is_timeout, Transport};
+use sha2::{Digest as _, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long the device must stay quiet before its readback reply is complete
+const REPLY_QUIET: Duration = Duration::from_millis(500);
+
+/// Checksums of an image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digest {
+    pub size: u64,
+    pub crc32: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Digest {
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        Self::of_stream(&mut File::open(path)?)
+    }
+
+    pub fn of_stream(stream: &mut dyn Read) -> std::io::Result<Self> {
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut sha256 = Sha256::new();
+        let mut size = 0;
+        let mut buf = vec![0; 8192];
+        loop {
+            let len = stream.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            crc32.update(&buf[..len]);
+            sha256.update(&buf[..len]);
+            size += len as u64;
+        }
+        Ok(Self {
+            size,
+            crc32: crc32.finalize(),
+            sha256: sha256.finalize().into(),
+        })
+    }
+
+    pub fn crc32_hex(&self) -> String {
+        format!("{:08x}", self.crc32)
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        self.sha256
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// A command asking the device for the checksum of what it received.
+/// `{size}` in the command is replaced by the size of the image in bytes.
+#[derive(Clone, Debug)]
+pub struct Readback {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl Readback {
+    /// Sends the command and collects the device's reply
+    pub fn run(
+        &self,
+        device: &mut dyn Transport,
+        digest: &Digest,
+    ) -> Result<String, TransferError> {
+        let command = self.command.replace("{size}", &digest.size.to_string());
+        println!("Readback command: {command}");
+        device.clear_input()?;
+        device.write_all(command.as_bytes())?;
+        device.write_all(b"\r\n")?;
+        device.flush()?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut reply = vec![];
+        let mut last_byte = Instant::now();
+        let mut buf = [0; 256];
+        while Instant::now() < deadline {
+            match device.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    reply.extend_from_slice(&buf[..len]);
+                    last_byte = Instant::now();
+                }
+                Err(err) if is_timeout(&err) => {
+                    if !reply.is_empty() && last_byte.elapsed() >= REPLY_QUIET {
+                        break;
+                    }
+                }
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+        }
+        let reply = String::from_utf8_lossy(&reply).into_owned();
+        println!("Readback reply: {reply:?}");
+        // The echo of the command, with the size in it, isn't the answer
+        let reply = match reply.split_once('\n') {
+            Some((echo, rest)) if echo.trim().ends_with(command.trim()) => rest.to_owned(),
+            _ => reply,
+        };
+        Ok(reply)
+    }
+}
+
+/// A checksum found in the device's reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reported {
+    Crc32(u32),
+    Sha256(String),
+}
+
+impl Reported {
+    /// Finds the checksum in a reply. The last one wins, as the device
+    /// echoes the command, which may hold hex numbers, before it answers.
+    /// Eight digits without a `0x` are only taken for a CRC on a line that
+    /// says so, or when a letter shows they aren't a decimal count.
+    pub fn parse(reply: &str) -> Option<Self> {
+        reply.lines().rev().find_map(|line| {
+            let lower = line.to_ascii_lowercase();
+            let labelled = lower.contains("crc") || lower.contains("checksum");
+            line.split(|c: char| !c.is_ascii_alphanumeric())
+                .rev()
+                .find_map(|token| {
+                    let (hex, prefixed) = match token
+                        .strip_prefix("0x")
+                        .or_else(|| token.strip_prefix("0X"))
+                    {
+                        Some(hex) => (hex, true),
+                        None => (token, false),
+                    };
+                    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return None;
+                    }
+                    let decimal = hex.chars().all(|c| c.is_ascii_digit());
+                    match hex.len() {
+                        64 => Some(Reported::Sha256(hex.to_ascii_lowercase())),
+                        8 if prefixed || labelled || !decimal => {
+                            u32::from_str_radix(hex, 16).ok().map(Reported::Crc32)
+                        }
+                        1..=7 if prefixed => u32::from_str_radix(hex, 16).ok().map(Reported::Crc32),
+                        _ => None,
+                    }
+                })
+        })
+    }
+
+    pub fn matches(&self, digest: &Digest) -> bool {
+        match self {
+            Reported::Crc32(crc32) => *crc32 == digest.crc32,
+            Reported::Sha256(sha256) => *sha256 == digest.sha256_hex(),
+        }
+    }
+}
+
+impl std::fmt::Display for Reported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reported::Crc32(crc32) => write!(f, "CRC32 {crc32:08x}"),
+            Reported::Sha256(sha256) => write!(f, "SHA-256 {sha256}"),
+        }
+    }
+}
+
+/// Result of comparing the image with the device's readback
+#[derive(Clone, Debug)]
+pub enum Verdict {
+    /// No readback was run
+    NotChecked,
+    Pass(Reported),
+    Fail(Reported),
+    /// The device's reply held no checksum
+    NoChecksum(String),
+    /// The readback command itself failed
+    Error(String),
+}
+
+impl Verdict {
+    pub fn is_pass(&self) -> bool {
+        matches!(self, Verdict::Pass(_))
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::NotChecked => write!(f, "No readback"),
+            Verdict::Pass(reported) => write!(f, "PASS, device reports {reported}"),
+            Verdict::Fail(reported) => write!(f, "FAIL, device reports {reported}"),
+            Verdict::NoChecksum(reply) => write!(f, "FAIL, no checksum in reply {reply:?}"),
+            Verdict::Error(err) => write!(f, "FAIL, readback error: {err}"),
+        }
+    }
+}
+
+/// Checksums of the files sent and the verdict of the readback
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub files: Vec<(PathBuf, Digest)>,
+    pub verdict: Verdict,
+}
+
+impl Report {
+    /// Runs the readback against the checksums of the files sent. It only
+    /// runs for a single file, there is no telling which file of a batch
+    /// the device would report on.
+    pub fn build(
+        files: Vec<(PathBuf, Digest)>,
+        readback: Option<&Readback>,
+        device: &mut dyn Transport,
+    ) -> Self {
+        for (path, digest) in &files {
+            println!(
+                "{}: CRC32 {}, SHA-256 {}",
+                path.display(),
+                digest.crc32_hex(),
+                digest.sha256_hex()
+            );
+        }
+        let verdict = match (readback, files.as_slice()) {
+            (Some(readback), [(_, digest)]) => match readback.run(device, digest) {
+                Ok(reply) => match Reported::parse(&reply) {
+                    Some(reported) if reported.matches(digest) => Verdict::Pass(reported),
+                    Some(reported) => Verdict::Fail(reported),
+                    None => Verdict::NoChecksum(reply),
+                },
+                Err(err) => Verdict::Error(err.to_string()),
+            },
+            _ => Verdict::NotChecked,
+        };
+        println!("Verification: {verdict}");
+        Self { files, verdict }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_digest_and_parse() {
+    let digest = Digest::of_stream(&mut &b"123456789"[..]).unwrap();
+    assert_eq!(digest.size, 9);
+    assert_eq!(digest.crc32_hex(), "cbf43926");
+    assert_eq!(
+        digest.sha256_hex(),
+        "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+    );
+
+    let reply = "crc 0x08000000 9\r\nCRC32: CBF43926\r\n> ";
+    let reported = Reported::parse(reply).unwrap();
+    assert_eq!(reported, Reported::Crc32(0xcbf43926));
+    assert!(reported.matches(&digest));
+    let reply = format!("sha256sum: {}  -\r\n", digest.sha256_hex());
+    assert!(Reported::parse(&reply).unwrap().matches(&digest));
+    assert_eq!(Reported::parse("crc 0x1f"), Some(Reported::Crc32(0x1f)));
+    assert_eq!(Reported::parse("no checksum 12345 here"), None);
+    // A byte count isn't a checksum, a labelled one is
+    assert_eq!(Reported::parse("12582912 bytes received\r\n> "), None);
+    assert_eq!(
+        Reported::parse("CRC32: 12345678\r\n12582912 bytes"),
+        Some(Reported::Crc32(0x12345678))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_readback_over_loopback() {
+    use crate::transport::loopback_pair;
+    use std::io::Write;
+    let (mut device, mut peer) = loopback_pair();
+    let digest = Digest::of_stream(&mut &b"123456789"[..]).unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut command = vec![];
+        let mut byte = [0; 1];
+        while !command.ends_with(b"\r\n") {
+            if let Ok(1) = peer.read(&mut byte) {
+                command.push(byte[0]);
+            }
+        }
+        assert_eq!(command, b"crc32 9\r\n");
+        peer.write_all(b"crc32 9\r\n0xcbf43926\r\n").unwrap();
+    });
+    let readback = Readback {
+        command: "crc32 {size}".into(),
+        timeout: Duration::from_secs(5),
+    };
+    let reply = readback.run(&mut device, &digest).unwrap();
+    handle.join().unwrap();
+    assert_eq!(reply, "0xcbf43926\r\n");
+    assert!(Reported::parse(&reply).unwrap().matches(&digest));
+}
@@ -0,0 +1,717 @@
+use std::collections::VecDeque;
+use std::mem;
+
+/// Lines kept once they scroll off the top of the screen
+pub const SCROLLBACK: usize = 1000;
+
+const TAB: usize = 8;
+
+/// Longest CSI parameter string kept, longer ones aren't real sequences
+const MAX_CSI: usize = 64;
+
+/// A color as the device set it, the renderer picks the actual colors
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    /// One of the 256 xterm colors, the first 16 are the ANSI ones
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// SGR attributes of a cell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            ch: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+/// What DECSC saves and DECRC restores
+#[derive(Clone, Copy, Debug, Default)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    style: Style,
+    /// Whether G0 and G1 hold the DEC line drawing set
+    graphics: [bool; 2],
+    /// G1 is shifted in
+    shifted: bool,
+}
+
+/// Where the parser is in an escape sequence
+enum State {
+    Ground,
+    Escape,
+    /// ESC followed by an intermediate, such as `(` to designate G0
+    EscapeIntermediate(char),
+    /// Parameter and intermediate characters so far
+    Csi(String),
+    Osc,
+    OscEscape,
+}
+
+/// The screen of a VT100 with the ANSI and xterm additions device shells
+/// and curses programs use: a grid of cells written at the cursor, SGR
+/// colors, scroll regions and the alternate screen.
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    lines: Vec<Vec<Cell>>,
+    /// The primary screen while the alternate one is shown
+    primary: Option<Vec<Vec<Cell>>>,
+    scrollback: VecDeque<Vec<Cell>>,
+    cursor: Cursor,
+    saved: Cursor,
+    /// A character was written to the last column, the next one wraps
+    wrap_pending: bool,
+    /// Scroll region, both lines included
+    top: usize,
+    bottom: usize,
+    autowrap: bool,
+    cursor_visible: bool,
+    /// Cursor keys send SS3 sequences instead of CSI ones
+    application_cursor: bool,
+    state: State,
+    /// Answers to device status requests, for the port
+    replies: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+        Self {
+            rows,
+            cols,
+            lines: vec![vec![Cell::default(); cols]; rows],
+            primary: None,
+            scrollback: VecDeque::new(),
+            cursor: Cursor::default(),
+            saved: Cursor::default(),
+            wrap_pending: false,
+            top: 0,
+            bottom: rows - 1,
+            autowrap: true,
+            cursor_visible: true,
+            application_cursor: false,
+            state: State::Ground,
+            replies: vec![],
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor.row, self.cursor.col)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// Whether the program asked for the cursor keys' application mode
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor
+    }
+
+    /// Lines in the scrollback, which come before the screen's lines
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    /// A line of the scrollback followed by the screen, None past the end
+    pub fn line(&self, index: usize) -> Option<&[Cell]> {
+        match index.checked_sub(self.scrollback.len()) {
+            None => self.scrollback.get(index).map(Vec::as_slice),
+            Some(row) => self.lines.get(row).map(Vec::as_slice),
+        }
+    }
+
+    /// Bytes to send back to the device, such as a cursor position report
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        mem::take(&mut self.replies)
+    }
+
+    /// The scrollback and the screen as plain text, for copying
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = (0..self.scrollback.len() + self.rows)
+            .filter_map(|index| self.line(index))
+            .map(|line| {
+                let text: String = line.iter().map(|cell| cell.ch).collect();
+                text.trim_end().to_string()
+            })
+            .collect();
+        lines.join("\n").trim_end().to_string()
+    }
+
+    /// Changes the size, lines that no longer fit above the cursor go to
+    /// the scrollback
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+        if (rows, cols) == (self.rows, self.cols) {
+            return;
+        }
+        if self.cursor.row >= rows {
+            let excess = self.cursor.row + 1 - rows;
+            for line in self.lines.drain(..excess) {
+                if self.primary.is_none() {
+                    self.scrollback.push_back(line);
+                }
+            }
+            self.cursor.row -= excess;
+        }
+        for lines in [Some(&mut self.lines), self.primary.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            lines.resize(rows, vec![Cell::default(); cols]);
+            for line in lines.iter_mut() {
+                line.resize(cols, Cell::default());
+            }
+        }
+        while self.scrollback.len() > SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.top = 0;
+        self.bottom = rows - 1;
+        self.cursor.col = self.cursor.col.min(cols - 1);
+        self.saved.row = self.saved.row.min(rows - 1);
+        self.saved.col = self.saved.col.min(cols - 1);
+        self.wrap_pending = false;
+    }
+
+    /// Runs decoded text from the device through the terminal
+    pub fn feed(&mut self, text: &str) {
+        for ch in text.chars() {
+            self.advance(ch);
+        }
+    }
+
+    fn advance(&mut self, ch: char) {
+        // Controls act in the middle of sequences too, CAN and SUB abort them
+        match ch {
+            '\x1B' => {
+                self.state = match self.state {
+                    State::Osc => State::OscEscape,
+                    _ => State::Escape,
+                };
+                return;
+            }
+            '\x18' | '\x1A' => {
+                self.state = State::Ground;
+                return;
+            }
+            '\x07' if matches!(self.state, State::Osc) => {
+                self.state = State::Ground;
+                return;
+            }
+            '\x00'..='\x1F' if !matches!(self.state, State::Osc | State::OscEscape) => {
+                self.control(ch);
+                return;
+            }
+            // DEL and the C1 controls a Latin-1 decode turns up
+            '\x7F'..='\u{9F}' => return,
+            _ => (),
+        }
+        match mem::replace(&mut self.state, State::Ground) {
+            State::Ground => self.print(ch),
+            State::Escape => self.escape(ch),
+            State::EscapeIntermediate(intermediate) => match intermediate {
+                '(' => self.cursor.graphics[0] = ch == '0',
+                ')' => self.cursor.graphics[1] = ch == '0',
+                _ => (),
+            },
+            State::Csi(mut sequence) => match ch {
+                '\x40'..='\x7E' if sequence.len() < MAX_CSI => self.csi(&sequence, ch),
+                '\x40'..='\x7E' => (),
+                _ => {
+                    if sequence.len() < MAX_CSI {
+                        sequence.push(ch);
+                    }
+                    self.state = State::Csi(sequence);
+                }
+            },
+            State::Osc => self.state = State::Osc,
+            // Anything else after ESC ends the string as well
+            State::OscEscape => (),
+        }
+    }
+
+    fn control(&mut self, ch: char) {
+        match ch {
+            '\x08' => {
+                self.cursor.col = self.cursor.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            '\t' => {
+                self.cursor.col = ((self.cursor.col / TAB + 1) * TAB).min(self.cols - 1);
+                self.wrap_pending = false;
+            }
+            '\n' | '\x0B' | '\x0C' => self.index(),
+            '\r' => {
+                self.cursor.col = 0;
+                self.wrap_pending = false;
+            }
+            '\x0E' => self.cursor.shifted = true,
+            '\x0F' => self.cursor.shifted = false,
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        if self.wrap_pending && self.autowrap {
+            self.cursor.col = 0;
+            self.index();
+        }
+        self.wrap_pending = false;
+        let ch = if self.cursor.graphics[self.cursor.shifted as usize] {
+            line_drawing(ch)
+        } else {
+            ch
+        };
+        self.lines[self.cursor.row][self.cursor.col] = Cell {
+            ch,
+            style: self.cursor.style,
+        };
+        if self.cursor.col + 1 < self.cols {
+            self.cursor.col += 1;
+        } else {
+            self.wrap_pending = true;
+        }
+    }
+
+    fn escape(&mut self, ch: char) {
+        match ch {
+            '[' => self.state = State::Csi(String::new()),
+            ']' => self.state = State::Osc,
+            '(' | ')' | '#' | ' ' => self.state = State::EscapeIntermediate(ch),
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'D' => self.index(),
+            'E' => {
+                self.cursor.col = 0;
+                self.index();
+            }
+            'M' => self.reverse_index(),
+            'c' => {
+                let scrollback = mem::take(&mut self.scrollback);
+                *self = Screen::new(self.rows, self.cols);
+                self.scrollback = scrollback;
+            }
+            // Keypad modes and the rest don't change the screen
+            _ => (),
+        }
+    }
+
+    fn csi(&mut self, sequence: &str, command: char) {
+        let private = sequence.starts_with('?');
+        let params: Vec<usize> = sequence
+            .trim_start_matches(['?', '>', '='])
+            .split([';', ':'])
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        // Zero and missing parameters count as one for most commands
+        let count = |index: usize| params.get(index).copied().unwrap_or(0).max(1);
+        let param = |index: usize| params.get(index).copied().unwrap_or(0);
+        if !command.is_ascii_alphabetic() && command != '@' && command != '`' {
+            return;
+        }
+        if !matches!(command, 'h' | 'l' | 'm') {
+            self.wrap_pending = false;
+        }
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match command {
+            'A' => self.cursor.row = row.saturating_sub(count(0)).max(self.upper_limit()),
+            'B' | 'e' => self.cursor.row = row.saturating_add(count(0)).min(self.lower_limit()),
+            'C' | 'a' => self.cursor.col = col.saturating_add(count(0)).min(self.cols - 1),
+            'D' => self.cursor.col = col.saturating_sub(count(0)),
+            'E' => {
+                self.cursor.row = row.saturating_add(count(0)).min(self.lower_limit());
+                self.cursor.col = 0;
+            }
+            'F' => {
+                self.cursor.row = row.saturating_sub(count(0)).max(self.upper_limit());
+                self.cursor.col = 0;
+            }
+            'G' | '`' => self.cursor.col = (count(0) - 1).min(self.cols - 1),
+            'd' => self.cursor.row = (count(0) - 1).min(self.rows - 1),
+            'H' | 'f' => {
+                self.cursor.row = (count(0) - 1).min(self.rows - 1);
+                self.cursor.col = (count(1) - 1).min(self.cols - 1);
+            }
+            'J' => {
+                let blank = self.blank();
+                let (rows, cols) = (self.rows, self.cols);
+                match param(0) {
+                    0 => {
+                        self.erase(row, col, cols);
+                        for line in &mut self.lines[row + 1..rows] {
+                            line.fill(blank);
+                        }
+                    }
+                    1 => {
+                        for line in &mut self.lines[..row] {
+                            line.fill(blank);
+                        }
+                        self.erase(row, 0, col + 1);
+                    }
+                    2 | 3 => {
+                        for line in &mut self.lines {
+                            line.fill(blank);
+                        }
+                        if param(0) == 3 {
+                            self.scrollback.clear();
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            'K' => match param(0) {
+                0 => self.erase(row, col, self.cols),
+                1 => self.erase(row, 0, col + 1),
+                2 => self.erase(row, 0, self.cols),
+                _ => (),
+            },
+            'X' => self.erase(row, col, col.saturating_add(count(0))),
+            '@' => {
+                let blank = self.blank();
+                let line = &mut self.lines[row];
+                let n = count(0).min(self.cols - col);
+                line[col..].rotate_right(n);
+                line[col..col + n].fill(blank);
+            }
+            'P' => {
+                let blank = self.blank();
+                let line = &mut self.lines[row];
+                let n = count(0).min(self.cols - col);
+                line[col..].rotate_left(n);
+                let cols = self.cols;
+                line[cols - n..].fill(blank);
+            }
+            'L' if (self.top..=self.bottom).contains(&row) => {
+                self.scroll_down_from(row, count(0));
+                self.cursor.col = 0;
+            }
+            'M' if (self.top..=self.bottom).contains(&row) => {
+                self.scroll_up_from(row, count(0));
+                self.cursor.col = 0;
+            }
+            'S' => self.scroll_up_from(self.top, count(0)),
+            'T' if params.len() <= 1 => self.scroll_down_from(self.top, count(0)),
+            'm' => self.sgr(&params),
+            'r' if !private => {
+                let top = count(0) - 1;
+                let bottom = match param(1) {
+                    0 => self.rows - 1,
+                    bottom => bottom.min(self.rows) - 1,
+                };
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.cursor.row = 0;
+                    self.cursor.col = 0;
+                }
+            }
+            's' if !private => self.save_cursor(),
+            'u' if !private => self.restore_cursor(),
+            'h' | 'l' if private => {
+                for &mode in &params {
+                    self.set_private_mode(mode, command == 'h');
+                }
+            }
+            'n' if !private => match param(0) {
+                5 => self.replies.extend_from_slice(b"\x1B[0n"),
+                6 => self
+                    .replies
+                    .extend_from_slice(format!("\x1B[{};{}R", row + 1, col + 1).as_bytes()),
+                _ => (),
+            },
+            // A VT100 with advanced video
+            'c' if sequence.is_empty() || sequence == "0" => {
+                self.replies.extend_from_slice(b"\x1B[?1;2c")
+            }
+            _ => (),
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: usize, set: bool) {
+        match mode {
+            1 => self.application_cursor = set,
+            7 => self.autowrap = set,
+            25 => self.cursor_visible = set,
+            47 | 1047 => self.alternate_screen(set),
+            1048 if set => self.save_cursor(),
+            1048 => self.restore_cursor(),
+            1049 if set => {
+                self.save_cursor();
+                self.alternate_screen(true);
+            }
+            1049 => {
+                self.alternate_screen(false);
+                self.restore_cursor();
+            }
+            _ => (),
+        }
+    }
+
+    /// Switches to a blank alternate screen, or back to the primary one
+    fn alternate_screen(&mut self, on: bool) {
+        let blank = vec![vec![Cell::default(); self.cols]; self.rows];
+        match (on, self.primary.take()) {
+            (true, None) => self.primary = Some(mem::replace(&mut self.lines, blank)),
+            (false, Some(primary)) => self.lines = primary,
+            (_, primary) => self.primary = primary,
+        }
+    }
+
+    fn sgr(&mut self, params: &[usize]) {
+        let style = &mut self.cursor.style;
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dim = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                7 => style.reverse = true,
+                22 => {
+                    style.bold = false;
+                    style.dim = false;
+                }
+                23 => style.italic = false,
+                24 => style.underline = false,
+                27 => style.reverse = false,
+                30..=37 => style.fg = Color::Indexed((param - 30) as u8),
+                38 => style.fg = extended_color(&mut params),
+                39 => style.fg = Color::Default,
+                40..=47 => style.bg = Color::Indexed((param - 40) as u8),
+                48 => style.bg = extended_color(&mut params),
+                49 => style.bg = Color::Default,
+                90..=97 => style.fg = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => style.bg = Color::Indexed((param - 100 + 8) as u8),
+                _ => (),
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = self.cursor;
+    }
+
+    fn restore_cursor(&mut self) {
+        self.cursor = self.saved;
+        self.wrap_pending = false;
+    }
+
+    /// Moves down a line, scrolling the region at its bottom
+    fn index(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.bottom {
+            self.scroll_up_from(self.top, 1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.top {
+            self.scroll_down_from(self.top, 1);
+        } else {
+            self.cursor.row = self.cursor.row.saturating_sub(1);
+        }
+    }
+
+    /// Scrolls the lines from the row to the bottom of the region up. Lines
+    /// leaving the top of the primary screen go to the scrollback.
+    fn scroll_up_from(&mut self, row: usize, count: usize) {
+        let count = count.min(self.bottom + 1 - row);
+        let blank = vec![self.blank(); self.cols];
+        let removed: Vec<_> = self.lines.drain(row..row + count).collect();
+        let at = self.bottom + 1 - count;
+        for _ in 0..count {
+            self.lines.insert(at, blank.clone());
+        }
+        if row == 0 && self.primary.is_none() {
+            self.scrollback.extend(removed);
+            while self.scrollback.len() > SCROLLBACK {
+                self.scrollback.pop_front();
+            }
+        }
+    }
+
+    fn scroll_down_from(&mut self, row: usize, count: usize) {
+        let count = count.min(self.bottom + 1 - row);
+        let blank = vec![self.blank(); self.cols];
+        self.lines.drain(self.bottom + 1 - count..=self.bottom);
+        for _ in 0..count {
+            self.lines.insert(row, blank.clone());
+        }
+    }
+
+    /// Erases columns of a row, the end excluded
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let blank = self.blank();
+        let end = end.min(self.cols);
+        if start < end {
+            self.lines[row][start..end].fill(blank);
+        }
+    }
+
+    /// An erased cell takes the current background
+    fn blank(&self) -> Cell {
+        Cell {
+            ch: ' ',
+            style: Style {
+                bg: self.cursor.style.bg,
+                ..Style::default()
+            },
+        }
+    }
+
+    /// Cursor moves up stop at the top of the region, if they start in it
+    fn upper_limit(&self) -> usize {
+        if self.cursor.row >= self.top {
+            self.top
+        } else {
+            0
+        }
+    }
+
+    fn lower_limit(&self) -> usize {
+        if self.cursor.row <= self.bottom {
+            self.bottom
+        } else {
+            self.rows - 1
+        }
+    }
+}
+
+/// The color after 38 or 48, `5;n` for an indexed one or `2;r;g;b`
+fn extended_color(params: &mut impl Iterator<Item = usize>) -> Color {
+    match params.next() {
+        Some(5) => Color::Indexed(params.next().unwrap_or(0) as u8),
+        Some(2) => {
+            let mut channel = || params.next().unwrap_or(0) as u8;
+            Color::Rgb(channel(), channel(), channel())
+        }
+        _ => Color::Default,
+    }
+}
+
+/// The DEC special graphics set, which curses draws boxes with
+fn line_drawing(ch: char) -> char {
+    match ch {
+        '`' => '◆',
+        'a' => '▒',
+        'f' => '°',
+        'g' => '±',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => ch,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_text_wrap_and_scrollback() {
+    let mut screen = Screen::new(3, 10);
+    screen.feed("login: root\r\n\tX\r\nline 3\r\nline 4");
+    // "login: roo" fills the first row, "t" wraps to the second
+    assert_eq!(screen.scrollback_len(), 2);
+    assert_eq!(screen.text(), "login: roo\nt\n        X\nline 3\nline 4");
+    assert_eq!(screen.cursor(), (2, 6));
+    screen.feed("\x1B[2J\x1B[H\x1B[31;1mred\x1B[0m \x1B[48;5;196mbg\x1B[K");
+    let line = screen.line(screen.scrollback_len()).unwrap();
+    assert_eq!(line[0].style.fg, Color::Indexed(1));
+    assert!(line[0].style.bold);
+    assert_eq!(line[3].style, Style::default());
+    assert_eq!(line[4].style.bg, Color::Indexed(196));
+    // Erasing takes the background color
+    assert_eq!(line[9].style.bg, Color::Indexed(196));
+}
+
+#[cfg(test)]
+#[test]
+fn test_cursor_editing_and_regions() {
+    let mut screen = Screen::new(5, 10);
+    screen.feed("abcdef\x1B[3D\x1B[2P\x1B[1@\x1B[2;3HX\x1B[1;1H\x1B[2Cy");
+    assert_eq!(screen.text(), "aby f\n  X");
+    // Scroll region of rows 2 to 4, the others stay
+    screen.feed("\x1B[H\x1B[2J1\r\n2\r\n3\r\n4\r\n5\x1B[2;4r\x1B[4;1H\n");
+    assert_eq!(screen.text(), "1\n3\n4\n\n5");
+    assert_eq!(screen.scrollback_len(), 0);
+    screen.feed("\x1B[2;1H\x1BM");
+    assert_eq!(screen.text(), "1\n\n3\n4\n5");
+    // Cursor position report
+    screen.feed("\x1B[r\x1B[3;7H\x1B[6n");
+    assert_eq!(screen.take_replies(), b"\x1B[3;7R");
+    // Parameters past any screen size stop at its edge
+    screen.feed(&format!(
+        "\x1B[{max}B\x1B[{max}C\x1B[{max}X\x1B[6n",
+        max = usize::MAX
+    ));
+    assert_eq!(screen.take_replies(), b"\x1B[5;10R");
+    let endless = format!("\x1B[{}H", "1;".repeat(10_000));
+    screen.feed(&endless);
+    assert_eq!(screen.cursor(), (4, 9));
+}
+
+#[cfg(test)]
+#[test]
+fn test_alternate_screen_and_line_drawing() {
+    let mut screen = Screen::new(3, 10);
+    screen.feed("$ vi\r\n");
+    screen.feed("\x1B[?1049h\x1B[H\x1B(0lqk\x1B(B ok\x1B]0;title\x07");
+    assert_eq!(screen.text(), "┌─┐ ok");
+    screen.feed("\x1B[?25l");
+    assert!(!screen.cursor_visible());
+    screen.feed("\x1B[?1049l");
+    assert_eq!(screen.text(), "$ vi");
+    assert_eq!(screen.cursor(), (1, 0));
+}
@@ -1,345 +1,810 @@
-use serialport::SerialPort;
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{drain, is_timeout, Transport};
 use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
 pub struct XModem {
     /// Maximum retries
     retries: i32,
     /// Padding bytes
     padbyte: u8,
+    /// Block length used when sending
+    block_length: BlockLength,
+    /// Receive with CRC-16 rather than the 8bit checksum
+    pub(crate) crc_mode: bool,
+    /// Remove the padding from the last block received
+    trim_padding: bool,
+    /// Time to wait for the peer to start the transfer
+    pub(crate) sync_timeout: Duration,
+    /// Time to wait for each packet or reply once the transfer started
+    pub(crate) packet_timeout: Duration,
+    /// Progress of the current transfer and its cancel token
+    pub(crate) reporter: Reporter,
+}
+
+/// Length of the data block carried by each packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockLength {
+    /// 128 byte blocks, sent with an SOH header
+    Standard,
+    /// 1024 byte blocks, sent with an STX header (XModem-1K)
+    OneK,
+}
+
+impl BlockLength {
+    /// Number of data bytes in a block
+    pub fn size(self) -> usize {
+        match self {
+            BlockLength::Standard => 128,
+            BlockLength::OneK => 1024,
+        }
+    }
+}
+
+/// A packet read by the receiver
+pub(crate) enum Received {
+    /// Packet number and data block
+    Packet(u8, Vec<u8>),
+    /// End of transmission
+    Eot,
 }
 
 const SOH: u8 = 0x01;
 const STX: u8 = 0x02;
 const EOT: u8 = 0x04;
-const ACK: u8 = 0x06;
-const NAK: u8 = 0x15;
+pub(crate) const ACK: u8 = 0x06;
+pub(crate) const NAK: u8 = 0x15;
 const CAN: u8 = 0x18;
+const BS: u8 = 0x08;
 const SUB: u8 = 0x1A;
-const CRC: u8 = 0x43;
+pub(crate) const CRC: u8 = 0x43;
 
-impl XModem {
-    pub fn new() -> Self {
+/// Unanswered CRC polls before the receiver falls back to checksum mode
+const CRC_POLLS: i32 = 3;
+
+/// Builds an `XModem` with tuned settings. The builder can be kept and
+/// cloned to reuse a profile, such as one for firmware uploads.
+#[derive(Clone, Debug)]
+pub struct XModemBuilder {
+    retries: i32,
+    padbyte: u8,
+    block_length: BlockLength,
+    crc_mode: bool,
+    trim_padding: bool,
+    sync_timeout: Duration,
+    packet_timeout: Duration,
+}
+
+impl Default for XModemBuilder {
+    fn default() -> Self {
         Self {
             retries: 16,
             padbyte: SUB,
+            block_length: BlockLength::Standard,
+            crc_mode: false,
+            trim_padding: true,
+            sync_timeout: Duration::from_secs(10),
+            packet_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl XModemBuilder {
+    /// Maximum retries for a packet, or for the start of the transfer
+    pub fn retries(mut self, retries: i32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Byte the last block is padded with
+    pub fn padbyte(mut self, padbyte: u8) -> Self {
+        self.padbyte = padbyte;
+        self
+    }
+
+    /// Block length used when sending
+    pub fn block_length(mut self, block_length: BlockLength) -> Self {
+        self.block_length = block_length;
+        self
+    }
+
+    /// Ask the sender for CRC-16 rather than the 8bit checksum when receiving.
+    /// A sender always uses what the receiver asks for.
+    pub fn crc_mode(mut self, crc_mode: bool) -> Self {
+        self.crc_mode = crc_mode;
+        self
+    }
+
+    /// Remove trailing pad bytes from the last block received. A file that
+    /// really ends in the pad byte loses those bytes, XModem can't tell
+    /// them apart from padding.
+    pub fn trim_padding(mut self, trim_padding: bool) -> Self {
+        self.trim_padding = trim_padding;
+        self
+    }
+
+    /// Time to wait for the peer to start the transfer, on each retry
+    pub fn sync_timeout(mut self, timeout: Duration) -> Self {
+        self.sync_timeout = timeout;
+        self
+    }
+
+    /// Time to wait for each packet or reply once the transfer started
+    pub fn packet_timeout(mut self, timeout: Duration) -> Self {
+        self.packet_timeout = timeout;
+        self
+    }
+
+    pub fn build(&self) -> XModem {
+        XModem {
+            retries: self.retries,
+            padbyte: self.padbyte,
+            block_length: self.block_length,
+            crc_mode: self.crc_mode,
+            trim_padding: self.trim_padding,
+            sync_timeout: self.sync_timeout,
+            packet_timeout: self.packet_timeout,
+            reporter: Reporter::default(),
+        }
+    }
+}
+
+impl XModem {
+    pub fn new() -> Self {
+        XModemBuilder::default().build()
+    }
+
+    /// Creates an XModem-1K sender, it falls back to 128 byte blocks if the
+    /// receiver asks for checksum mode.
+    pub fn new_1k() -> Self {
+        let mut xmodem = Self::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem
+    }
+
+    pub fn builder() -> XModemBuilder {
+        XModemBuilder::default()
+    }
+
+    /// Sets the function called every time the transfer progresses
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    /// Sets the size reported as the progress total, the size of a stream
+    /// being sent isn't known otherwise.
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.reporter.set_total(total);
+    }
+
+    /// Token that cancels the transfer from another thread. The transfer
+    /// sends the cancel sequence to the peer and fails with `TransferError::Aborted`.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Cancels the transfer with a token shared with other work
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.reporter.set_cancel_token(token);
+    }
+
+    /// Counts a retry in both the caller's error count and the progress
+    fn count_error(&mut self, errors: &mut i32) {
+        *errors += 1;
+        self.reporter.count_retry();
+    }
+
+    /// Sends the cancel sequence to the peer if the transfer was cancelled on this end
+    pub(crate) fn check_cancel(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        if !self.reporter.is_cancelled() {
+            return Ok(());
         }
+        println!("Transfer cancelled, sending CAN");
+        self.send_abort(device)?;
+        Err(TransferError::Aborted)
     }
 
-    fn send_byte(&mut self, device: &mut Box<dyn SerialPort>, byte: u8) {
-        let packet: Vec<u8> = vec![byte];
-        device.write(&packet[..]).expect("Failed to send byte");
+    /// Sends eight CANs, then eight backspaces that erase them again
+    /// if the peer already left the transfer for a command line.
+    pub(crate) fn send_abort(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.write_all(&[CAN; 8])?;
+        device.write_all(&[BS; 8])?;
+        device.flush()?;
+        Ok(())
     }
 
-    fn read_byte(&mut self, device: &mut Box<dyn SerialPort>) -> Result<u8, std::io::Error> {
+    /// Cleans up the line after a transfer ended. A peer left waiting by a
+    /// failure is told to stop, and whatever the peer still sends is
+    /// drained so it doesn't show up in the terminal.
+    pub(crate) fn settle<T>(
+        &mut self,
+        device: &mut dyn Transport,
+        result: Result<T, TransferError>,
+    ) -> Result<T, TransferError> {
+        match &result {
+            Ok(_) => return result,
+            Err(TransferError::TooManyRetries) | Err(TransferError::Timeout) => {
+                println!("Giving up, sending CAN");
+                let _ = self.send_abort(device);
+            }
+            Err(_) => (),
+        }
+        drain(device);
+        result
+    }
+
+    pub(crate) fn send_byte(
+        &mut self,
+        device: &mut dyn Transport,
+        byte: u8,
+    ) -> Result<(), TransferError> {
+        device.write_all(&[byte])?;
+        Ok(())
+    }
+
+    /// Reads a byte, waiting up to the packet timeout for it
+    pub(crate) fn read_byte(&mut self, device: &mut dyn Transport) -> Result<u8, TransferError> {
+        self.read_byte_within(device, self.packet_timeout)
+    }
+
+    /// Reads a byte, waiting up to `timeout` for it. The port's own read
+    /// timeout only decides how often the deadline is checked.
+    pub(crate) fn read_byte_within(
+        &mut self,
+        device: &mut dyn Transport,
+        timeout: Duration,
+    ) -> Result<u8, TransferError> {
+        let deadline = Instant::now() + timeout;
         let mut bytes = [0; 1];
-        match device.read(&mut bytes) {
-            Ok(_) => Ok(bytes[0]),
-            Err(err) => Err(err),
+        loop {
+            self.check_cancel(device)?;
+            match device.read(&mut bytes) {
+                Ok(0) => return Err(TransferError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                Ok(_) => return Ok(bytes[0]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
         }
     }
 
-    /// Receives to a stream on the XModem protocol
+    /// Receives to a stream on the XModem protocol, in CRC mode if the
+    /// builder asked for it and the sender supports it. Returns the number
+    /// of bytes written, without the padding if it is trimmed.
     pub fn receive(
         &mut self,
-        device: &mut Box<dyn SerialPort>,
+        device: &mut dyn Transport,
         mut stream: Box<dyn Write>,
+    ) -> Result<usize, TransferError> {
+        self.reporter.start();
+        let crc_mode = self.crc_mode;
+        let result = self.receive_stream(device, stream.as_mut(), crc_mode);
+        let result = self.settle(device, result);
+        self.reporter.finish(result)
+    }
+
+    fn receive_stream(
+        &mut self,
+        device: &mut dyn Transport,
+        stream: &mut dyn Write,
         crc_mode: bool,
-    ) -> Result<usize, &'static str> {
+    ) -> Result<usize, TransferError> {
+        // Synchronization, a sender that only knows checksums ignores the
+        // CRC polls so the receiver falls back to checksum mode after a few.
+        let mut crc_mode = crc_mode;
+        let mut polls = 0;
+        let header = loop {
+            let poll = if crc_mode { CRC } else { NAK };
+            self.send_byte(device, poll)?;
+            match self.read_byte_within(device, self.sync_timeout) {
+                Ok(header) => break header,
+                Err(TransferError::Timeout) => {
+                    polls += 1;
+                    self.reporter.count_retry();
+                    println!("No answer to poll {polls}");
+                    if polls > self.retries {
+                        return Err(TransferError::SyncTimeout);
+                    }
+                    if crc_mode && polls >= CRC_POLLS {
+                        println!("Falling back to checksum mode");
+                        crc_mode = false;
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        // Receive Packets
         let mut errors = 0;
         let mut size = 0;
-        let mut cancel = false;
-        // Synchronization
-        let buf = if crc_mode { vec![CRC] } else { vec![NAK] };
-        device.write(&buf[..]).expect("Sync I/O failure");
-        // Receive Packets
-        let mut data_length: usize = 128;
+        let mut header = Some(header);
         let mut packet_num: u8 = 1;
+        // Each block is held back until the next one arrives, so the padding
+        // of the last block can be trimmed once EOT shows it was the last.
+        let mut pending: Option<Vec<u8>> = None;
         loop {
-            // Read Header
-            match self.read_byte(device) {
-                Ok(header) => {
-                    println!("Data received {:?}", header);
-                    match header {
-                        SOH => data_length = 128,
-                        STX => data_length = 1024,
-                        EOT => break,
-                        CAN => {
-                            if cancel {
-                                return Err("Cancelled got CAN Twice");
-                            }
-                            cancel = true;
-                            continue;
-                        }
-                        _ => {
-                            device.write(&buf[..]).expect("Sync I/O failure");
-                            errors += 1;
-                            if errors > self.retries {
-                                return Err(
-                                    "Synchronization failed, reached max number of retries",
-                                );
+            let timeout = self.packet_timeout;
+            // 'C' only asks for the first block, later ones are asked for with NAK
+            let poll = if crc_mode && pending.is_none() {
+                CRC
+            } else {
+                NAK
+            };
+            let received = self.receive_packet_after(
+                device,
+                header.take(),
+                crc_mode,
+                poll,
+                timeout,
+                &mut errors,
+            )?;
+            match received {
+                Received::Eot => {
+                    self.reporter.set_state(TransferState::Finishing);
+                    if let Some(mut last) = pending.take() {
+                        if self.trim_padding {
+                            while last.last() == Some(&self.padbyte) {
+                                last.pop();
                             }
-                            continue;
                         }
+                        size += last.len();
+                        stream
+                            .write_all(&last)
+                            .map_err(TransferError::StreamWrite)?;
                     }
+                    break;
                 }
-                Err(err) => {
-                    errors += 1;
-                    println!("Error Count: {errors}, Error: {err}");
-                    if errors > self.retries {
-                        return Err("Packet Send Failed, reached max number of retries");
+                Received::Packet(num, data) if num == packet_num => {
+                    if pending.is_none() && size == 0 {
+                        self.reporter.set_state(TransferState::Transferring);
                     }
+                    let len = data.len();
+                    if let Some(previous) = pending.replace(data) {
+                        size += previous.len();
+                        stream
+                            .write_all(&previous)
+                            .map_err(TransferError::StreamWrite)?;
+                    }
+                    self.accept_packet(device)?;
+                    self.reporter.count_packet(len);
+                    packet_num = packet_num.wrapping_add(1);
+                    errors = 0;
+                }
+                // The sender missed our ACK and sent the last packet again
+                Received::Packet(num, _)
+                    if pending.is_some() && num == packet_num.wrapping_sub(1) =>
+                {
+                    println!("Duplicate packet {num}, ACK again");
+                    self.accept_packet(device)?;
                 }
+                Received::Packet(num, _) => {
+                    // Losing a whole packet can't be recovered from
+                    println!("Packet {num} out of sequence, expected {packet_num}");
+                    self.send_abort(device)?;
+                    return Err(TransferError::Sequence {
+                        expected: packet_num,
+                        received: num,
+                    });
+                }
+            }
+        }
+        self.send_byte(device, ACK)?;
+        println!("Data received, size: {size}");
+        Ok(size)
+    }
+
+    /// Reads the next packet from the sender, waiting up to `timeout` for it
+    /// to start. Packets that fail their checks are NAKed and read again,
+    /// `poll` is sent when no header arrives.
+    pub(crate) fn receive_packet(
+        &mut self,
+        device: &mut dyn Transport,
+        crc_mode: bool,
+        poll: u8,
+        timeout: Duration,
+        errors: &mut i32,
+    ) -> Result<Received, TransferError> {
+        self.receive_packet_after(device, None, crc_mode, poll, timeout, errors)
+    }
+
+    /// Reads the next packet, starting from a header byte the caller already
+    /// read if there is one.
+    fn receive_packet_after(
+        &mut self,
+        device: &mut dyn Transport,
+        mut header: Option<u8>,
+        crc_mode: bool,
+        poll: u8,
+        timeout: Duration,
+        errors: &mut i32,
+    ) -> Result<Received, TransferError> {
+        let mut cancel = false;
+        let mut deadline = Instant::now() + timeout;
+        loop {
+            if *errors > self.retries {
+                return Err(TransferError::TooManyRetries);
             }
+            // Read Header, anything else before it is line noise
+            let byte = match header.take() {
+                Some(byte) => Ok(byte),
+                None => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    self.read_byte_within(device, remaining)
+                }
+            };
+            let data_length = match byte {
+                Ok(SOH) => BlockLength::Standard.size(),
+                Ok(STX) => BlockLength::OneK.size(),
+                Ok(EOT) => return Ok(Received::Eot),
+                Ok(CAN) => {
+                    if cancel {
+                        return Err(TransferError::Cancelled);
+                    }
+                    cancel = true;
+                    continue;
+                }
+                Ok(byte) => {
+                    println!("Skipping byte {byte} before header");
+                    cancel = false;
+                    continue;
+                }
+                Err(err) if err.is_recoverable() => {
+                    self.count_error(errors);
+                    println!("Error Count: {errors}, Error: {err}");
+                    self.send_byte(device, poll)?;
+                    deadline = Instant::now() + timeout;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
 
-            // Read rest of packet.
+            // Read rest of packet, it may arrive over several reads
             let packet_length = if crc_mode {
                 data_length + 4
             } else {
                 data_length + 3
             };
             let mut packet = vec![0; packet_length];
-            match device.read(&mut packet) {
-                Ok(_) => {
-                    println!("Data received {:?}", packet);
-
-                    let pn1 = packet[0];
-                    let pn2 = packet[1];
-                    if (pn1 + pn2) != 0xff {
-                        println!("Error Packet Number was not expected");
-                        errors += 1;
-                        self.send_byte(device, NAK);
-                        continue;
-                    } else if pn1 != packet_num {
-                        println!("Error Packet Number was not expected");
-                        errors += 1;
-                        self.send_byte(device, NAK);
-                        continue;
-                    }
-                    if errors > self.retries {
-                        return Err("Packet Send Failed, reached max number of retries");
-                    }
+            let result = self.read_exact_within(device, &mut packet, self.packet_timeout);
+            deadline = Instant::now() + timeout;
+            match result {
+                Ok(()) => (),
+                Err(err) if err.is_recoverable() => {
+                    self.count_error(errors);
+                    println!("Error Count: {errors}, Error: {err}");
+                    self.reject_packet(device)?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
 
-                    if crc_mode {
-                        let calc_crc = crc(&packet[2..packet_length - 2]);
-                        let received_crc = ((packet[packet_length - 2] as u16) << 8)
-                            | packet[packet_length - 1] as u16;
-                        if received_crc != calc_crc {
-                            println!("CRC error: theirs {received_crc}, ours {calc_crc}");
-                            errors += 1;
-                            self.send_byte(device, NAK);
-                            continue;
-                        }
-                    } else {
-                        let calc_checksum = checksum(&packet[2..packet_length - 1]);
-                        let received_checksum = packet[packet_length - 1];
-                        if calc_checksum != received_checksum {
-                            println!(
-                                "Check sum error: theirs {received_checksum}, ours {calc_checksum}"
-                            );
-                            errors += 1;
-                            self.send_byte(device, NAK);
-                            continue;
-                        }
-                    }
+            let pn1 = packet[0];
+            let pn2 = packet[1];
+            if pn1 != 0xff - pn2 {
+                println!("Error Packet Number complement did not match");
+                self.count_error(errors);
+                self.reject_packet(device)?;
+                continue;
+            }
 
-                    size += data_length;
-                    stream
-                        .as_mut()
-                        .write(&packet[2..packet_length - 1])
-                        .expect("Failed to write to stream");
-                    println!("Send ACK");
-                    self.send_byte(device, ACK);
-                    if packet_num == 255 {
-                        packet_num = 0;
-                    } else {
-                        packet_num += 1;
-                    }
+            let data = &packet[2..2 + data_length];
+            let check = &packet[2 + data_length..];
+            if crc_mode {
+                let calc_crc = crc(data);
+                let received_crc = u16::from_be_bytes([check[0], check[1]]);
+                if received_crc != calc_crc {
+                    println!("CRC error: theirs {received_crc}, ours {calc_crc}");
+                    self.count_error(errors);
+                    self.reject_packet(device)?;
+                    continue;
                 }
-                _ => {
-                    errors += 1;
-                    if errors > self.retries {
-                        return Err("Packet Send Failed, reached max number of retries");
-                    }
+            } else {
+                let calc_checksum = checksum(data);
+                let received_checksum = check[0];
+                if calc_checksum != received_checksum {
+                    println!("Check sum error: theirs {received_checksum}, ours {calc_checksum}");
+                    self.count_error(errors);
+                    self.reject_packet(device)?;
+                    continue;
                 }
             }
+            return Ok(Received::Packet(pn1, data.to_vec()));
         }
-        self.send_byte(device, ACK);
-        println!("Data received, size: {size}");
-        Ok(size)
+    }
+
+    /// ACKs a packet. A copy of it already on the line is dropped first,
+    /// nothing else can be in flight while the sender waits for the ACK,
+    /// so the sender gets one ACK and can't take a second for the next packet.
+    fn accept_packet(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.clear_input()?;
+        self.send_byte(device, ACK)
+    }
+
+    /// Drops the rest of a bad packet from the line and asks for it again
+    fn reject_packet(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        device.clear_input()?;
+        self.send_byte(device, NAK)
+    }
+
+    /// Fills the buffer from the device, waiting up to `timeout` for all of it
+    fn read_exact_within(
+        &mut self,
+        device: &mut dyn Transport,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(), TransferError> {
+        let deadline = Instant::now() + timeout;
+        let mut len = 0;
+        while len < buf.len() {
+            match device.read(&mut buf[len..]) {
+                Ok(0) => return Err(TransferError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                Ok(n) => len += n,
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if len < buf.len() && Instant::now() >= deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+        Ok(())
     }
 
     /// Sends a stream over the XModem protocol
     pub fn send(
         &mut self,
-        device: &mut Box<dyn SerialPort>,
+        device: &mut dyn Transport,
+        stream: Box<dyn Read>,
+    ) -> Result<(), TransferError> {
+        self.reporter.start();
+        let result = self
+            .synchronize_sender(device)
+            .and_then(|crc_mode| self.send_stream(device, stream, crc_mode));
+        let result = self.settle(device, result);
+        self.reporter.finish(result)
+    }
+
+    /// Sends the packets of a stream followed by the end of transmission,
+    /// the receiver must already be synchronized.
+    pub(crate) fn send_stream(
+        &mut self,
+        device: &mut dyn Transport,
         mut stream: Box<dyn Read>,
-    ) -> Result<(), &'static str> {
-        let mut errors = 0;
+        crc_mode: bool,
+    ) -> Result<(), TransferError> {
+        // 1K blocks are only sent to receivers asking for CRC, a checksum
+        // receiver is assumed to be a plain 128 byte XModem implementation.
+        let block_length = if crc_mode {
+            self.block_length
+        } else {
+            BlockLength::Standard
+        };
+        println!("Block Length: {}", block_length.size());
+        self.reporter.set_state(TransferState::Transferring);
 
+        // Send Packets
+        let mut packet_num: u8 = 1;
+        device.clear_input()?;
+        let mut data: Vec<u8> = vec![0; block_length.size()];
+        loop {
+            let len = read_block(stream.as_mut(), &mut data).map_err(TransferError::StreamRead)?;
+            if len == data.len() {
+                self.send_packet(device, packet_num, &data, crc_mode)?;
+                packet_num = packet_num.wrapping_add(1);
+                continue;
+            }
+            // The tail of the stream goes out in 128 byte blocks so it isn't
+            // padded all the way up to a full 1K block.
+            for chunk in data[..len].chunks(BlockLength::Standard.size()) {
+                self.send_packet(device, packet_num, chunk, crc_mode)?;
+                packet_num = packet_num.wrapping_add(1);
+            }
+            break;
+        }
+
+        self.reporter.set_state(TransferState::Finishing);
+        self.send_eot(device)
+    }
+
+    /// Waits for the receiver to start the transfer, returns true if it asked for CRC mode
+    pub(crate) fn synchronize_sender(
+        &mut self,
+        device: &mut dyn Transport,
+    ) -> Result<bool, TransferError> {
+        let mut errors = 0;
         let mut cancel = false;
-        let mut crc_mode = false;
-        // Synchronize with Reciever
         loop {
-            match self.read_byte(device) {
+            self.check_cancel(device)?;
+            match self.read_byte_within(device, self.sync_timeout) {
                 Ok(header) => {
                     println!("Receiver Byte: {}, Errors: {}", header, errors);
                     match header {
-                        NAK => break,
+                        NAK => return Ok(false),
                         CRC => {
                             println!("Use CRC Mode");
-                            crc_mode = true;
-                            break;
+                            return Ok(true);
                         }
                         CAN => {
                             if cancel {
-                                return Err("Cancelled got CAN Twice");
+                                return Err(TransferError::Cancelled);
                             }
                             cancel = true;
                         }
-                        EOT => return Err("Cancelled got EOT"),
+                        EOT => return Err(TransferError::Cancelled),
                         _ => {
                             errors += 1;
                             if errors > self.retries {
-                                return Err(
-                                    "Synchronization failed, reached max number of retries",
-                                );
+                                return Err(TransferError::SyncTimeout);
                             }
                         }
                     }
                 }
-                Err(err) => {
+                Err(err) if err.is_recoverable() => {
                     errors += 1;
                     println!("Error Count: {errors}, Error: {err}");
                     if errors > self.retries {
-                        return Err("Packet Send Failed, reached max number of retries");
+                        return Err(TransferError::SyncTimeout);
                     }
                 }
+                Err(err) => return Err(err),
             }
         }
+    }
 
-        // Send Packets
-        errors = 0;
-        let packet_length: usize = 128;
-        let mut packet_num: u8 = 1;
-        device
-            .clear(serialport::ClearBuffer::Input)
-            .expect("Failed to clear buffer");
-        loop {
-            let mut data: Vec<u8> = vec![self.padbyte; packet_length];
-            match stream.as_mut().read(&mut data) {
-                Ok(0) => break,
-                Ok(len) => {
-                    loop {
-                        // Emit Packet
-                        let mut packet: Vec<u8> = vec![];
-                        let seq2: u8 = 0xff - packet_num;
-                        println!("PacketNum: {}", packet_num);
-                        println!("PacketNum Inverse: {}", seq2);
-                        println!("Stream Data Len: {}", len);
-                        println!("Data to send {:?}", data);
-                        packet.push(SOH);
-                        packet.push(packet_num);
-                        packet.push(seq2);
-                        for val in &data {
-                            packet.push(val.clone());
-                        }
+    /// Builds a packet around a block of data, blocks longer than 128 bytes
+    /// are sent as 1K blocks. Short blocks are padded with the pad byte.
+    fn make_packet(&self, packet_num: u8, data: &[u8], crc_mode: bool) -> Vec<u8> {
+        let (header, block_length) = if data.len() > BlockLength::Standard.size() {
+            (STX, BlockLength::OneK.size())
+        } else {
+            (SOH, BlockLength::Standard.size())
+        };
+        let mut block = data.to_vec();
+        block.resize(block_length, self.padbyte);
 
-                        if crc_mode {
-                            let crc = crc(&data);
-                            let hi_crc_byte: u8 = (crc >> 8) as u8;
-                            let lo_crc_byte: u8 = (crc & 0xff) as u8;
-                            println!("CRC: {}", crc);
-                            packet.push(hi_crc_byte);
-                            packet.push(lo_crc_byte);
-                        } else {
-                            let checksum = checksum(&data);
-                            println!("Checksum: {}", checksum);
-                            packet.push(checksum);
-                        }
-                        device.write(&packet[..]).expect("Failed to Send Bytes");
-                        println!("Packet to send: {:?}", packet);
-                        device
-                            .clear(serialport::ClearBuffer::Input)
-                            .expect("Failed to clear buffer");
-                        assert!(device.bytes_to_read().unwrap() == 0);
-                        // Get Receiver ACK
-                        match self.read_byte(device) {
-                            Ok(byte) => match byte {
-                                ACK => {
-                                    if packet_num == 255 {
-                                        packet_num = 0;
-                                    } else {
-                                        packet_num += 1;
-                                    }
-                                    break;
-                                }
-                                NAK => {
-                                    errors += 1;
-                                    println!("Received NAK resending");
-                                    if errors > self.retries {
-                                        return Err(
-                                            "Packet Send Failed, reached max number of retries",
-                                        );
-                                    }
-                                }
-                                _ => {
-                                    errors += 1;
-                                    if errors > self.retries {
-                                        return Err(
-                                            "Packet Send Failed, reached max number of retries",
-                                        );
-                                    }
-                                }
-                            },
-                            Err(err) => {
-                                errors += 1;
-                                println!("Error Count: {errors}, Error: {err}");
-                                if errors > self.retries {
-                                    return Err(
-                                        "Packet Send Failed, reached max number of retries",
-                                    );
-                                }
-                            }
-                        }
-                    }
+        let mut packet: Vec<u8> = Vec::with_capacity(block_length + 5);
+        packet.push(header);
+        packet.push(packet_num);
+        packet.push(0xff - packet_num);
+        packet.extend_from_slice(&block);
+        if crc_mode {
+            let crc = crc(&block);
+            println!("CRC: {}", crc);
+            packet.push((crc >> 8) as u8);
+            packet.push((crc & 0xff) as u8);
+        } else {
+            let checksum = checksum(&block);
+            println!("Checksum: {}", checksum);
+            packet.push(checksum);
+        }
+        packet
+    }
+
+    /// Sends a single packet and waits for the receiver to acknowledge it
+    pub(crate) fn send_packet(
+        &mut self,
+        device: &mut dyn Transport,
+        packet_num: u8,
+        data: &[u8],
+        crc_mode: bool,
+    ) -> Result<(), TransferError> {
+        let mut errors = 0;
+        println!("PacketNum: {}", packet_num);
+        println!("Stream Data Len: {}", data.len());
+        let packet = self.make_packet(packet_num, data, crc_mode);
+        loop {
+            self.check_cancel(device)?;
+            self.discard_stale(device)?;
+            device.write_all(&packet)?;
+            println!("Packet to send: {:?}", packet);
+            // Get Receiver ACK
+            match self.read_reply(device) {
+                Ok(ACK) => {
+                    self.reporter.count_packet(data.len());
+                    return Ok(());
                 }
-                _ => {
-                    return Err("IO Read Error");
+                Ok(NAK) => {
+                    self.count_error(&mut errors);
+                    println!("Received NAK resending");
                 }
+                Ok(_) => self.count_error(&mut errors),
+                Err(err) if err.is_recoverable() => {
+                    self.count_error(&mut errors);
+                    println!("Error Count: {errors}, Error: {err}");
+                }
+                Err(err) => return Err(err),
+            }
+            if errors > self.retries {
+                return Err(TransferError::TooManyRetries);
             }
         }
+    }
 
-        // End of Transmission Sync
+    /// Drops stale bytes so the next byte read is the reply to the next
+    /// write. CAN CAN among them means the receiver gave up meanwhile.
+    fn discard_stale(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let pending = device.bytes_to_read()?;
+        if pending > 0 {
+            let mut stale = vec![0; pending];
+            let len = match device.read(&mut stale) {
+                Ok(len) => len,
+                Err(err) if is_timeout(&err) => 0,
+                Err(err) => return Err(TransferError::Io(err)),
+            };
+            if stale[..len].windows(2).any(|pair| pair == [CAN, CAN]) {
+                println!("Receiver cancelled");
+                return Err(TransferError::Cancelled);
+            }
+        }
+        device.clear_input()?;
+        Ok(())
+    }
+
+    /// Reads the receiver's reply to a packet or EOT. CAN CAN cancels the
+    /// transfer, a lone CAN is line noise and the byte after it is the reply.
+    fn read_reply(&mut self, device: &mut dyn Transport) -> Result<u8, TransferError> {
+        match self.read_byte(device)? {
+            CAN => match self.read_byte(device)? {
+                CAN => {
+                    println!("Receiver cancelled");
+                    Err(TransferError::Cancelled)
+                }
+                byte => Ok(byte),
+            },
+            byte => Ok(byte),
+        }
+    }
+
+    /// Ends the transfer and waits for the receiver to acknowledge it
+    pub(crate) fn send_eot(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let mut errors = 0;
         loop {
-            device
-                .clear(serialport::ClearBuffer::Input)
-                .expect("Failed to clear buffer");
-            assert!(device.bytes_to_read().unwrap() == 0);
-            self.send_byte(device, EOT);
-            let mut bytes = [0; 1];
-            match device.read_exact(&mut bytes) {
-                Ok(_) => {
-                    let byte = bytes[0];
+            self.check_cancel(device)?;
+            self.discard_stale(device)?;
+            self.send_byte(device, EOT)?;
+            match self.read_reply(device) {
+                Ok(byte) => {
                     println!("End Sync Received Byte: {}, Errors: {}", byte, errors);
                     match byte {
-                        ACK => break,
+                        ACK => return Ok(()),
                         _ => {
-                            errors += 1;
+                            self.count_error(&mut errors);
                             if errors > self.retries {
-                                return Err(
-                                    "End of Transmission Sync, reached max number of retries",
-                                );
+                                return Err(TransferError::TooManyRetries);
                             }
                         }
                     }
                 }
-                _ => return Err("End of Transmission Sync I/O failure"),
+                Err(err) if err.is_recoverable() => {
+                    self.count_error(&mut errors);
+                    println!("Error Count: {errors}, Error: {err}");
+                    if errors > self.retries {
+                        return Err(TransferError::TooManyRetries);
+                    }
+                }
+                Err(err) => return Err(err),
             }
         }
-        Ok(())
     }
 }
 
+/// Reads from the stream until the block is full or the stream ends,
+/// returns the number of bytes read.
+pub(crate) fn read_block(stream: &mut dyn Read, block: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < block.len() {
+        match stream.read(&mut block[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
 /// Calculates 8bit XModem checksum
 fn checksum(data: &[u8]) -> u8 {
     let sum: u32 = data.iter().map(|&val| val as u32).sum();
@@ -348,7 +813,7 @@ fn checksum(data: &[u8]) -> u8 {
 }
 
 /// Calculate 16bit XModem CRC
-fn crc(data: &[u8]) -> u16 {
+pub(crate) fn crc(data: &[u8]) -> u16 {
     let mut crc = 0;
     for val in data {
         let item: i32 = val.clone().into();
@@ -370,3 +835,399 @@ fn test_crc() {
     let result = crc(&data);
     assert_eq!(result, 0x5A76);
 }
+
+#[cfg(test)]
+#[test]
+fn test_make_packet_1k() {
+    let xmodem = XModem::new_1k();
+    let data: Vec<u8> = vec![0x55; 1024];
+    let packet = xmodem.make_packet(3, &data, true);
+    assert_eq!(packet.len(), 1024 + 5);
+    assert_eq!(&packet[..3], &[STX, 3, 0xfc]);
+
+    let packet = xmodem.make_packet(4, &data[..10], false);
+    assert_eq!(packet.len(), 128 + 4);
+    assert_eq!(&packet[..3], &[SOH, 4, 0xfb]);
+    assert_eq!(packet[3 + 10], SUB);
+}
+
+#[cfg(test)]
+#[test]
+fn test_transfer_over_tcp() {
+    use std::time::Duration;
+    let (mut sender, mut receiver) = crate::transport::tcp_pair();
+    sender
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let data: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+    let source = data.clone();
+    let handle = std::thread::spawn(move || {
+        XModem::new_1k().send(&mut sender, Box::new(std::io::Cursor::new(source)))
+    });
+
+    let path = std::env::temp_dir().join(format!("terminalrs_xmodem_{}", std::process::id()));
+    let stream = std::fs::File::create(&path).unwrap();
+    let size = XModem::builder()
+        .crc_mode(true)
+        .build()
+        .receive(&mut receiver, Box::new(stream))
+        .unwrap();
+    handle.join().unwrap().unwrap();
+
+    let received = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(size, data.len());
+    assert_eq!(received, data);
+}
+
+#[cfg(test)]
+#[test]
+fn test_progress_and_cancel() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    let (mut sender, mut receiver) = crate::transport::tcp_pair();
+    sender
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let updates = Arc::new(Mutex::new(vec![]));
+    let mut xmodem = XModem::new();
+    xmodem.set_total(Some(1000));
+    let token = xmodem.cancel_token();
+    let log = updates.clone();
+    // Cancel once the third packet has been acknowledged
+    xmodem.on_progress(move |progress| {
+        if progress.packets == 3 {
+            token.cancel();
+        }
+        log.lock().unwrap().push(progress.clone());
+    });
+    let handle = std::thread::spawn(move || {
+        xmodem.send(&mut sender, Box::new(std::io::Cursor::new(vec![7; 1000])))
+    });
+
+    let result = XModem::builder()
+        .crc_mode(true)
+        .build()
+        .receive(&mut receiver, Box::new(std::io::sink()));
+    let sent = handle.join().unwrap();
+
+    assert!(matches!(result, Err(TransferError::Cancelled)));
+    assert!(matches!(sent, Err(TransferError::Aborted)));
+    let updates = updates.lock().unwrap();
+    let progress = updates.last().unwrap();
+    assert_eq!(progress.packets, 3);
+    assert_eq!(progress.bytes, 384);
+    assert_eq!(progress.state, TransferState::Cancelled);
+    assert_eq!(updates[0].state, TransferState::Synchronizing);
+    assert!(updates
+        .iter()
+        .any(|progress| progress.state == TransferState::Transferring));
+}
+
+/// Conformance tests running the XModem engine against itself, or against a
+/// scripted peer, over an in-memory loopback.
+#[cfg(test)]
+mod conformance {
+    use super::*;
+    use crate::transport::{loopback_pair, Loopback, Shared};
+    use std::thread::JoinHandle;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + i / 256) as u8).collect()
+    }
+
+    fn fast() -> XModemBuilder {
+        XModem::builder()
+            .sync_timeout(Duration::from_millis(200))
+            .packet_timeout(Duration::from_millis(200))
+    }
+
+    fn spawn_sender(
+        mut device: Loopback,
+        sender: XModemBuilder,
+        data: Vec<u8>,
+    ) -> JoinHandle<Result<(), TransferError>> {
+        std::thread::spawn(move || {
+            sender
+                .build()
+                .send(&mut device, Box::new(std::io::Cursor::new(data)))
+        })
+    }
+
+    /// Sends data from one engine to another, returns what was received
+    fn round_trip(data: &[u8], sender: XModemBuilder, receiver: XModemBuilder) -> Vec<u8> {
+        let (a, mut b) = loopback_pair();
+        let handle = spawn_sender(a, sender, data.to_vec());
+        let output = Shared::default();
+        let size = receiver
+            .build()
+            .receive(&mut b, Box::new(output.clone()))
+            .unwrap();
+        handle.join().unwrap().unwrap();
+        assert_eq!(size, output.bytes().len());
+        output.bytes()
+    }
+
+    /// Reads bytes from the scripted peer's end until one arrives
+    fn expect_byte(device: &mut Loopback) -> u8 {
+        let mut byte = [0; 1];
+        for _ in 0..500 {
+            if let Ok(1) = device.read(&mut byte) {
+                return byte[0];
+            }
+        }
+        panic!("no byte from the engine");
+    }
+
+    #[test]
+    fn test_checksum_mode() {
+        let data = pattern(1000);
+        assert_eq!(round_trip(&data, fast(), fast().crc_mode(false)), data);
+    }
+
+    #[test]
+    fn test_crc_mode() {
+        let data = pattern(1000);
+        assert_eq!(round_trip(&data, fast(), fast().crc_mode(true)), data);
+    }
+
+    #[test]
+    fn test_1k_blocks() {
+        let data = pattern(5000);
+        let sender = fast().block_length(BlockLength::OneK);
+        assert_eq!(round_trip(&data, sender, fast().crc_mode(true)), data);
+    }
+
+    #[test]
+    fn test_1k_sender_to_checksum_receiver() {
+        let data = pattern(3000);
+        let sender = fast().block_length(BlockLength::OneK);
+        assert_eq!(round_trip(&data, sender, fast().crc_mode(false)), data);
+    }
+
+    #[test]
+    fn test_packet_number_wraparound() {
+        // 300 packets, the packet number wraps from 255 to 0
+        let data = pattern(300 * 128);
+        assert_eq!(round_trip(&data, fast(), fast().crc_mode(true)), data);
+        let sender = fast().block_length(BlockLength::OneK);
+        let data = pattern(260 * 1024 + 77);
+        assert_eq!(round_trip(&data, sender, fast().crc_mode(true)), data);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        assert!(round_trip(&[], fast(), fast().crc_mode(true)).is_empty());
+        assert!(round_trip(&[], fast(), fast().crc_mode(false)).is_empty());
+    }
+
+    #[test]
+    fn test_exact_block_multiples() {
+        for len in [128, 128 * 7, 1024, 1024 * 3] {
+            let data = pattern(len);
+            assert_eq!(round_trip(&data, fast(), fast()), data);
+            let sender = fast().block_length(BlockLength::OneK);
+            assert_eq!(round_trip(&data, sender, fast().crc_mode(true)), data);
+        }
+    }
+
+    #[test]
+    fn test_padding() {
+        let data = b"ends in pad bytes\x1a\x1a".to_vec();
+        // Trimming can't tell the file's own pad bytes from padding
+        let trimmed = round_trip(&data, fast(), fast());
+        assert_eq!(trimmed, b"ends in pad bytes");
+        let padded = round_trip(&data, fast(), fast().trim_padding(false));
+        assert_eq!(padded.len(), 128);
+        assert_eq!(&padded[..data.len()], &data[..]);
+        assert!(padded[data.len()..].iter().all(|&b| b == SUB));
+        // Another pad byte keeps the SUBs of the file
+        let sender = fast().padbyte(0);
+        let receiver = fast().padbyte(0);
+        assert_eq!(round_trip(&data, sender, receiver), data);
+    }
+
+    #[test]
+    fn test_fallback_to_checksum() {
+        let (mut peer, mut device) = loopback_pair();
+        let output = Shared::default();
+        let stream = output.clone();
+        let handle = std::thread::spawn(move || {
+            fast()
+                .crc_mode(true)
+                .build()
+                .receive(&mut device, Box::new(stream))
+        });
+        // A checksum only sender ignores the CRC polls until a NAK arrives
+        let mut polls = 0;
+        while expect_byte(&mut peer) != NAK {
+            polls += 1;
+        }
+        assert_eq!(polls, CRC_POLLS);
+        let packet = XModem::new().make_packet(1, b"checksum", false);
+        peer.write_all(&packet).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        peer.write_all(&[EOT]).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        assert_eq!(handle.join().unwrap().unwrap(), 8);
+        assert_eq!(output.bytes(), b"checksum");
+    }
+
+    #[test]
+    fn test_duplicate_and_fragmented_packets() {
+        let (mut peer, mut device) = loopback_pair();
+        let output = Shared::default();
+        let stream = output.clone();
+        let handle = std::thread::spawn(move || {
+            fast()
+                .crc_mode(true)
+                .build()
+                .receive(&mut device, Box::new(stream))
+        });
+        assert_eq!(expect_byte(&mut peer), CRC);
+        let xmodem = XModem::new();
+        let first = xmodem.make_packet(1, &[1; 128], true);
+        let second = xmodem.make_packet(2, &[2; 128], true);
+        // The packet arrives in pieces slower than the port timeout
+        for chunk in first.chunks(50) {
+            peer.write_all(chunk).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(expect_byte(&mut peer), ACK);
+        // Our ACK was "lost", the packet is sent again and must not be kept twice
+        peer.write_all(&first).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        peer.write_all(&second).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        peer.write_all(&[EOT]).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        handle.join().unwrap().unwrap();
+        let mut expected = vec![1; 128];
+        expected.extend_from_slice(&[2; 128]);
+        assert_eq!(output.bytes(), expected);
+    }
+
+    #[test]
+    fn test_corrupt_packet_is_nacked() {
+        let (mut peer, mut device) = loopback_pair();
+        let output = Shared::default();
+        let stream = output.clone();
+        let handle = std::thread::spawn(move || {
+            fast()
+                .crc_mode(true)
+                .build()
+                .receive(&mut device, Box::new(stream))
+        });
+        assert_eq!(expect_byte(&mut peer), CRC);
+        let packet = XModem::new().make_packet(1, b"data", true);
+        let mut corrupt = packet.clone();
+        corrupt[10] ^= 0x40;
+        peer.write_all(&corrupt).unwrap();
+        assert_eq!(expect_byte(&mut peer), NAK);
+        peer.write_all(&packet).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        // Once a block arrived a timeout asks again with NAK, not 'C'
+        assert_eq!(expect_byte(&mut peer), NAK);
+        peer.write_all(&[EOT]).unwrap();
+        assert_eq!(expect_byte(&mut peer), ACK);
+        assert_eq!(handle.join().unwrap().unwrap(), 4);
+        assert_eq!(output.bytes(), b"data");
+    }
+
+    #[test]
+    fn test_sequence_error() {
+        let (mut peer, mut device) = loopback_pair();
+        let handle = std::thread::spawn(move || {
+            fast()
+                .crc_mode(true)
+                .build()
+                .receive(&mut device, Box::new(std::io::sink()))
+        });
+        assert_eq!(expect_byte(&mut peer), CRC);
+        let packet = XModem::new().make_packet(2, b"skipped one", true);
+        peer.write_all(&packet).unwrap();
+        assert_eq!(expect_byte(&mut peer), CAN);
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(TransferError::Sequence {
+                expected: 1,
+                received: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_sync_timeout() {
+        let (_peer, mut device) = loopback_pair();
+        let result = fast()
+            .retries(2)
+            .sync_timeout(Duration::from_millis(20))
+            .build()
+            .receive(&mut device, Box::new(std::io::sink()));
+        assert!(matches!(result, Err(TransferError::SyncTimeout)));
+        let (_peer, mut device) = loopback_pair();
+        let result = fast()
+            .retries(2)
+            .sync_timeout(Duration::from_millis(20))
+            .build()
+            .send(&mut device, Box::new(std::io::empty()));
+        assert!(matches!(result, Err(TransferError::SyncTimeout)));
+    }
+
+    /// Reads a 128 byte CRC packet from the engine and returns its number
+    fn expect_packet(device: &mut Loopback) -> u8 {
+        assert_eq!(expect_byte(device), SOH);
+        let mut rest = [0; 132];
+        for byte in rest.iter_mut() {
+            *byte = expect_byte(device);
+        }
+        rest[0]
+    }
+
+    #[test]
+    fn test_sender_honours_receiver_cancel() {
+        let (mut peer, device) = loopback_pair();
+        let handle = spawn_sender(device, fast(), pattern(1000));
+        peer.write_all(&[CRC]).unwrap();
+        assert_eq!(expect_packet(&mut peer), 1);
+        peer.write_all(&[ACK]).unwrap();
+        assert_eq!(expect_packet(&mut peer), 2);
+        peer.write_all(&[CAN, CAN]).unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(TransferError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_sender_abort_drains_line() {
+        let (mut peer, mut device) = loopback_pair();
+        let mut sender = fast().build();
+        let token = sender.cancel_token();
+        let handle = std::thread::spawn(move || {
+            let result = sender.send(&mut device, Box::new(std::io::Cursor::new(pattern(1000))));
+            (result, device)
+        });
+        peer.write_all(&[CRC]).unwrap();
+        assert_eq!(expect_packet(&mut peer), 1);
+        token.cancel();
+        // Still ACKing as the abort goes out, the drain must swallow it
+        peer.write_all(&[ACK, ACK]).unwrap();
+        let mut abort = [0; 16];
+        for byte in abort.iter_mut() {
+            *byte = expect_byte(&mut peer);
+        }
+        assert_eq!(abort[..8], [CAN; 8]);
+        assert_eq!(abort[8..], [BS; 8]);
+        peer.write_all(b"leftover").unwrap();
+        let (result, device) = handle.join().unwrap();
+        assert!(matches!(result, Err(TransferError::Aborted)));
+        assert_eq!(device.bytes_to_read().unwrap(), 0);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, TransferState};
+use crate::transport::Transport;
+use crate::xmodem::{BlockLength, Received, XModem, XModemBuilder, ACK, CRC, NAK};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// YModem batch transfers, built on the XModem-1K packet engine
+pub struct YModem {
+    xmodem: XModem,
+}
+
+impl YModem {
+    /// Uses the retries, timeouts, pad byte and CRC preference of the
+    /// builder, blocks are always sent 1K
+    pub fn from_builder(builder: XModemBuilder) -> Self {
+        Self {
+            xmodem: builder.block_length(BlockLength::OneK).build(),
+        }
+    }
+
+    /// Sets the function called every time the transfer progresses
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.xmodem.on_progress(observer);
+    }
+
+    /// Token that cancels the transfer from another thread
+    pub fn cancel_token(&self) -> CancelToken {
+        self.xmodem.cancel_token()
+    }
+
+    /// Sends a batch of files over the YModem protocol
+    pub fn send(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        let total = files
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        self.xmodem.set_total(Some(total));
+        self.xmodem.reporter.start();
+        let result = self.send_files(device, files);
+        let result = self.xmodem.settle(device, result);
+        self.xmodem.reporter.finish(result)
+    }
+
+    fn send_files(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        for path in files {
+            let name = file_name(path)?;
+            let stream = File::open(path).map_err(TransferError::StreamRead)?;
+            let size = stream.metadata().map_err(TransferError::StreamRead)?.len();
+            println!("YModem Send: {name}, Size: {size}");
+
+            let crc_mode = self.xmodem.synchronize_sender(device)?;
+            self.xmodem
+                .send_packet(device, 0, &file_header(&name, size), crc_mode)?;
+            // The receiver asks for the data with a second poll
+            let crc_mode = self.xmodem.synchronize_sender(device)?;
+            self.xmodem
+                .send_stream(device, Box::new(stream), crc_mode)?;
+        }
+
+        // An empty header ends the batch
+        let crc_mode = self.xmodem.synchronize_sender(device)?;
+        self.xmodem.send_packet(device, 0, &[0; 128], crc_mode)
+    }
+
+    /// Receives a batch of files over the YModem protocol into a directory,
+    /// returns the paths of the received files.
+    pub fn receive(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        self.xmodem.set_total(None);
+        self.xmodem.reporter.start();
+        let result = self.receive_files(device, directory);
+        let result = self.xmodem.settle(device, result);
+        self.xmodem.reporter.finish(result)
+    }
+
+    fn receive_files(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        let mut received = vec![];
+        let crc_mode = self.xmodem.crc_mode;
+        let poll = if crc_mode { CRC } else { NAK };
+        loop {
+            let mut errors = 0;
+            self.xmodem.send_byte(device, poll)?;
+            let timeout = self.xmodem.sync_timeout;
+            let header =
+                match self
+                    .xmodem
+                    .receive_packet(device, crc_mode, poll, timeout, &mut errors)?
+                {
+                    Received::Packet(0, data) => data,
+                    Received::Packet(num, _) => {
+                        return Err(TransferError::Sequence {
+                            expected: 0,
+                            received: num,
+                        })
+                    }
+                    Received::Eot => {
+                        // Left over end of transmission from the previous file
+                        self.xmodem.send_byte(device, ACK)?;
+                        continue;
+                    }
+                };
+            self.xmodem.send_byte(device, ACK)?;
+
+            let (name, size) = match parse_file_header(&header) {
+                Some(info) => info,
+                None => {
+                    println!("YModem batch complete");
+                    return Ok(received);
+                }
+            };
+            println!("YModem Receive: {name}, Size: {size:?}");
+            self.xmodem.reporter.add_total(size.unwrap_or(0));
+            self.xmodem.reporter.set_state(TransferState::Transferring);
+            let path = directory.join(&name);
+            let mut stream = File::create(&path).map_err(TransferError::StreamWrite)?;
+            self.receive_file(device, &mut stream, size)?;
+            received.push(path);
+        }
+    }
+
+    /// Receives the data blocks of a file, data past the size from the header
+    /// is padding and is dropped.
+    fn receive_file(
+        &mut self,
+        device: &mut dyn Transport,
+        stream: &mut File,
+        size: Option<u64>,
+    ) -> Result<(), TransferError> {
+        let mut errors = 0;
+        let mut remaining = size.unwrap_or(u64::MAX);
+        let mut packet_num: u8 = 1;
+        let mut eot_count = 0;
+        let mut started = false;
+        let crc_mode = self.xmodem.crc_mode;
+        self.xmodem
+            .send_byte(device, if crc_mode { CRC } else { NAK })?;
+        loop {
+            let timeout = self.xmodem.packet_timeout;
+            // 'C' only asks for the first block, later ones are asked for with NAK
+            let poll = if started || !crc_mode { NAK } else { CRC };
+            match self
+                .xmodem
+                .receive_packet(device, crc_mode, poll, timeout, &mut errors)?
+            {
+                Received::Eot => {
+                    // The first EOT is NAKed to make sure it wasn't line noise
+                    eot_count += 1;
+                    if eot_count == 1 {
+                        self.xmodem.send_byte(device, NAK)?;
+                        continue;
+                    }
+                    self.xmodem.send_byte(device, ACK)?;
+                    return Ok(());
+                }
+                // The sender missed our ACK and sent the last packet again
+                Received::Packet(num, _) if num == packet_num.wrapping_sub(1) => {
+                    println!("Duplicate packet {num}, ACK again");
+                    self.xmodem.send_byte(device, ACK)?;
+                }
+                Received::Packet(num, data) => {
+                    if num != packet_num {
+                        println!("Error Packet Number was not expected");
+                        errors += 1;
+                        self.xmodem.reporter.count_retry();
+                        self.xmodem.send_byte(device, NAK)?;
+                        continue;
+                    }
+                    let len = remaining.min(data.len() as u64) as usize;
+                    stream
+                        .write_all(&data[..len])
+                        .map_err(TransferError::StreamWrite)?;
+                    remaining -= len as u64;
+                    started = true;
+                    self.xmodem.send_byte(device, ACK)?;
+                    self.xmodem.reporter.count_packet(len);
+                    packet_num = packet_num.wrapping_add(1);
+                }
+            }
+        }
+    }
+}
+
+/// File name sent to the receiver, the last component of the path
+pub(crate) fn file_name(path: &Path) -> Result<String, TransferError> {
+    match path.file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(TransferError::StreamRead(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid file name",
+        ))),
+    }
+}
+
+/// Builds the block 0 header carrying the file name and size,
+/// padded with NULs to a 128 or 1024 byte block.
+pub(crate) fn file_header(name: &str, size: u64) -> Vec<u8> {
+    let mut header = name.as_bytes().to_vec();
+    header.push(0);
+    header.extend_from_slice(size.to_string().as_bytes());
+    header.push(0);
+    let block_length = if header.len() > 128 { 1024 } else { 128 };
+    header.resize(block_length, 0);
+    header
+}
+
+/// Parses a block 0 header into the file name and size,
+/// returns None for the empty header ending the batch.
+pub(crate) fn parse_file_header(header: &[u8]) -> Option<(String, Option<u64>)> {
+    let name_end = header.iter().position(|&b| b == 0)?;
+    if name_end == 0 {
+        return None;
+    }
+    // Only keep the last path component, the sender's directories are ignored
+    let name = String::from_utf8_lossy(&header[..name_end]);
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let info = &header[name_end + 1..];
+    let info_end = info.iter().position(|&b| b == 0).unwrap_or(info.len());
+    let size = std::str::from_utf8(&info[..info_end])
+        .ok()
+        .and_then(|info| info.split(' ').next())
+        .and_then(|size| size.parse().ok());
+    Some((name, size))
+}
+
+#[cfg(test)]
+#[test]
+fn test_file_header() {
+    let header = file_header("firmware.bin", 70000);
+    assert_eq!(header.len(), 128);
+    assert_eq!(
+        parse_file_header(&header),
+        Some(("firmware.bin".to_string(), Some(70000)))
+    );
+    assert_eq!(
+        parse_file_header(b"dir/image.bin\x001234 14475263014 100644\x00"),
+        Some(("image.bin".to_string(), Some(1234)))
+    );
+    assert_eq!(parse_file_header(&[0; 128]), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_batch_over_tcp() {
+    use std::time::Duration;
+    let (mut sender, mut receiver) = crate::transport::tcp_pair();
+    sender
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("terminalrs_ymodem_{}", std::process::id()));
+    let source = root.join("source");
+    let target = root.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let first: Vec<u8> = (0..5000).map(|i| (i * 13) as u8).collect();
+    let second = b"short file".to_vec();
+    std::fs::write(source.join("first.bin"), &first).unwrap();
+    std::fs::write(source.join("second.txt"), &second).unwrap();
+    let files = vec![source.join("first.bin"), source.join("second.txt")];
+    let handle = std::thread::spawn(move || {
+        let result = YModem::from_builder(XModem::builder()).send(&mut sender, &files);
+        result.map(|_| sender)
+    });
+
+    let mut ymodem = YModem::from_builder(XModem::builder().crc_mode(true));
+    let received = ymodem.receive(&mut receiver, &target).unwrap();
+    let mut sender = handle.join().unwrap().unwrap();
+
+    assert_eq!(
+        received,
+        vec![target.join("first.bin"), target.join("second.txt")]
+    );
+    assert_eq!(std::fs::read(target.join("first.bin")).unwrap(), first);
+    assert_eq!(std::fs::read(target.join("second.txt")).unwrap(), second);
+
+    // A receiver built without CRC asks for the 8bit checksum
+    std::fs::remove_dir_all(&target).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let files = vec![source.join("second.txt")];
+    let handle = std::thread::spawn(move || {
+        YModem::from_builder(XModem::builder()).send(&mut sender, &files)
+    });
+    let mut ymodem = YModem::from_builder(XModem::builder().crc_mode(false));
+    let received = ymodem.receive(&mut receiver, &target).unwrap();
+    handle.join().unwrap().unwrap();
+    assert_eq!(received, vec![target.join("second.txt")]);
+    assert_eq!(std::fs::read(target.join("second.txt")).unwrap(), second);
+    std::fs::remove_dir_all(&root).unwrap();
+}
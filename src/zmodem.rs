@@ -0,0 +1,935 @@
+use crate::error::TransferError;
+use crate::progress::{CancelToken, Progress, Reporter, TransferState};
+use crate::transport::{is_timeout, Transport};
+use crate::xmodem::{crc, read_block};
+use crate::ymodem::{file_header, file_name, parse_file_header};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct ZModem {
+    /// Maximum retries
+    retries: i32,
+    /// Time to wait for the peer before a read fails
+    timeout: Duration,
+    /// Use 32bit CRCs on the frames we send
+    crc32: bool,
+    /// The last header received used a 32bit CRC, its subpackets do too
+    rx_crc32: bool,
+    /// Bytes read from the device but not used yet
+    rx: VecDeque<u8>,
+    /// Progress of the batch and its cancel token
+    reporter: Reporter,
+    /// Size of the files of the batch already finished
+    done: u64,
+    /// Offer files with ZCRESUM so partial ones are continued
+    resume: bool,
+}
+
+const ZPAD: u8 = b'*';
+const ZDLE: u8 = 0x18;
+const ZBIN: u8 = b'A';
+const ZHEX: u8 = b'B';
+const ZBIN32: u8 = b'C';
+
+// Frame types
+const ZRQINIT: u8 = 0;
+const ZRINIT: u8 = 1;
+const ZSINIT: u8 = 2;
+const ZACK: u8 = 3;
+const ZFILE: u8 = 4;
+const ZSKIP: u8 = 5;
+const ZABORT: u8 = 7;
+const ZFIN: u8 = 8;
+const ZRPOS: u8 = 9;
+const ZDATA: u8 = 10;
+const ZEOF: u8 = 11;
+const ZFERR: u8 = 12;
+const ZCAN: u8 = 16;
+
+// Data subpacket ends
+const ZCRCE: u8 = b'h';
+const ZCRCG: u8 = b'i';
+const ZCRCQ: u8 = b'j';
+const ZCRCW: u8 = b'k';
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+// ZRINIT capabilities
+const CANFDX: u8 = 0x01;
+const CANOVIO: u8 = 0x02;
+const CANFC32: u8 = 0x20;
+
+/// ZFILE conversion option for binary transfers
+const ZCBIN: u8 = 1;
+/// ZFILE conversion option asking the receiver to resume a partial file
+const ZCRESUM: u8 = 3;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Length of the data subpackets we send
+const SUBPACKET_LENGTH: usize = 1024;
+/// Longest data subpacket we accept
+const MAX_SUBPACKET_LENGTH: usize = 8192;
+/// Bytes skipped while looking for a header before giving up
+const MAX_GARBAGE: usize = 8192;
+
+/// A frame header, the type and its four data bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    data: [u8; 4],
+}
+
+impl Header {
+    /// Header carrying a file position
+    fn position(kind: u8, position: u32) -> Self {
+        Self {
+            kind,
+            data: position.to_le_bytes(),
+        }
+    }
+
+    /// Header carrying flags in ZF0
+    fn flags(kind: u8, zf0: u8) -> Self {
+        Self {
+            kind,
+            data: [0, 0, 0, zf0],
+        }
+    }
+
+    fn get_position(&self) -> u32 {
+        u32::from_le_bytes(self.data)
+    }
+
+    fn zf0(&self) -> u8 {
+        self.data[3]
+    }
+}
+
+/// A byte read from a ZDLE encoded stream
+enum ZByte {
+    Data(u8),
+    /// Data subpacket end, ZCRCE, ZCRCG, ZCRCQ or ZCRCW
+    End(u8),
+}
+
+impl ZModem {
+    pub fn new() -> Self {
+        Self {
+            retries: 10,
+            timeout: Duration::from_secs(10),
+            crc32: false,
+            rx_crc32: false,
+            rx: VecDeque::new(),
+            reporter: Reporter::default(),
+            done: 0,
+            resume: false,
+        }
+    }
+
+    /// Asks the receiver to continue files it already holds part of,
+    /// rather than replacing them
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Sets the function called every time the transfer progresses
+    pub fn on_progress(&mut self, observer: impl FnMut(&Progress) + Send + 'static) {
+        self.reporter.set_observer(Box::new(observer));
+    }
+
+    /// Token that cancels the transfer from another thread
+    pub fn cancel_token(&self) -> CancelToken {
+        self.reporter.cancel_token()
+    }
+
+    /// Sends a batch of files over the ZModem protocol. With resume set, a
+    /// receiver that already holds part of a file asks for the rest with ZRPOS.
+    pub fn send(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        let total = files
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        self.reporter.set_total(Some(total));
+        self.reporter.start();
+        let result = self.send_files(device, files);
+        self.reporter.finish(result)
+    }
+
+    fn send_files(
+        &mut self,
+        device: &mut dyn Transport,
+        files: &[PathBuf],
+    ) -> Result<(), TransferError> {
+        self.rx.clear();
+        self.done = 0;
+        // Starts the receiver on hosts that don't auto detect ZModem
+        self.write(device, b"rz\r")?;
+        self.synchronize_sender(device)?;
+        for path in files {
+            let name = file_name(path)?;
+            let mut file = File::open(path).map_err(TransferError::StreamRead)?;
+            let size = file.metadata().map_err(TransferError::StreamRead)?.len();
+            println!("ZModem Send: {name}, Size: {size}");
+            // Headers carry 32 bit positions, a larger file can't be sent
+            let size = u32::try_from(size).map_err(|_| {
+                TransferError::StreamRead(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "File too large for ZModem, over 4 GiB",
+                ))
+            })?;
+            self.send_file(device, &name, &mut file, size)?;
+            self.done += u64::from(size);
+        }
+        self.reporter.set_state(TransferState::Finishing);
+        self.finish_session(device)
+    }
+
+    /// Receives a batch of files over the ZModem protocol into a directory,
+    /// returns the paths of the received files. A partial file left by an
+    /// earlier transfer is resumed from its current length when the sender
+    /// asks for that, otherwise it is overwritten.
+    pub fn receive(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        self.reporter.set_total(None);
+        self.reporter.start();
+        let result = self.receive_files(device, directory);
+        self.reporter.finish(result)
+    }
+
+    fn receive_files(
+        &mut self,
+        device: &mut dyn Transport,
+        directory: &Path,
+    ) -> Result<Vec<PathBuf>, TransferError> {
+        self.rx.clear();
+        self.done = 0;
+        let mut received = vec![];
+        let mut errors = 0;
+        // The file being received and the current offset in it
+        let mut current: Option<(PathBuf, File)> = None;
+        let mut offset: u32 = 0;
+        let zrinit = Header::flags(ZRINIT, CANFDX | CANOVIO | CANFC32);
+        self.send_hex_header(device, &zrinit)?;
+        loop {
+            self.check_cancel(device)?;
+            if errors > self.retries {
+                self.send_cancel(device);
+                return Err(TransferError::TooManyRetries);
+            }
+            let header = match self.read_header(device) {
+                Ok(header) => header,
+                Err(err) if !err.is_recoverable() => return Err(err),
+                Err(err) => {
+                    errors += 1;
+                    self.reporter.count_retry();
+                    println!("Error Count: {errors}, Error: {err}");
+                    match current {
+                        Some(_) => {
+                            self.send_hex_header(device, &Header::position(ZRPOS, offset))?
+                        }
+                        None => self.send_hex_header(device, &zrinit)?,
+                    }
+                    continue;
+                }
+            };
+            match header.kind {
+                ZRQINIT => self.send_hex_header(device, &zrinit)?,
+                ZSINIT => {
+                    // The attention string isn't used, the frame only needs an ACK
+                    match self.read_subpacket(device) {
+                        Ok(_) => self.send_hex_header(device, &Header::flags(ZACK, 0))?,
+                        Err(_) => errors += 1,
+                    }
+                }
+                ZFILE => {
+                    let info = match self.read_subpacket(device) {
+                        Ok((info, _)) => info,
+                        Err(err) => {
+                            errors += 1;
+                            println!("Error Count: {errors}, Error: {err}");
+                            self.send_hex_header(device, &zrinit)?;
+                            continue;
+                        }
+                    };
+                    let (name, size) = match parse_file_header(&info) {
+                        Some(file_info) => file_info,
+                        None => return Err(TransferError::Protocol("Invalid file header")),
+                    };
+                    let path = directory.join(&name);
+                    let existing = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                    println!("ZModem Receive: {name}, Size: {size:?}, Existing: {existing}");
+                    // Only a sender asking for crash recovery gets its partial file
+                    // continued, anything else replaces what is there
+                    let resume = header.zf0() == ZCRESUM && existing > 0;
+                    if resume && Some(existing) == size {
+                        println!("File already complete, skipping");
+                        self.send_hex_header(device, &Header::flags(ZSKIP, 0))?;
+                        continue;
+                    }
+                    let resume_from = u32::try_from(existing)
+                        .ok()
+                        .filter(|_| resume && size.is_some_and(|size| existing < size));
+                    self.reporter.add_total(size.unwrap_or(0));
+                    let file = if resume_from.is_some() {
+                        OpenOptions::new().append(true).open(&path)
+                    } else {
+                        File::create(&path)
+                    };
+                    let file = file.map_err(TransferError::StreamWrite)?;
+                    offset = resume_from.unwrap_or(0);
+                    current = Some((path, file));
+                    self.reporter.set_state(TransferState::Transferring);
+                    self.send_hex_header(device, &Header::position(ZRPOS, offset))?;
+                }
+                ZDATA => {
+                    let file = match current.as_mut() {
+                        Some((_, file)) => file,
+                        None => {
+                            self.send_hex_header(device, &zrinit)?;
+                            continue;
+                        }
+                    };
+                    if header.get_position() != offset {
+                        println!("Data position {} expected {offset}", header.get_position());
+                        errors += 1;
+                        self.reporter.count_retry();
+                        self.send_hex_header(device, &Header::position(ZRPOS, offset))?;
+                        continue;
+                    }
+                    loop {
+                        match self.read_subpacket(device) {
+                            Ok((data, end)) => {
+                                file.write_all(&data).map_err(TransferError::StreamWrite)?;
+                                offset = u32::try_from(data.len())
+                                    .ok()
+                                    .and_then(|len| offset.checked_add(len))
+                                    .ok_or(TransferError::Protocol(
+                                        "Data past the 4 GiB ZModem limit",
+                                    ))?;
+                                errors = 0;
+                                self.reporter.count_position(self.done + u64::from(offset));
+                                if end == ZCRCQ || end == ZCRCW {
+                                    self.send_hex_header(device, &Header::position(ZACK, offset))?;
+                                }
+                                if end == ZCRCE || end == ZCRCW {
+                                    break;
+                                }
+                            }
+                            Err(err) if !err.is_recoverable() => return Err(err),
+                            Err(err) => {
+                                errors += 1;
+                                self.reporter.count_retry();
+                                println!("Error Count: {errors}, Error: {err}");
+                                self.send_hex_header(device, &Header::position(ZRPOS, offset))?;
+                                break;
+                            }
+                        }
+                    }
+                }
+                ZEOF => {
+                    // An EOF past our offset means data is still in flight
+                    if header.get_position() != offset {
+                        continue;
+                    }
+                    if let Some((path, file)) = current.take() {
+                        file.sync_all().map_err(TransferError::StreamWrite)?;
+                        println!("Data received, size: {offset}");
+                        self.done += u64::from(offset);
+                        received.push(path);
+                    }
+                    self.send_hex_header(device, &zrinit)?;
+                }
+                ZFIN => {
+                    self.reporter.set_state(TransferState::Finishing);
+                    self.send_hex_header(device, &Header::flags(ZFIN, 0))?;
+                    // The sender ends the session with "OO", it may never arrive
+                    let timeout = self.timeout;
+                    self.timeout = Duration::from_secs(1);
+                    let _ = self.read_raw(device).and_then(|_| self.read_raw(device));
+                    self.timeout = timeout;
+                    return Ok(received);
+                }
+                ZCAN | ZABORT | ZFERR => return Err(TransferError::Cancelled),
+                _ => println!("Ignoring frame type {}", header.kind),
+            }
+        }
+    }
+
+    /// Sends ZRQINIT until the receiver answers with ZRINIT
+    fn synchronize_sender(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let mut errors = 0;
+        loop {
+            self.check_cancel(device)?;
+            self.send_hex_header(device, &Header::flags(ZRQINIT, 0))?;
+            match self.read_header(device) {
+                Ok(header) if header.kind == ZRINIT => {
+                    self.crc32 = header.zf0() & CANFC32 != 0;
+                    println!("Receiver capabilities: {:#04x}", header.zf0());
+                    return Ok(());
+                }
+                Ok(header) if header.kind == ZCAN || header.kind == ZABORT => {
+                    return Err(TransferError::Cancelled)
+                }
+                Ok(_) => errors += 1,
+                Err(err) if !err.is_recoverable() => return Err(err),
+                Err(err) => {
+                    errors += 1;
+                    println!("Error Count: {errors}, Error: {err}");
+                }
+            }
+            if errors > self.retries {
+                return Err(TransferError::SyncTimeout);
+            }
+        }
+    }
+
+    /// Offers a file to the receiver and streams it from the position the
+    /// receiver asks for.
+    fn send_file(
+        &mut self,
+        device: &mut dyn Transport,
+        name: &str,
+        file: &mut File,
+        size: u32,
+    ) -> Result<(), TransferError> {
+        let mut errors = 0;
+        let mut offset = loop {
+            self.check_cancel(device)?;
+            let option = if self.resume { ZCRESUM } else { ZCBIN };
+            self.send_bin_header(device, &Header::flags(ZFILE, option))?;
+            self.send_subpacket(device, &file_header(name, size.into()), ZCRCW)?;
+            match self.read_header(device) {
+                Ok(header) => match header.kind {
+                    ZRPOS => break header.get_position(),
+                    ZSKIP => {
+                        println!("Receiver skipped {name}");
+                        return Ok(());
+                    }
+                    ZCAN | ZABORT | ZFERR => return Err(TransferError::Cancelled),
+                    _ => errors += 1,
+                },
+                Err(err) if !err.is_recoverable() => return Err(err),
+                Err(err) => {
+                    errors += 1;
+                    println!("Error Count: {errors}, Error: {err}");
+                }
+            }
+            if errors > self.retries {
+                return Err(TransferError::TooManyRetries);
+            }
+        };
+        if offset > 0 {
+            println!("Resuming {name} from {offset}");
+        }
+        self.reporter.set_state(TransferState::Transferring);
+
+        loop {
+            self.check_cancel(device)?;
+            if let Some(position) = self.stream_data(device, file, offset, size)? {
+                errors += 1;
+                self.reporter.count_retry();
+                println!("Receiver asked to resend from {position}");
+                offset = position;
+            } else {
+                self.send_bin_header(device, &Header::position(ZEOF, size))?;
+                match self.read_header(device) {
+                    Ok(header) => match header.kind {
+                        ZRINIT => return Ok(()),
+                        ZRPOS => offset = header.get_position(),
+                        ZCAN | ZABORT | ZFERR => return Err(TransferError::Cancelled),
+                        _ => (),
+                    },
+                    Err(err) if !err.is_recoverable() => return Err(err),
+                    Err(err) => println!("Error Count: {errors}, Error: {err}"),
+                }
+                errors += 1;
+            }
+            if errors > self.retries {
+                self.send_cancel(device);
+                return Err(TransferError::TooManyRetries);
+            }
+        }
+    }
+
+    /// Streams the file from an offset as a ZDATA frame. Returns the position
+    /// to resend from if the receiver interrupted the stream with ZRPOS.
+    fn stream_data(
+        &mut self,
+        device: &mut dyn Transport,
+        file: &mut File,
+        offset: u32,
+        size: u32,
+    ) -> Result<Option<u32>, TransferError> {
+        file.seek(SeekFrom::Start(offset.into()))
+            .map_err(TransferError::StreamRead)?;
+        self.rx.clear();
+        self.send_bin_header(device, &Header::position(ZDATA, offset))?;
+        let (mut position, size) = (u64::from(offset), u64::from(size));
+        let mut data = vec![0; SUBPACKET_LENGTH];
+        loop {
+            let len = read_block(file, &mut data).map_err(TransferError::StreamRead)?;
+            position += len as u64;
+            let end = if len < data.len() || position >= size {
+                ZCRCE
+            } else {
+                ZCRCG
+            };
+            self.send_subpacket(device, &data[..len], end)?;
+            self.reporter.count_position(self.done + position);
+            if end == ZCRCE {
+                return Ok(None);
+            }
+            self.check_cancel(device)?;
+            // Check the reverse channel for an error report from the receiver
+            if let Some(header) = self.poll_header(device)? {
+                match header.kind {
+                    ZRPOS => return Ok(Some(header.get_position())),
+                    ZCAN | ZABORT | ZFERR => return Err(TransferError::Cancelled),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Ends the session with ZFIN and the closing "OO"
+    fn finish_session(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        let mut errors = 0;
+        loop {
+            self.check_cancel(device)?;
+            self.send_hex_header(device, &Header::flags(ZFIN, 0))?;
+            match self.read_header(device) {
+                Ok(header) if header.kind == ZFIN => return self.write(device, b"OO"),
+                Err(err) if !err.is_recoverable() => return Err(err),
+                _ => errors += 1,
+            }
+            if errors > self.retries {
+                return Err(TransferError::TooManyRetries);
+            }
+        }
+    }
+
+    /// Reads a header if the receiver has sent one, without waiting
+    fn poll_header(&mut self, device: &mut dyn Transport) -> Result<Option<Header>, TransferError> {
+        let pending = device.bytes_to_read()?;
+        if pending > 0 {
+            let mut buf = vec![0; pending];
+            let len = device.read(&mut buf)?;
+            self.rx.extend(&buf[..len]);
+        }
+        // Anything that isn't the start of a header is line noise
+        while self.rx.front().is_some_and(|&b| b != ZPAD) {
+            self.rx.pop_front();
+        }
+        if self.rx.is_empty() {
+            return Ok(None);
+        }
+        match self.read_header(device) {
+            Ok(header) => Ok(Some(header)),
+            Err(err) if !err.is_recoverable() => Err(err),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads the next header, skipping anything before it
+    fn read_header(&mut self, device: &mut dyn Transport) -> Result<Header, TransferError> {
+        let mut garbage = 0;
+        let mut cancels = 0;
+        loop {
+            let byte = self.read_raw(device)?;
+            if byte == CAN {
+                cancels += 1;
+                if cancels >= 5 {
+                    return Err(TransferError::Cancelled);
+                }
+                continue;
+            }
+            cancels = 0;
+            if byte != ZPAD {
+                garbage += 1;
+                if garbage > MAX_GARBAGE {
+                    return Err(TransferError::Protocol("No header found"));
+                }
+                continue;
+            }
+            let mut byte = self.read_raw(device)?;
+            while byte == ZPAD {
+                byte = self.read_raw(device)?;
+            }
+            if byte != ZDLE {
+                continue;
+            }
+            let header = match self.read_raw(device)? {
+                ZHEX => self.read_hex_header(device)?,
+                ZBIN => self.read_bin_header(device, false)?,
+                ZBIN32 => self.read_bin_header(device, true)?,
+                _ => return Err(TransferError::Protocol("Unknown header format")),
+            };
+            println!("Header received: {:?}", header);
+            return Ok(header);
+        }
+    }
+
+    fn read_hex_header(&mut self, device: &mut dyn Transport) -> Result<Header, TransferError> {
+        let mut bytes = [0; 7];
+        for byte in bytes.iter_mut() {
+            let hi = hex_value(self.read_raw(device)?)?;
+            let lo = hex_value(self.read_raw(device)?)?;
+            *byte = (hi << 4) | lo;
+        }
+        // Hex headers end in CR LF, LF may have the high bit set
+        if self.read_raw(device)? & 0x7f == b'\r' {
+            self.read_raw(device)?;
+        }
+        if crc(&bytes[..5]) != u16::from_be_bytes([bytes[5], bytes[6]]) {
+            return Err(TransferError::Protocol("Header CRC error"));
+        }
+        Ok(Header {
+            kind: bytes[0],
+            data: [bytes[1], bytes[2], bytes[3], bytes[4]],
+        })
+    }
+
+    fn read_bin_header(
+        &mut self,
+        device: &mut dyn Transport,
+        crc32: bool,
+    ) -> Result<Header, TransferError> {
+        let crc_length = if crc32 { 4 } else { 2 };
+        let mut bytes = vec![];
+        for _ in 0..5 + crc_length {
+            match self.read_escaped(device)? {
+                ZByte::Data(byte) => bytes.push(byte),
+                ZByte::End(_) => {
+                    return Err(TransferError::Protocol(
+                        "Unexpected subpacket end in header",
+                    ))
+                }
+            }
+        }
+        if !check_crc(&bytes[..5], &bytes[5..], crc32) {
+            return Err(TransferError::Protocol("Header CRC error"));
+        }
+        self.rx_crc32 = crc32;
+        Ok(Header {
+            kind: bytes[0],
+            data: [bytes[1], bytes[2], bytes[3], bytes[4]],
+        })
+    }
+
+    /// Reads a data subpacket, returns its data and how it ended. The CRC
+    /// length follows the header the subpacket belongs to.
+    fn read_subpacket(
+        &mut self,
+        device: &mut dyn Transport,
+    ) -> Result<(Vec<u8>, u8), TransferError> {
+        let mut data = vec![];
+        let end = loop {
+            match self.read_escaped(device)? {
+                ZByte::Data(byte) => data.push(byte),
+                ZByte::End(end) => break end,
+            }
+            if data.len() > MAX_SUBPACKET_LENGTH {
+                return Err(TransferError::Protocol("Data subpacket too long"));
+            }
+        };
+        let crc_length = if self.rx_crc32 { 4 } else { 2 };
+        let mut received_crc = vec![];
+        for _ in 0..crc_length {
+            match self.read_escaped(device)? {
+                ZByte::Data(byte) => received_crc.push(byte),
+                ZByte::End(_) => {
+                    return Err(TransferError::Protocol("Unexpected subpacket end in CRC"))
+                }
+            }
+        }
+        data.push(end);
+        if !check_crc(&data, &received_crc, self.rx_crc32) {
+            return Err(TransferError::Protocol("Data subpacket CRC error"));
+        }
+        data.pop();
+        Ok((data, end))
+    }
+
+    /// Reads a byte and undoes the ZDLE encoding
+    fn read_escaped(&mut self, device: &mut dyn Transport) -> Result<ZByte, TransferError> {
+        loop {
+            let byte = self.read_raw(device)?;
+            match byte {
+                ZDLE => break,
+                // Flow control characters are never part of the data
+                XON | XOFF | 0x91 | 0x93 => continue,
+                _ => return Ok(ZByte::Data(byte)),
+            }
+        }
+        let mut cancels = 1;
+        loop {
+            let byte = self.read_raw(device)?;
+            match byte {
+                ZCRCE | ZCRCG | ZCRCQ | ZCRCW => return Ok(ZByte::End(byte)),
+                ZRUB0 => return Ok(ZByte::Data(0x7f)),
+                ZRUB1 => return Ok(ZByte::Data(0xff)),
+                CAN => {
+                    cancels += 1;
+                    if cancels >= 5 {
+                        return Err(TransferError::Cancelled);
+                    }
+                }
+                XON | XOFF | 0x91 | 0x93 => (),
+                _ if byte & 0x60 == 0x40 => return Ok(ZByte::Data(byte ^ 0x40)),
+                _ => return Err(TransferError::Protocol("Bad escape sequence")),
+            }
+        }
+    }
+
+    /// Reads a byte from the device, waiting up to the timeout for it
+    fn read_raw(&mut self, device: &mut dyn Transport) -> Result<u8, TransferError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(byte) = self.rx.pop_front() {
+                return Ok(byte);
+            }
+            let mut buf = [0; 1024];
+            match device.read(&mut buf) {
+                // A closed stream, wait out the timeout like a silent line
+                Ok(0) => std::thread::sleep(Duration::from_millis(1)),
+                Ok(len) => self.rx.extend(&buf[..len]),
+                Err(err) if is_timeout(&err) => (),
+                Err(err) => return Err(TransferError::Io(err)),
+            }
+            if self.rx.is_empty() && Instant::now() > deadline {
+                return Err(TransferError::Timeout);
+            }
+        }
+    }
+
+    fn send_hex_header(
+        &mut self,
+        device: &mut dyn Transport,
+        header: &Header,
+    ) -> Result<(), TransferError> {
+        println!("Send Header: {:?}", header);
+        self.write(device, &hex_header(header))
+    }
+
+    fn send_bin_header(
+        &mut self,
+        device: &mut dyn Transport,
+        header: &Header,
+    ) -> Result<(), TransferError> {
+        println!("Send Header: {:?}", header);
+        let frame = bin_header(header, self.crc32);
+        self.write(device, &frame)
+    }
+
+    fn send_subpacket(
+        &mut self,
+        device: &mut dyn Transport,
+        data: &[u8],
+        end: u8,
+    ) -> Result<(), TransferError> {
+        let frame = subpacket(data, end, self.crc32);
+        self.write(device, &frame)
+    }
+
+    /// Aborts the session if the transfer was cancelled on this end
+    fn check_cancel(&mut self, device: &mut dyn Transport) -> Result<(), TransferError> {
+        if !self.reporter.is_cancelled() {
+            return Ok(());
+        }
+        println!("Transfer cancelled, sending CAN");
+        self.send_cancel(device);
+        Err(TransferError::Aborted)
+    }
+
+    /// Aborts the session on the peer
+    fn send_cancel(&mut self, device: &mut dyn Transport) {
+        let _ = self.write(device, &[CAN; 8]);
+        let _ = self.write(device, &[0x08; 8]);
+    }
+
+    fn write(&mut self, device: &mut dyn Transport, bytes: &[u8]) -> Result<(), TransferError> {
+        device.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+const CAN: u8 = 0x18;
+
+/// Encodes a header in hex, used for headers sent by the receiver and
+/// for the session control frames.
+fn hex_header(header: &Header) -> Vec<u8> {
+    let mut bytes = vec![header.kind];
+    bytes.extend_from_slice(&header.data);
+    let crc = crc(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+
+    let mut frame = vec![ZPAD, ZPAD, ZDLE, ZHEX];
+    for byte in bytes {
+        frame.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+    frame.push(b'\r');
+    frame.push(b'\n' | 0x80);
+    if header.kind != ZACK && header.kind != ZFIN {
+        frame.push(XON);
+    }
+    frame
+}
+
+/// Encodes a binary header with a 16 or 32bit CRC
+fn bin_header(header: &Header, crc32: bool) -> Vec<u8> {
+    let mut bytes = vec![header.kind];
+    bytes.extend_from_slice(&header.data);
+    let mut frame = vec![ZPAD, ZDLE, if crc32 { ZBIN32 } else { ZBIN }];
+    for &byte in &bytes {
+        escape(byte, &mut frame);
+    }
+    for byte in frame_crc(&bytes, crc32) {
+        escape(byte, &mut frame);
+    }
+    frame
+}
+
+/// Encodes a data subpacket, the CRC covers the data and the end type
+fn subpacket(data: &[u8], end: u8, crc32: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() * 2 + 10);
+    for &byte in data {
+        escape(byte, &mut frame);
+    }
+    frame.push(ZDLE);
+    frame.push(end);
+    let mut covered = data.to_vec();
+    covered.push(end);
+    for byte in frame_crc(&covered, crc32) {
+        escape(byte, &mut frame);
+    }
+    frame
+}
+
+/// ZDLE escapes the bytes that would upset the line or the protocol
+fn escape(byte: u8, frame: &mut Vec<u8>) {
+    match byte {
+        ZDLE | 0x10 | XON | XOFF | 0x90 | 0x91 | 0x93 => {
+            frame.push(ZDLE);
+            frame.push(byte ^ 0x40);
+        }
+        _ => frame.push(byte),
+    }
+}
+
+/// CRC bytes in the order they are sent, 16bit big endian or 32bit little endian
+fn frame_crc(data: &[u8], crc32: bool) -> Vec<u8> {
+    if crc32 {
+        self::crc32(data).to_le_bytes().to_vec()
+    } else {
+        crc(data).to_be_bytes().to_vec()
+    }
+}
+
+fn check_crc(data: &[u8], received: &[u8], crc32: bool) -> bool {
+    frame_crc(data, crc32) == received
+}
+
+fn hex_value(byte: u8) -> Result<u8, TransferError> {
+    match byte {
+        b'0'..=b'9' => Ok(byte - b'0'),
+        b'a'..=b'f' => Ok(byte - b'a' + 10),
+        b'A'..=b'F' => Ok(byte - b'A' + 10),
+        _ => Err(TransferError::Protocol("Bad hex header")),
+    }
+}
+
+/// Calculate 32bit CRC (IEEE 802.3)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffffffff;
+    for &val in data {
+        crc ^= val as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xedb88320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+}
+
+#[cfg(test)]
+#[test]
+fn test_hex_header() {
+    let frame = hex_header(&Header::flags(ZRQINIT, 0));
+    assert_eq!(&frame[..], b"**\x18B00000000000000\r\x8a\x11");
+}
+
+#[cfg(test)]
+#[test]
+fn test_subpacket_escaping() {
+    let frame = subpacket(&[0x41, ZDLE, XON], ZCRCE, false);
+    assert_eq!(
+        &frame[..7],
+        &[0x41, ZDLE, ZDLE ^ 0x40, ZDLE, XON ^ 0x40, ZDLE, ZCRCE]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_resume_over_tcp() {
+    let (mut sender, mut receiver) = crate::transport::tcp_pair();
+    sender
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("terminalrs_zmodem_{}", std::process::id()));
+    let source = root.join("source");
+    let target = root.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let data: Vec<u8> = (0..20000).map(|i| (i * 31 % 256) as u8).collect();
+    std::fs::write(source.join("image.bin"), &data).unwrap();
+    // An earlier transfer died part way through
+    std::fs::write(target.join("image.bin"), &data[..7000]).unwrap();
+    // and an older image sits next to it
+    std::fs::write(target.join("old.bin"), &data[..500]).unwrap();
+    std::fs::write(source.join("old.bin"), &data[1000..3000]).unwrap();
+    let files = vec![source.join("image.bin")];
+    let handle = std::thread::spawn(move || {
+        let mut zmodem = ZModem::new();
+        zmodem.set_resume(true);
+        zmodem.send(&mut sender, &files)?;
+        // Without resume the older file is replaced, not continued
+        ZModem::new().send(&mut sender, &[source.join("old.bin")])
+    });
+
+    let received = ZModem::new().receive(&mut receiver, &target).unwrap();
+    assert_eq!(received, vec![target.join("image.bin")]);
+    assert_eq!(std::fs::read(target.join("image.bin")).unwrap(), data);
+    let received = ZModem::new().receive(&mut receiver, &target).unwrap();
+    handle.join().unwrap().unwrap();
+    assert_eq!(received, vec![target.join("old.bin")]);
+    assert_eq!(
+        std::fs::read(target.join("old.bin")).unwrap(),
+        &data[1000..3000]
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}